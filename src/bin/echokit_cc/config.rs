@@ -0,0 +1,316 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use echokit_terminal::terminal::pacing::InputPacing;
+
+/// Permission modes accepted by `claude --permission-mode`
+pub const PERMISSION_MODES: [&str; 4] = ["default", "acceptEdits", "plan", "bypassPermissions"];
+
+/// The echokit_cc config file. Every setting has a default, environment
+/// variables and command line options override the file.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Script starting sessions created without custom options, e.g. `run_cc.sh`
+    pub claude_command: String,
+    /// Claude executable launched directly for sessions with custom options
+    pub claude_bin: String,
+    pub bind: String,
+    /// Directory holding the session workspaces, like `ECHOKIT_WORKING_PATH`
+    /// of run_cc.sh
+    pub working_path: PathBuf,
+    /// Only repositories under this directory can back worktree sessions
+    pub repository_root: Option<PathBuf>,
+    /// Only directories under this one can be the `cwd` of a session
+    pub cwd_root: Option<PathBuf>,
+    /// Flags `create_session` may pass to claude without a profile, as
+    /// `--flag`, `--flag=value` or `--flag value`
+    pub allowed_args: Vec<String>,
+    /// Environment variables `create_session` may set without a profile
+    pub allowed_env: Vec<String>,
+    /// Served for every path not handled by the API
+    pub static_dir: PathBuf,
+    pub log: LogConfig,
+    pub timeouts: Timeouts,
+    pub buffers: Buffers,
+    pub input: InputPacing,
+    pub recording: Recording,
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            claude_command: String::new(),
+            claude_bin: "claude".to_string(),
+            bind: "localhost:0".to_string(),
+            working_path: std::env::home_dir()
+                .unwrap_or_default()
+                .join("echokit_cc_sessions"),
+            repository_root: None,
+            cwd_root: None,
+            allowed_args: Vec::new(),
+            allowed_env: Vec::new(),
+            static_dir: PathBuf::from("static"),
+            log: LogConfig::default(),
+            timeouts: Timeouts::default(),
+            buffers: Buffers::default(),
+            input: InputPacing::default(),
+            recording: Recording::default(),
+            profiles: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// `env_logger` filter, e.g. `info` or `echokit_cc=debug`
+    pub level: String,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "error".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    /// A session without activity for this long is ended
+    pub idle_sec: u64,
+    /// How long to wait for Claude Code to write its history file on start
+    pub startup_sec: u64,
+    /// How long the PTY may stay quiet before Claude Code is considered idle
+    /// or waiting on a permission prompt
+    pub poll_interval_ms: u64,
+    /// How long `DELETE /api/sessions/{id}` waits for the session to end
+    pub close_sec: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            idle_sec: 120,
+            startup_sec: 20,
+            poll_interval_ms: 5000,
+            close_sec: 10,
+        }
+    }
+}
+
+impl Timeouts {
+    pub fn startup(&self) -> Duration {
+        Duration::from_secs(self.startup_sec)
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_millis(self.poll_interval_ms)
+    }
+
+    pub fn close(&self) -> Duration {
+        Duration::from_secs(self.close_sec)
+    }
+}
+
+#[derive(Debug, Clone, Copy, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Buffers {
+    /// Messages a session broadcast channel holds for slow subscribers, a
+    /// subscriber falling further behind is resynced with a screen snapshot
+    pub session_broadcast: usize,
+    /// PTY output is sent once this many bytes are pending
+    pub output_coalesce_bytes: usize,
+    /// or once the oldest pending output is this old, 0 sends every read
+    pub output_coalesce_ms: u64,
+}
+
+impl Default for Buffers {
+    fn default() -> Self {
+        Self {
+            session_broadcast: 256,
+            output_coalesce_bytes: 16 * 1024,
+            output_coalesce_ms: 10,
+        }
+    }
+}
+
+/// Asciicast recordings of the sessions, served by
+/// `GET /api/sessions/{id}/recording`
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Recording {
+    pub enabled: bool,
+    /// Also record what is typed into the terminal, prompts included
+    pub input: bool,
+    /// Defaults to `<working path>/.recordings`
+    pub dir: Option<PathBuf>,
+    /// A recording is rotated once it grows beyond this size, 0 for no limit
+    pub max_bytes: u64,
+    /// Rotated recordings kept per session besides the current one
+    pub max_files: usize,
+}
+
+impl Default for Recording {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            input: false,
+            dir: None,
+            max_bytes: 64 * 1024 * 1024,
+            max_files: 2,
+        }
+    }
+}
+
+impl Recording {
+    /// The current recording of a session, rotated ones are
+    /// `<session id>.cast.<n>`
+    pub fn path(&self, working_path: &Path, session_id: &str) -> PathBuf {
+        self.dir
+            .clone()
+            .unwrap_or_else(|| working_path.join(".recordings"))
+            .join(format!("{}.cast", session_id))
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read config {}: {}", path.display(), e))?;
+        let config: Config = toml::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("Invalid config {}: {}", path.display(), e))?;
+        Ok(config)
+    }
+
+    /// Every problem of the merged config, empty when it is usable
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        if self.claude_command.trim().is_empty() {
+            errors.push(
+                "claude_command is required (--claude-command, ECHOKIT_CLAUDE_COMMAND or the config file)"
+                    .to_string(),
+            );
+        }
+        if self.claude_bin.trim().is_empty() {
+            errors.push("claude_bin must not be empty".to_string());
+        }
+        match self.bind.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {}
+            _ => errors.push(format!("bind must be <host>:<port>, got {:?}", self.bind)),
+        }
+        if self.working_path.as_os_str().is_empty() {
+            errors.push("working_path must not be empty".to_string());
+        }
+        for (name, dir) in [
+            ("repository_root", &self.repository_root),
+            ("cwd_root", &self.cwd_root),
+        ] {
+            if let Some(dir) = dir
+                && !dir.is_dir()
+            {
+                errors.push(format!("{} {} is not a directory", name, dir.display()));
+            }
+        }
+        for arg in &self.allowed_args {
+            if !arg.starts_with('-') || arg.contains('=') {
+                errors.push(format!(
+                    "allowed_args must list flags like --verbose, got {:?}",
+                    arg
+                ));
+            }
+        }
+        if self.timeouts.poll_interval_ms == 0 {
+            errors.push("timeouts.poll_interval_ms must be greater than 0".to_string());
+        }
+        if self.timeouts.startup_sec == 0 {
+            errors.push("timeouts.startup_sec must be greater than 0".to_string());
+        }
+        if self.timeouts.idle_sec.saturating_mul(1000) < self.timeouts.poll_interval_ms {
+            errors.push(
+                "timeouts.idle_sec must not be shorter than timeouts.poll_interval_ms".to_string(),
+            );
+        }
+        if self.input.wait_for_echo && self.input.echo_timeout_ms == 0 {
+            errors.push("input.echo_timeout_ms must be greater than 0".to_string());
+        }
+        if self.buffers.session_broadcast == 0 {
+            errors.push("buffers.session_broadcast must be greater than 0".to_string());
+        }
+        if self.buffers.output_coalesce_bytes == 0 {
+            errors.push("buffers.output_coalesce_bytes must be greater than 0".to_string());
+        }
+
+        for (name, profile) in &self.profiles {
+            if let Some(mode) = &profile.permission_mode
+                && !PERMISSION_MODES.contains(&mode.as_str())
+            {
+                errors.push(format!(
+                    "profiles.{}.permission_mode must be one of {}, got {}",
+                    name,
+                    PERMISSION_MODES.join(", "),
+                    mode
+                ));
+            }
+            if let Some(dir) = &profile.cwd_root
+                && !dir.is_dir()
+            {
+                errors.push(format!(
+                    "profiles.{}.cwd_root {} is not a directory",
+                    name,
+                    dir.display()
+                ));
+            }
+            if let Some(idle_sec) = profile.idle_sec
+                && idle_sec.saturating_mul(1000) < self.timeouts.poll_interval_ms
+            {
+                errors.push(format!(
+                    "profiles.{}.idle_sec must not be shorter than timeouts.poll_interval_ms",
+                    name
+                ));
+            }
+        }
+
+        errors
+    }
+}
+
+/// A named session setup selected with `create_session { profile }`
+#[derive(Debug, Clone, Default, serde::Deserialize, serde::Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    #[serde(default)]
+    pub description: String,
+    /// Claude executable, defaults to `--claude-bin`
+    #[serde(default)]
+    pub command: Option<String>,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Not listed by `GET /api/profiles`, it may hold credentials
+    #[serde(default, skip_serializing)]
+    pub env: HashMap<String, String>,
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub permission_mode: Option<String>,
+    /// Directory the `cwd` of a session must be inside, defaults to `--cwd-root`
+    #[serde(default)]
+    pub cwd_root: Option<PathBuf>,
+    /// Tools whose permission prompts are confirmed automatically, `*` for all
+    #[serde(default)]
+    pub auto_approve: Vec<String>,
+    /// Defaults to `--idle-sec`
+    #[serde(default)]
+    pub idle_sec: Option<u64>,
+    /// Input is refused once the session used this many input, output and
+    /// cache creation tokens, cache reads do not count
+    #[serde(default)]
+    pub max_tokens: Option<u64>,
+}
@@ -1,41 +1,120 @@
 use axum::{
     Json, Router,
-    extract::{Path, State, ws::WebSocketUpgrade},
+    extract::{DefaultBodyLimit, Path, Query, State, ws::WebSocketUpgrade},
     response::IntoResponse,
-    routing::{any, get_service, post},
+    routing::{any, delete, get, get_service, post},
 };
 use clap::Parser;
 use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
 use tower_http::services::ServeDir;
 
 use echokit_terminal::terminal::InputItem;
 
+mod config;
+mod replay;
 mod sessions_manager;
+mod workspace;
+mod worktree;
 mod ws;
 
+/// Command line options, each one overrides its environment variable, which
+/// overrides the config file
 #[derive(Parser)]
 #[command(name = "echokit_cc")]
 #[command(about = "A terminal session manager for claude code", long_about = None)]
 struct Args {
+    /// TOML config file, see config.rs for every setting
+    #[arg(long, env = "ECHOKIT_CC_CONFIG")]
+    config: Option<std::path::PathBuf>,
+
+    /// Print the merged config as TOML and exit
+    #[arg(long)]
+    print_config: bool,
+
     #[arg(
         short,
         long,
         env = "ECHOKIT_CLAUDE_COMMAND",
         help = "Command to start the claude code terminal session, e.g. ./run_cc.sh"
     )]
-    claude_command: String,
+    claude_command: Option<String>,
 
-    /// Port to bind the server to
-    #[arg(
-        short,
-        long,
-        default_value = "localhost:0",
-        env = "ECHOKIT_CC_BIND_ADDR"
-    )]
-    bind: String,
+    /// Port to bind the server to [default: localhost:0]
+    #[arg(short, long, env = "ECHOKIT_CC_BIND_ADDR")]
+    bind: Option<String>,
+
+    /// [default: 120]
+    #[arg(long, env = "ECHOKIT_IDLE_TIMEOUT")]
+    idle_sec: Option<u64>,
+
+    /// Seconds to wait for Claude Code to start [default: 20]
+    #[arg(long, env = "CC_WAIT_TIMEOUT")]
+    startup_sec: Option<u64>,
+
+    /// Directory holding the session workspaces, defaults to ~/echokit_cc_sessions like run_cc.sh
+    #[arg(long, env = "ECHOKIT_WORKING_PATH")]
+    working_path: Option<std::path::PathBuf>,
 
-    #[arg(long, default_value = "120", env = "ECHOKIT_IDLE_TIMEOUT")]
-    idle_sec: u64,
+    /// Only repositories under this directory can back worktree sessions,
+    /// `create_session` with a repository is refused when unset
+    #[arg(long, env = "ECHOKIT_REPOSITORY_ROOT")]
+    repository_root: Option<std::path::PathBuf>,
+
+    /// Only directories under this one can be the `cwd` of a session,
+    /// `create_session` with a cwd is refused when unset
+    #[arg(long, env = "ECHOKIT_CWD_ROOT")]
+    cwd_root: Option<std::path::PathBuf>,
+
+    /// Claude executable started directly for sessions created with a cwd,
+    /// model, permission mode, args or env [default: claude]
+    #[arg(long, env = "ECHOKIT_CLAUDE_BIN")]
+    claude_bin: Option<String>,
+
+    /// Directory served for every other path [default: static]
+    #[arg(long, env = "ECHOKIT_STATIC_DIR")]
+    static_dir: Option<std::path::PathBuf>,
+
+    /// `env_logger` filter [default: error]
+    #[arg(long, env = "RUST_LOG")]
+    log_level: Option<String>,
+}
+
+impl Args {
+    /// Override the settings of `config` given on the command line or in the
+    /// environment
+    fn apply(self, config: &mut config::Config) {
+        if let Some(claude_command) = self.claude_command {
+            config.claude_command = claude_command;
+        }
+        if let Some(bind) = self.bind {
+            config.bind = bind;
+        }
+        if let Some(idle_sec) = self.idle_sec {
+            config.timeouts.idle_sec = idle_sec;
+        }
+        if let Some(startup_sec) = self.startup_sec {
+            config.timeouts.startup_sec = startup_sec;
+        }
+        if let Some(working_path) = self.working_path {
+            config.working_path = working_path;
+        }
+        if let Some(repository_root) = self.repository_root {
+            config.repository_root = Some(repository_root);
+        }
+        if let Some(cwd_root) = self.cwd_root {
+            config.cwd_root = Some(cwd_root);
+        }
+        if let Some(claude_bin) = self.claude_bin {
+            config.claude_bin = claude_bin;
+        }
+        if let Some(static_dir) = self.static_dir {
+            config.static_dir = static_dir;
+        }
+        if let Some(log_level) = self.log_level {
+            config.log.level = log_level;
+        }
+    }
 }
 
 #[derive(serde::Deserialize)]
@@ -43,44 +122,96 @@ pub struct InputRequest {
     pub inputs: Vec<InputItem>,
 }
 
+/// Send `body` to the session and wait for the first broadcast message
+/// accepted by `is_response`
+#[allow(clippy::useless_format)]
+async fn request_session(
+    global_state: &ws::GlobalState,
+    id: String,
+    body: ws::WsInputMessage,
+    is_response: impl Fn(&ws::WsOutputMessage) -> bool,
+) -> Json<serde_json::Value> {
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    if global_state.tx.send((id.clone(), tx)).is_ok()
+        && let Ok((mut rx, tx, _)) = rx.await
+        && tx.send(body).is_ok()
+    {
+        loop {
+            match rx.recv().await {
+                Ok(e) if is_response(&e) => return Json(serde_json::to_value(e).unwrap()),
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => {
+                    log::error!("Failed to receive response from session");
+                    return Json(
+                        serde_json::to_value(ws::WsOutputMessage::SessionError {
+                            session_id: id,
+                            code: ws::WsOutputError::InternalError {
+                                error_message: "Failed to receive response from session"
+                                    .to_string(),
+                            },
+                        })
+                        .unwrap(),
+                    );
+                }
+            }
+        }
+    }
+    Json(
+        serde_json::to_value(ws::WsOutputMessage::SessionError {
+            session_id: id,
+            code: ws::WsOutputError::InternalError {
+                error_message: format!("Failed to send input"),
+            },
+        })
+        .unwrap(),
+    )
+}
+
 async fn api_input(
     State(global_state): State<Arc<ws::GlobalState>>,
     Path(id): Path<String>,
     Json(body): Json<ws::WsInputMessage>,
 ) -> impl IntoResponse {
+    request_session(&global_state, id, body, |e| {
+        !matches!(e, ws::WsOutputMessage::SessionPtyOutput { .. })
+    })
+    .await
+}
+
+#[derive(serde::Deserialize)]
+pub struct ChangesQuery {
+    #[serde(default)]
+    pub content: bool,
+}
+
+async fn api_profiles(State(global_state): State<Arc<ws::GlobalState>>) -> impl IntoResponse {
+    Json(serde_json::json!({ "profiles": global_state.config.profiles }))
+}
+
+async fn api_changes(
+    State(global_state): State<Arc<ws::GlobalState>>,
+    Path(id): Path<String>,
+    Query(query): Query<ChangesQuery>,
+) -> impl IntoResponse {
+    let (reply_tx, mut reply_rx) = tokio::sync::mpsc::unbounded_channel();
+    let body = ws::WsInputMessage::GetChanges {
+        content: query.content,
+        reply: Some(reply_tx),
+    };
     let (tx, rx) = tokio::sync::oneshot::channel();
-    if global_state.tx.send((id.clone(), tx)).is_ok() {
-        if let Ok((mut rx, tx)) = rx.await {
-            if tx.send(body).is_ok() {
-                loop {
-                    if let Ok(e) = rx.recv().await {
-                        if matches!(e, ws::WsOutputMessage::SessionPtyOutput { .. }) {
-                            continue;
-                        } else {
-                            return Json(serde_json::to_value(e).unwrap());
-                        }
-                    } else {
-                        log::error!("Failed to receive response from session");
-                        return Json(
-                            serde_json::to_value(ws::WsOutputMessage::SessionError {
-                                session_id: id,
-                                code: ws::WsOutputError::InternalError {
-                                    error_message: "Failed to receive response from session"
-                                        .to_string(),
-                                },
-                            })
-                            .unwrap(),
-                        );
-                    }
-                }
-            }
-        }
+    if global_state.tx.send((id.clone(), tx)).is_ok()
+        && let Ok((_, tx, _)) = rx.await
+        && tx.send(body).is_ok()
+        && let Some(reply) = reply_rx.recv().await
+    {
+        return Json(serde_json::to_value(reply).unwrap());
     }
+    log::error!("Failed to receive changes from session");
     Json(
         serde_json::to_value(ws::WsOutputMessage::SessionError {
             session_id: id,
             code: ws::WsOutputError::InternalError {
-                error_message: format!("Failed to send input"),
+                error_message: "Failed to receive response from session".to_string(),
             },
         })
         .unwrap(),
@@ -89,25 +220,74 @@ async fn api_input(
 
 #[tokio::main]
 async fn main() {
-    env_logger::init();
     let args = Args::parse();
 
-    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut config = match &args.config {
+        Some(path) => config::Config::load(path).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }),
+        None => config::Config::default(),
+    };
+    let print_config = args.print_config;
+    args.apply(&mut config);
+
+    let errors = config.validate();
+    if !errors.is_empty() {
+        for error in errors {
+            eprintln!("Invalid config: {}", error);
+        }
+        std::process::exit(1);
+    }
 
-    let claude_command = args.claude_command.to_string();
-    tokio::spawn(sessions_manager::start(claude_command, args.idle_sec, rx));
+    if print_config {
+        print!("{}", toml::to_string(&config).unwrap());
+        return;
+    }
+
+    env_logger::Builder::new()
+        .parse_filters(&config.log.level)
+        .init();
+
+    let config = Arc::new(config);
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(sessions_manager::start(config.clone(), rx));
 
-    let global_state = Arc::new(ws::GlobalState::new(tx));
+    let global_state = Arc::new(ws::GlobalState::new(tx, config.clone()));
 
     let app = Router::new()
         .route("/ws/{id}", any(websocket_handler))
         .route("/api/{id}/input", post(api_input))
-        .fallback_service(get_service(ServeDir::new("static")))
+        .route("/api/profiles", get(api_profiles))
+        .route("/api/sessions/{id}/changes", get(api_changes))
+        .route(
+            "/api/sessions/{id}/files",
+            get(workspace::api_list_files)
+                .post(workspace::api_upload_files)
+                .layer(DefaultBodyLimit::max(workspace::MAX_UPLOAD_SIZE)),
+        )
+        .route(
+            "/api/sessions/{id}/files/content",
+            get(workspace::api_file_content),
+        )
+        .route("/api/sessions/{id}/archive", get(workspace::api_archive))
+        .route(
+            "/api/sessions/{id}/recording",
+            get(workspace::api_recording),
+        )
+        .route("/api/sessions/{id}", delete(worktree::api_delete_session))
+        .route("/api/sessions/{id}/worktree", get(worktree::api_worktree))
+        .route(
+            "/api/sessions/{id}/worktree/commit",
+            post(worktree::api_commit),
+        )
+        .fallback_service(get_service(ServeDir::new(&config.static_dir)))
         .with_state(global_state.clone());
 
-    let listener = tokio::net::TcpListener::bind(&args.bind)
+    #[allow(clippy::expect_fun_call)]
+    let listener = tokio::net::TcpListener::bind(&config.bind)
         .await
-        .expect(&format!("Failed to bind to {}", args.bind));
+        .expect(&format!("Failed to bind to {}", config.bind));
 
     let bind_addr = listener.local_addr().unwrap();
 
@@ -129,9 +309,15 @@ async fn websocket_handler(
     State(global_state): State<Arc<ws::GlobalState>>,
     ws: WebSocketUpgrade,
     Path(id): Path<String>,
+    Query(query): Query<ws::WebSocketQuery>,
 ) -> impl IntoResponse {
     ws.on_upgrade(async |socket| {
-        if let Err(e) = ws::websocket(id, socket, global_state).await {
+        let result = if query.replay {
+            replay::websocket(id, socket, global_state, query).await
+        } else {
+            ws::websocket(id, socket, global_state, query).await
+        };
+        if let Err(e) = result {
             log::error!("WebSocket error: {:?}", e);
         }
     })
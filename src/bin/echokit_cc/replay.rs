@@ -0,0 +1,601 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use axum::extract::ws::{Message, WebSocket};
+use echokit_terminal::{
+    terminal::{
+        claude::{ClaudeCodeResult, ClaudeCodeTracker, HISTORY_MARKER},
+        recording,
+    },
+    types::claude::ClaudeCodeLog,
+};
+use tokio::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::sessions_manager::event_message;
+use crate::ws::{self, GlobalState, WebSocketQuery, WsOutputError, WsOutputMessage};
+
+/// Controls of a replay, any other input is refused since a replay is
+/// read-only
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(tag = "type")]
+enum ReplayControl {
+    #[serde(alias = "replay_pause")]
+    Pause {},
+    #[serde(alias = "replay_resume")]
+    Resume {},
+    /// Jump to `time` seconds into the recording
+    #[serde(alias = "replay_seek")]
+    Seek { time: f64 },
+    #[serde(alias = "replay_speed")]
+    Speed { speed: f64 },
+    #[serde(alias = "get_current_state")]
+    CurrentState {},
+}
+
+enum Entry {
+    Output(String),
+    /// A history line as a `ClaudeCodeResult::ClaudeLog`, or the move to
+    /// another history file
+    Log(ClaudeCodeResult),
+    /// The PTY stayed quiet for the poll interval
+    Quiet,
+}
+
+/// A recording merged with the history of its session, ordered by time
+struct Timeline {
+    size: (u16, u16),
+    duration: f64,
+    entries: Vec<(f64, Entry)>,
+}
+
+fn invalid_input(message: impl Into<String>) -> WsOutputError {
+    WsOutputError::InvalidInput {
+        error_message: message.into(),
+    }
+}
+
+/// The history file Claude Code wrote for `session_id`, in whichever project
+/// directory
+fn find_history(session_id: &str) -> Option<PathBuf> {
+    let projects = std::env::home_dir()?.join(".claude").join("projects");
+    std::fs::read_dir(projects)
+        .ok()?
+        .flatten()
+        .map(|project| project.path().join(format!("{}.jsonl", session_id)))
+        .find(|path| path.is_file())
+}
+
+/// Seconds since the Unix epoch of a history `timestamp`, an RFC 3339 UTC
+/// time like `2026-02-03T18:11:42.209Z`
+fn parse_timestamp(timestamp: &str) -> Option<f64> {
+    let (date, time) = timestamp.strip_suffix('Z')?.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(|n| n.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+    let mut time = time.splitn(3, ':');
+    let hours = time.next()?.parse::<i64>().ok()?;
+    let minutes = time.next()?.parse::<i64>().ok()?;
+    let seconds = time.next()?.parse::<f64>().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // days from civil, proleptic Gregorian calendar
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    Some((days * 86400 + hours * 3600 + minutes * 60) as f64 + seconds)
+}
+
+/// The history files the session moved to in a cast file, with the seconds
+/// since the Unix epoch when it did
+fn history_moves(cast: &str) -> Vec<(f64, PathBuf)> {
+    let mut lines = cast.lines();
+    let Some(start) = lines
+        .next()
+        .and_then(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .and_then(|header| header["timestamp"].as_f64())
+    else {
+        return Vec::new();
+    };
+    lines
+        .filter_map(|line| {
+            let (time, kind, data) = serde_json::from_str::<(f64, String, String)>(line).ok()?;
+            let path = data.strip_prefix(HISTORY_MARKER).filter(|_| kind == "m")?;
+            Some((start + time, PathBuf::from(path)))
+        })
+        .collect()
+}
+
+/// Add the lines of the history file at `path` to `entries`, none of them
+/// before `since` seconds into the recording started at `start` and none
+/// logged after `until`, when the recording ended
+async fn push_history(
+    entries: &mut Vec<(f64, Entry)>,
+    path: &Path,
+    start: f64,
+    since: f64,
+    until: Option<f64>,
+) {
+    let Ok(history) = tokio::fs::read_to_string(path).await else {
+        log::warn!("history {} is gone, replaying without it", path.display());
+        return;
+    };
+    // lines logged before the recording started, e.g. for a rotated part, set
+    // up the state it starts in
+    let mut time = since;
+    for line in history.lines() {
+        let Ok(log) = serde_json::from_str::<ClaudeCodeLog>(line) else {
+            continue;
+        };
+        // summaries and snapshots have no timestamp of their own
+        if let Some(timestamp) = serde_json::from_str::<serde_json::Value>(line)
+            .ok()
+            .and_then(|value| value["timestamp"].as_str().and_then(parse_timestamp))
+        {
+            time = (timestamp - start).max(since);
+        }
+        // the history is in order, the rest belongs to later parts
+        if until.is_some_and(|until| time > until) {
+            break;
+        }
+        entries.push((time, Entry::Log(ClaudeCodeResult::ClaudeLog(Box::new(log)))));
+    }
+}
+
+async fn load(config: &Config, session_id: &str, part: usize) -> Result<Timeline, WsOutputError> {
+    // only a valid UUID may become part of the file name
+    let uuid = uuid::Uuid::parse_str(session_id)
+        .map_err(|e| invalid_input(format!("Invalid UUID format: {}", e)))?
+        .to_string();
+
+    let current = config.recording.path(&config.working_path, &uuid);
+    let path = if part > 0 {
+        recording::rotated_path(&current, part)
+    } else {
+        current.clone()
+    };
+    let cast = tokio::fs::read_to_string(&path)
+        .await
+        .map_err(|_| invalid_input("Session has no such recording"))?;
+
+    let mut lines = cast.lines();
+    let header: serde_json::Value = lines
+        .next()
+        .and_then(|line| serde_json::from_str(line).ok())
+        .ok_or_else(|| invalid_input("Recording has no asciicast header"))?;
+    let start = header["timestamp"].as_f64().unwrap_or_default();
+    let size = (
+        header["height"].as_u64().unwrap_or(24) as u16,
+        header["width"].as_u64().unwrap_or(80) as u16,
+    );
+
+    // after `/clear` the history goes on in another file, the moves before
+    // this part are in the older ones
+    let mut moves = history_moves(&cast);
+    let mut older = part + 1;
+    while let Ok(cast) = tokio::fs::read_to_string(recording::rotated_path(&current, older)).await {
+        moves.extend(history_moves(&cast));
+        older += 1;
+    }
+    moves.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+
+    let mut output = Vec::new();
+    let mut duration: f64 = 0.0;
+    for line in lines {
+        if let Ok((time, kind, data)) = serde_json::from_str::<(f64, String, String)>(line)
+            && kind == "o"
+        {
+            duration = duration.max(time);
+            output.push((time, Entry::Output(data)));
+        }
+    }
+    // a rotated part is over, the current one may still be recording
+    let until = (part > 0).then_some(duration);
+
+    let mut entries = Vec::new();
+    match find_history(&uuid) {
+        Some(path) => push_history(&mut entries, &path, start, 0.0, until).await,
+        None => log::warn!("[{}] no history found, replaying without states", uuid),
+    }
+    for (moved_at, path) in moves {
+        let time = (moved_at - start).max(0.0);
+        if until.is_some_and(|until| time > until) {
+            break;
+        }
+        entries.push((
+            time,
+            Entry::Log(ClaudeCodeResult::HistoryMoved(path.clone())),
+        ));
+        push_history(&mut entries, &path, start, time, until).await;
+    }
+    entries.extend(output);
+    // stable, history lines stay ahead of output of the same time
+    entries.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+
+    let poll_interval = config.timeouts.poll_interval().as_secs_f64();
+    let mut timeline = Vec::with_capacity(entries.len());
+    let mut last = 0.0;
+    for (time, entry) in entries {
+        if time - last > poll_interval {
+            timeline.push((last + poll_interval, Entry::Quiet));
+        }
+        last = time;
+        timeline.push((time, entry));
+    }
+
+    Ok(Timeline {
+        size,
+        duration: duration.max(last),
+        entries: timeline,
+    })
+}
+
+/// Playback position, advancing with `speed` while playing
+struct Clock {
+    time: f64,
+    since: Option<Instant>,
+    speed: f64,
+}
+
+impl Clock {
+    fn now(&self) -> f64 {
+        let elapsed = self
+            .since
+            .map(|since| since.elapsed().as_secs_f64())
+            .unwrap_or_default();
+        self.time + elapsed * self.speed
+    }
+
+    fn is_paused(&self) -> bool {
+        self.since.is_none()
+    }
+
+    fn pause(&mut self) {
+        self.time = self.now();
+        self.since = None;
+    }
+
+    fn resume(&mut self) {
+        self.since.get_or_insert_with(Instant::now);
+    }
+
+    fn seek(&mut self, time: f64) {
+        self.time = time;
+        if self.since.is_some() {
+            self.since = Some(Instant::now());
+        }
+    }
+
+    fn set_speed(&mut self, speed: f64) {
+        self.seek(self.now());
+        self.speed = speed;
+    }
+
+    /// When the playback reaches `time`, `None` while paused
+    fn deadline(&self, time: f64) -> Option<Instant> {
+        let since = self.since?;
+        let wait = ((time - self.time) / self.speed).max(0.0);
+        Some(since + Duration::from_secs_f64(wait))
+    }
+}
+
+/// Plays a timeline, recomputing the session state as the live session did
+struct Player {
+    session_id: String,
+    timeline: Timeline,
+    next: usize,
+    tracker: ClaudeCodeTracker,
+    screen: vt100::Parser,
+    clock: Clock,
+}
+
+impl Player {
+    fn new(session_id: String, timeline: Timeline, speed: f64) -> Self {
+        let (rows, cols) = timeline.size;
+        Self {
+            session_id,
+            timeline,
+            next: 0,
+            tracker: ClaudeCodeTracker::default(),
+            screen: vt100::Parser::new(rows, cols, 0),
+            clock: Clock {
+                time: 0.0,
+                since: Some(Instant::now()),
+                speed,
+            },
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.next >= self.timeline.entries.len()
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        match self.timeline.entries.get(self.next) {
+            Some((time, _)) => self.clock.deadline(*time),
+            // the end is reported once, then the playback stops
+            None => self.clock.deadline(self.timeline.duration),
+        }
+    }
+
+    fn state_message(&self) -> WsOutputMessage {
+        WsOutputMessage::SessionState {
+            session_id: self.session_id.clone(),
+            current_state: self.tracker.state().clone(),
+            todos: self.tracker.todos().clone(),
+            permission_mode: self.tracker.permission_mode(),
+        }
+    }
+
+    fn replay_message(&self) -> WsOutputMessage {
+        WsOutputMessage::SessionReplay {
+            session_id: self.session_id.clone(),
+            time: self.clock.now().min(self.timeline.duration),
+            duration: self.timeline.duration,
+            paused: self.clock.is_paused(),
+            speed: self.clock.speed,
+        }
+    }
+
+    /// Apply the next entry, returning what the live session sent for it
+    fn apply_next(&mut self) -> Vec<WsOutputMessage> {
+        let mut messages = Vec::new();
+        let (_, entry) = &self.timeline.entries[self.next];
+        self.next += 1;
+
+        match entry {
+            Entry::Output(output) => {
+                self.screen.process(output.as_bytes());
+                if self.tracker.update_from_screen(output) {
+                    messages.push(self.state_message());
+                }
+                messages.push(WsOutputMessage::pty_output(output.clone().into()));
+            }
+            Entry::Log(result) => {
+                if self.tracker.update_state(result) {
+                    messages.push(self.state_message());
+                }
+            }
+            Entry::Quiet => match self.tracker.state().quiet_result() {
+                Some(ClaudeCodeResult::WaitForUserInput) => {
+                    if self.tracker.update_on_quiet() {
+                        messages.push(self.state_message());
+                    }
+                    messages.push(WsOutputMessage::SessionIdle {
+                        session_id: self.session_id.clone(),
+                    });
+                }
+                Some(result) if self.tracker.update_state(&result) => {
+                    messages.push(self.state_message());
+                }
+                _ => {}
+            },
+        }
+
+        for event in self.tracker.take_events() {
+            messages.push(event_message(self.session_id.clone(), event));
+        }
+        messages
+    }
+
+    /// Apply every entry the playback has reached
+    fn advance(&mut self) -> Vec<WsOutputMessage> {
+        let now = self.clock.now();
+        let mut messages = Vec::new();
+        while let Some((time, _)) = self.timeline.entries.get(self.next)
+            && *time <= now
+        {
+            messages.extend(self.apply_next());
+        }
+        messages
+    }
+
+    /// Rebuild the screen and state at `time`, returning the redrawn screen
+    /// and state
+    fn seek(&mut self, time: f64) -> Vec<WsOutputMessage> {
+        let time = time.clamp(0.0, self.timeline.duration);
+        let (rows, cols) = self.timeline.size;
+        self.next = 0;
+        self.tracker = ClaudeCodeTracker::default();
+        self.screen = vt100::Parser::new(rows, cols, 0);
+        self.clock.seek(time);
+
+        while let Some((entry_time, _)) = self.timeline.entries.get(self.next)
+            && *entry_time <= time
+        {
+            self.apply_next();
+        }
+
+        let screen = self.screen.screen().state_formatted();
+        vec![
+            WsOutputMessage::pty_output(String::from_utf8_lossy(&screen).to_string().into()),
+            self.state_message(),
+            self.replay_message(),
+        ]
+    }
+
+    fn control(&mut self, control: ReplayControl) -> Vec<WsOutputMessage> {
+        match control {
+            ReplayControl::Pause {} => self.clock.pause(),
+            ReplayControl::Resume {} if self.is_finished() => {
+                self.clock.resume();
+                return self.seek(0.0);
+            }
+            ReplayControl::Resume {} => self.clock.resume(),
+            ReplayControl::Seek { time } if time.is_finite() => return self.seek(time),
+            ReplayControl::Speed { speed } if speed.is_finite() && speed > 0.0 => {
+                self.clock.set_speed(speed)
+            }
+            ReplayControl::Seek { .. } | ReplayControl::Speed { .. } => {
+                return vec![self.error(invalid_input("Invalid replay time or speed"))];
+            }
+            ReplayControl::CurrentState {} => return vec![self.state_message()],
+        }
+        vec![self.replay_message()]
+    }
+
+    fn error(&self, code: WsOutputError) -> WsOutputMessage {
+        WsOutputMessage::SessionError {
+            session_id: self.session_id.clone(),
+            code,
+        }
+    }
+}
+
+async fn send(socket: &mut WebSocket, messages: Vec<WsOutputMessage>, binary: bool) -> bool {
+    for message in messages {
+        if socket
+            .send(ws::output_frame(message, binary))
+            .await
+            .is_err()
+        {
+            return false;
+        }
+    }
+    true
+}
+
+/// Serve the recording of `session_id` like a live session, read-only
+pub async fn websocket(
+    session_id: String,
+    mut socket: WebSocket,
+    global_state: Arc<GlobalState>,
+    query: WebSocketQuery,
+) -> anyhow::Result<()> {
+    let timeline = match load(&global_state.config, &session_id, query.part).await {
+        Ok(timeline) => timeline,
+        Err(code) => {
+            let error = WsOutputMessage::SessionError { session_id, code };
+            send(&mut socket, vec![error], false).await;
+            return Ok(());
+        }
+    };
+
+    let speed = if query.speed.is_finite() && query.speed > 0.0 {
+        query.speed
+    } else {
+        1.0
+    };
+    let mut player = Player::new(session_id.clone(), timeline, speed);
+    log::info!(
+        "[{}] replaying {} entries over {:.1}s",
+        session_id,
+        player.timeline.entries.len(),
+        player.timeline.duration
+    );
+
+    let mut messages = vec![player.state_message(), player.replay_message()];
+    loop {
+        if !send(&mut socket, std::mem::take(&mut messages), query.binary).await {
+            break;
+        }
+
+        let deadline = player.deadline();
+        tokio::select! {
+            _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                messages = player.advance();
+                if player.is_finished() && player.clock.now() >= player.timeline.duration {
+                    player.clock.pause();
+                    player.clock.seek(player.timeline.duration);
+                    messages.push(WsOutputMessage::SessionEnded {
+                        session_id: session_id.clone(),
+                    });
+                    messages.push(player.replay_message());
+                }
+            }
+            msg = socket.recv() => match msg {
+                Some(Ok(Message::Text(text))) => {
+                    messages = match serde_json::from_str::<ReplayControl>(&text) {
+                        Ok(control) => player.control(control),
+                        Err(_) => vec![player.error(invalid_input(
+                            "The session is a read-only replay",
+                        ))],
+                    };
+                }
+                Some(Ok(Message::Binary(_))) => {
+                    messages = vec![player.error(invalid_input("The session is a read-only replay"))];
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(parse_timestamp("1970-01-01T00:00:00Z"), Some(0.0));
+        assert_eq!(parse_timestamp("2000-03-01T00:00:00Z"), Some(951868800.0));
+        let time = parse_timestamp("2026-02-03T18:11:42.209Z").unwrap();
+        assert!((time - 1770142302.209).abs() < 1e-6);
+        assert_eq!(parse_timestamp("2026-02-03 18:11:42"), None);
+        assert_eq!(parse_timestamp("2026-13-03T18:11:42Z"), None);
+    }
+
+    #[test]
+    fn test_history_moves() {
+        let cast = [
+            r#"{"version":2,"width":80,"height":24,"timestamp":1000}"#,
+            r#"[0.5,"o","/clear\r\n"]"#,
+            r#"[1.5,"m","history:/tmp/next.jsonl"]"#,
+            r#"[2.0,"m","chapter"]"#,
+            r#"[2.5,"o","history:/tmp/other.jsonl"]"#,
+        ]
+        .join("\n");
+        assert_eq!(
+            history_moves(&cast),
+            vec![(1001.5, PathBuf::from("/tmp/next.jsonl"))]
+        );
+        assert!(history_moves("").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_push_history() {
+        let path =
+            std::env::temp_dir().join(format!("echokit_history_{}.jsonl", uuid::Uuid::new_v4()));
+        let system = |timestamp: &str| {
+            format!(
+                r#"{{"type":"system","sessionId":"s","uuid":"u","timestamp":"{}"}}"#,
+                timestamp
+            )
+        };
+        let history = [
+            system("1970-01-01T00:16:39Z"),
+            system("1970-01-01T00:16:45Z"),
+            r#"{"type":"summary","summary":"s","leafUuid":"u"}"#.to_string(),
+            system("1970-01-01T00:17:00Z"),
+            r#"{"type":"summary","summary":"s","leafUuid":"u"}"#.to_string(),
+        ]
+        .join("\n");
+        tokio::fs::write(&path, history).await.unwrap();
+
+        let times = |entries: Vec<(f64, Entry)>| {
+            entries
+                .into_iter()
+                .map(|(time, _)| time)
+                .collect::<Vec<_>>()
+        };
+        let mut entries = Vec::new();
+        push_history(&mut entries, &path, 1000.0, 0.0, None).await;
+        assert_eq!(times(entries), vec![0.0, 5.0, 5.0, 20.0, 20.0]);
+
+        // a rotated part ending 10s in leaves out what was logged later
+        let mut entries = Vec::new();
+        push_history(&mut entries, &path, 1000.0, 2.0, Some(10.0)).await;
+        assert_eq!(times(entries), vec![2.0, 5.0, 5.0]);
+
+        tokio::fs::remove_file(path).await.unwrap();
+    }
+}
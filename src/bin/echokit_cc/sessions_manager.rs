@@ -1,33 +1,309 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
 
 use echokit_terminal::terminal::{
-    EchokitChild,
-    claude::{ClaudeCode, ClaudeCodeResult, ClaudeCodeState},
+    EchokitChild, OutputChunk,
+    claude::{
+        ClaudeCode, ClaudeCodeEvent, ClaudeCodeResult, ClaudeCodeState, PLAN_OPTION_APPROVE,
+        PLAN_OPTION_AUTO_ACCEPT, PermissionMode,
+    },
+    commands::{self, CommandOutput},
+    output::OutputBroadcast,
+    pacing::{InputPacing, SubmitMode},
+    recording::RecordingOptions,
 };
 
+use crate::config::{Config, PERMISSION_MODES, Profile};
+use crate::worktree;
 use crate::ws::{self, WsInputMessage, WsOutputMessage};
 
+/// Limits the terminal loop applies to one session
+struct SessionPolicy {
+    idle_sec: u64,
+    poll_interval: std::time::Duration,
+    pacing: InputPacing,
+    auto_approve: Vec<String>,
+    max_tokens: Option<u64>,
+}
+
+impl SessionPolicy {
+    fn new(config: &Config, profile: Option<&Profile>) -> Self {
+        Self {
+            idle_sec: profile
+                .and_then(|p| p.idle_sec)
+                .unwrap_or(config.timeouts.idle_sec),
+            poll_interval: config.timeouts.poll_interval(),
+            pacing: config.input,
+            auto_approve: profile.map(|p| p.auto_approve.clone()).unwrap_or_default(),
+            max_tokens: profile.and_then(|p| p.max_tokens),
+        }
+    }
+
+    /// The pending tool requests to confirm without asking, when every one of
+    /// them is covered by `auto_approve`
+    fn auto_approved(&self, state: &ClaudeCodeState) -> Option<Vec<String>> {
+        let ClaudeCodeState::PreUseTool {
+            request,
+            is_pending: true,
+            ..
+        } = state
+        else {
+            return None;
+        };
+
+        let pending = request.iter().filter(|tool| !tool.done).collect::<Vec<_>>();
+        let approved = !pending.is_empty()
+            && pending.iter().all(|tool| {
+                self.auto_approve
+                    .iter()
+                    .any(|name| name == "*" || *name == tool.name)
+            });
+        approved.then(|| pending.iter().map(|tool| tool.id.clone()).collect())
+    }
+}
+
+fn invalid_input(error_message: String) -> ws::WsOutputError {
+    ws::WsOutputError::InvalidInput { error_message }
+}
+
+/// The profile selected by `options`, which may not override what the profile
+/// fixes
+fn session_profile<'a>(
+    config: &'a Config,
+    options: &ws::SessionOptions,
+) -> Result<Option<&'a Profile>, ws::WsOutputError> {
+    let Some(name) = &options.profile else {
+        return Ok(None);
+    };
+    let profile = config
+        .profiles
+        .get(name)
+        .ok_or_else(|| invalid_input(format!("Unknown profile {}", name)))?;
+
+    if options.model.is_some()
+        || options.permission_mode.is_some()
+        || !options.args.is_empty()
+        || !options.env.is_empty()
+    {
+        return Err(invalid_input(format!(
+            "model, permission_mode, args and env are set by profile {}",
+            name
+        )));
+    }
+    Ok(Some(profile))
+}
+
+/// Refuse the `args` and `env` of `options` the config does not allow. A
+/// profile sets them itself, the client cannot pick any then.
+fn check_allowed(config: &Config, options: &ws::SessionOptions) -> Result<(), ws::WsOutputError> {
+    // a token not starting with `-` is only allowed as the value of the
+    // allowed flag before it, like `--add-dir /x`
+    let mut takes_value = false;
+    for arg in &options.args {
+        if takes_value && !arg.starts_with('-') {
+            takes_value = false;
+            continue;
+        }
+        let (flag, value) = match arg.split_once('=') {
+            Some((flag, _)) => (flag, true),
+            None => (arg.as_str(), false),
+        };
+        if !config.allowed_args.iter().any(|allowed| allowed == flag) {
+            return Err(invalid_input(format!(
+                "Argument {} is not allowed, see allowed_args",
+                arg
+            )));
+        }
+        takes_value = !value;
+    }
+    if let Some(key) = options
+        .env
+        .keys()
+        .find(|key| !config.allowed_env.contains(key))
+    {
+        return Err(invalid_input(format!(
+            "Environment variable {} is not allowed, see allowed_env",
+            key
+        )));
+    }
+    Ok(())
+}
+
+/// Refuse the options `create_session` cannot start a session with, before
+/// anything is set up for them
+fn check_options(
+    config: &Config,
+    uuid: &uuid::Uuid,
+    options: &ws::SessionOptions,
+    profile: Option<&Profile>,
+) -> Result<(), ws::WsOutputError> {
+    if !options.is_custom() {
+        return Ok(());
+    }
+    if profile.is_none() {
+        check_allowed(config, options)?;
+    }
+
+    let permission_mode = profile
+        .and_then(|p| p.permission_mode.as_ref())
+        .or(options.permission_mode.as_ref());
+    if let Some(mode) = permission_mode
+        && !PERMISSION_MODES.contains(&mode.as_str())
+    {
+        return Err(invalid_input(format!(
+            "Unknown permission mode {}, expected one of {}",
+            mode,
+            PERMISSION_MODES.join(", ")
+        )));
+    }
+
+    // the session directory is only created once the session starts
+    if options.cwd.is_some() {
+        session_cwd(config, uuid, options, profile)?;
+    }
+    Ok(())
+}
+
+/// The working directory of a session with custom options: `cwd` when it lies
+/// inside the cwd root, `<working path>/<session id>` otherwise
+fn session_cwd(
+    config: &Config,
+    uuid: &uuid::Uuid,
+    options: &ws::SessionOptions,
+    profile: Option<&Profile>,
+) -> Result<PathBuf, ws::WsOutputError> {
+    let Some(cwd) = &options.cwd else {
+        let dir = config.working_path.join(uuid.to_string());
+        std::fs::create_dir_all(&dir).map_err(|e| ws::WsOutputError::InternalError {
+            error_message: format!("Failed to create session directory: {}", e),
+        })?;
+        return Ok(dir);
+    };
+
+    if options.repository.is_some() {
+        return Err(invalid_input(
+            "cwd cannot be combined with repository".to_string(),
+        ));
+    }
+    let Some(cwd_root) = profile
+        .and_then(|p| p.cwd_root.as_ref())
+        .or(config.cwd_root.as_ref())
+    else {
+        return Err(invalid_input(
+            "Custom cwd is disabled, start echokit_cc with --cwd-root".to_string(),
+        ));
+    };
+    let root = cwd_root
+        .canonicalize()
+        .map_err(|e| invalid_input(format!("Invalid cwd root: {}", e)))?;
+    let cwd = std::path::Path::new(cwd)
+        .canonicalize()
+        .map_err(|e| invalid_input(format!("Invalid cwd {}: {}", cwd, e)))?;
+    if !cwd.is_dir() || !cwd.starts_with(&root) {
+        return Err(invalid_input(format!(
+            "cwd must be a directory inside {}",
+            root.display()
+        )));
+    }
+    Ok(cwd)
+}
+
+/// Start claude with `options`, which passed `check_options`
 async fn create_session(
-    claude_command: &str,
-    uuid: &str,
+    config: &Config,
+    uuid: uuid::Uuid,
+    options: &ws::SessionOptions,
+    profile: Option<&Profile>,
 ) -> Result<EchokitChild<ClaudeCode>, ws::WsOutputError> {
-    let uuid = uuid::Uuid::parse_str(uuid).map_err(|e| ws::WsOutputError::InvalidInput {
-        error_message: format!("Invalid UUID format: {}", e),
-    })?;
+    let start_error = |e| ws::WsOutputError::InternalError {
+        error_message: format!("Failed to start claude terminal process: {}", e),
+    };
 
-    echokit_terminal::terminal::claude::new(claude_command, uuid, (24, 80))
+    if !options.is_custom() {
+        return echokit_terminal::terminal::claude::new_with_timeout(
+            &config.claude_command,
+            uuid,
+            options.size(),
+            config.timeouts.startup(),
+            // the workspace APIs serve the directory the script runs claude in
+            &[(
+                std::ffi::OsStr::new("ECHOKIT_WORKING_PATH"),
+                config.working_path.as_os_str(),
+            )],
+        )
         .await
-        .map_err(|e| ws::WsOutputError::InternalError {
-            error_message: format!("Failed to start claude terminal process: {}", e),
-        })
+        .map_err(start_error);
+    }
+
+    let model = profile
+        .and_then(|p| p.model.as_ref())
+        .or(options.model.as_ref());
+    let permission_mode = profile
+        .and_then(|p| p.permission_mode.as_ref())
+        .or(options.permission_mode.as_ref());
+    let (extra_args, env) = match profile {
+        Some(profile) => (&profile.args, &profile.env),
+        None => (&options.args, &options.env),
+    };
+
+    let cwd = session_cwd(config, &uuid, options, profile)?;
+
+    // Same history location as run_cc.sh, resume when Claude already saved one
+    let history_file = std::env::home_dir()
+        .unwrap_or_default()
+        .join(".claude")
+        .join("projects")
+        .join(cwd.to_string_lossy().replace(['/', '_'], "-"))
+        .join(format!("{}.jsonl", uuid));
+    let mut args = if history_file.metadata().is_ok_and(|m| m.len() > 0) {
+        vec!["--resume".to_string(), uuid.to_string()]
+    } else {
+        vec!["--session-id".to_string(), uuid.to_string()]
+    };
+
+    if let Some(model) = model {
+        args.extend(["--model".to_string(), model.clone()]);
+    }
+    if let Some(mode) = permission_mode {
+        args.extend(["--permission-mode".to_string(), mode.clone()]);
+    }
+    args.extend(extra_args.iter().cloned());
+
+    let mut env = env
+        .iter()
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect::<Vec<_>>();
+    env.push(("CLAUDE_SESSION_ID".to_string(), uuid.to_string()));
+
+    let claude_bin = profile
+        .and_then(|p| p.command.as_ref())
+        .unwrap_or(&config.claude_bin);
+
+    log::info!(
+        "[{}] Starting {} {:?} in {}",
+        uuid,
+        claude_bin,
+        args,
+        cwd.display()
+    );
+    echokit_terminal::terminal::claude::new_with_command(
+        claude_bin,
+        &args,
+        &env,
+        options.size(),
+        Some(cwd),
+    )
+    .await
+    .map_err(start_error)
 }
 
 pub async fn start(
-    claude_command: String,
-    idle_sec: u64,
+    config: Arc<Config>,
     mut rx: tokio::sync::mpsc::UnboundedReceiver<(String, ws::RxSender)>,
 ) -> anyhow::Result<()> {
     let mut sessions: HashMap<String, (ws::WsInputTx, ws::WsOutputTx)> = HashMap::new();
+    // Options of the last `create_session`, reused when a session is restarted
+    // by any other input
+    let session_options: SessionOptionsMap = Arc::default();
 
     loop {
         let input = rx.recv().await;
@@ -38,90 +314,233 @@ pub async fn start(
 
         let (uuid, input) = input.unwrap();
 
-        if let Some((ws_input_tx, ws_output_tx)) = sessions.get(&uuid) {
-            if !ws_input_tx.is_closed() {
-                let _ = input.send((ws_output_tx.subscribe(), ws_input_tx.clone()));
-                continue;
-            }
+        if let Some((ws_input_tx, ws_output_tx)) = sessions.get(&uuid)
+            && !ws_input_tx.is_closed()
+        {
+            let _ = input.send((
+                ws_output_tx.subscribe(),
+                ws_input_tx.clone(),
+                ws_output_tx.clone(),
+            ));
+            continue;
         }
 
-        {
-            let (ws_output_tx, ws_output_rx) = tokio::sync::broadcast::channel(100);
-            let (ws_input_tx, mut ws_input_rx) =
-                tokio::sync::mpsc::unbounded_channel::<WsInputMessage>();
+        let ws_output_tx = Arc::new(OutputBroadcast::new(
+            config.buffers.session_broadcast,
+            config.buffers.output_coalesce_bytes,
+            std::time::Duration::from_millis(config.buffers.output_coalesce_ms),
+            (24, 80),
+        ));
+        let (ws_input_tx, ws_input_rx) = tokio::sync::mpsc::unbounded_channel::<WsInputMessage>();
 
-            let _ = input.send((ws_output_rx, ws_input_tx.clone()));
+        let _ = input.send((
+            ws_output_tx.subscribe(),
+            ws_input_tx.clone(),
+            ws_output_tx.clone(),
+        ));
 
-            let request = ws_input_rx.recv().await;
-            if request.is_none() {
-                log::warn!("No input received for session UUID: {}", uuid);
-                continue;
+        // clients coming while it starts join the session, it is started anew
+        // once the start failed and dropped the receiver
+        sessions.insert(uuid.clone(), (ws_input_tx.clone(), ws_output_tx.clone()));
+        tokio::spawn(open_session(
+            config.clone(),
+            uuid,
+            ws_input_tx,
+            ws_input_rx,
+            ws_output_tx,
+            session_options.clone(),
+        ));
+    }
+
+    Ok(())
+}
+
+type SessionOptionsMap = Arc<std::sync::Mutex<HashMap<String, ws::SessionOptions>>>;
+
+/// Start the session `uuid` for its first input and run it. Nothing else
+/// waits on the start, claude and a worktree take their time.
+async fn open_session(
+    config: Arc<Config>,
+    uuid: String,
+    ws_input_tx: ws::WsInputTx,
+    mut ws_input_rx: ws::WsInputRx,
+    ws_output_tx: ws::WsOutputTx,
+    session_options: SessionOptionsMap,
+) {
+    let request = ws_input_rx.recv().await;
+    if request.is_none() {
+        log::warn!("No input received for session UUID: {}", uuid);
+        return;
+    }
+
+    let input = request.unwrap();
+
+    if input.is_query() {
+        log::info!(
+            "Received {:?} request before session creation for UUID: {}",
+            input,
+            uuid
+        );
+        let error = WsOutputMessage::SessionError {
+            session_id: uuid.clone(),
+            code: ws::WsOutputError::SessionNotFound,
+        };
+        match input {
+            WsInputMessage::GetChanges {
+                reply: Some(reply), ..
+            } => {
+                let _ = reply.send(error);
+            }
+            _ => {
+                let _ = ws_output_tx.send(error);
             }
+        }
+        return;
+    }
 
-            let input = request.unwrap();
+    let send_error = |code| {
+        let _ = ws_output_tx.send(WsOutputMessage::SessionError {
+            session_id: uuid.clone(),
+            code,
+        });
+    };
 
-            if let WsInputMessage::CurrentState {} = &input {
-                log::info!(
-                    "Received CurrentState request before session creation for UUID: {}",
-                    uuid
-                );
-                let _ = ws_output_tx.send(WsOutputMessage::SessionError {
-                    session_id: uuid.clone(),
-                    code: ws::WsOutputError::SessionNotFound,
-                });
-                continue;
+    let session_uuid = match uuid::Uuid::parse_str(&uuid) {
+        Ok(session_uuid) => session_uuid,
+        Err(e) => {
+            send_error(invalid_input(format!("Invalid UUID format: {}", e)));
+            return;
+        }
+    };
+
+    let options = match &input {
+        WsInputMessage::CreateSession { options } => options.clone(),
+        _ => session_options
+            .lock()
+            .unwrap()
+            .get(&uuid)
+            .cloned()
+            .unwrap_or_default(),
+    };
+
+    let profile = match session_profile(&config, &options).and_then(|profile| {
+        check_options(&config, &session_uuid, &options, profile).map(|()| profile)
+    }) {
+        Ok(profile) => profile,
+        Err(e) => {
+            send_error(e);
+            return;
+        }
+    };
+
+    // only a worktree made for this start is removed when claude fails
+    let new_worktree = match &options.repository {
+        Some(repository) => match worktree::create(
+            config.repository_root.as_deref(),
+            &config.working_path,
+            &uuid,
+            repository,
+            options.base_ref.as_deref(),
+        )
+        .await
+        {
+            Ok((dir, created)) => created.then_some(dir),
+            Err(e) => {
+                log::error!("Failed to create worktree for UUID {}: {:?}", uuid, e);
+                send_error(e);
+                return;
             }
+        },
+        None => None,
+    };
 
-            let _ = ws_input_tx.send(input);
+    let _ = ws_input_tx.send(input);
+    ws_output_tx.set_size(options.size());
 
-            log::info!("Creating new session for UUID: {}", uuid);
-            match create_session(&claude_command, &uuid).await {
-                Ok(terminal) => {
-                    sessions.insert(uuid.clone(), (ws_input_tx, ws_output_tx.clone()));
+    log::info!("Creating new session for UUID: {}", uuid);
+    match create_session(&config, session_uuid, &options, profile).await {
+        Ok(mut terminal) => {
+            terminal.set_poll_interval(config.timeouts.poll_interval());
+            if config.recording.enabled {
+                start_recording(&config, &uuid, &mut terminal, options.size());
+            }
+            let policy = SessionPolicy::new(&config, profile);
+            session_options
+                .lock()
+                .unwrap()
+                .insert(uuid.clone(), options);
+            drop(ws_input_tx);
 
-                    tokio::spawn(async move {
-                        if let Err(e) =
-                            terminal_loop(terminal, ws_input_rx, ws_output_tx, idle_sec).await
-                        {
-                            log::error!("[{}] Terminal loop error: {:?}", uuid, e);
-                        }
-                    });
-                }
-                Err(e) => {
-                    log::error!("Failed to create session for UUID {}: {:?}", uuid, e);
-                    let _ = ws_output_tx.send(WsOutputMessage::SessionError {
-                        session_id: uuid.clone(),
-                        code: e,
-                    });
-                }
+            if let Err(e) = terminal_loop(terminal, ws_input_rx, ws_output_tx, policy).await {
+                log::error!("[{}] Terminal loop error: {:?}", uuid, e);
+            }
+        }
+        Err(e) => {
+            log::error!("Failed to create session for UUID {}: {:?}", uuid, e);
+            send_error(e);
+            if let Some(dir) = new_worktree
+                && let Err(e) = worktree::remove(&dir, &uuid, true, true).await
+            {
+                log::warn!(
+                    "[{}] Failed to remove worktree {}: {}",
+                    uuid,
+                    dir.display(),
+                    e
+                );
             }
         }
     }
+}
 
-    Ok(())
+fn start_recording(
+    config: &Config,
+    session_id: &str,
+    terminal: &mut EchokitChild<ClaudeCode>,
+    size: (u16, u16),
+) {
+    let options = RecordingOptions {
+        path: config.recording.path(&config.working_path, session_id),
+        input: config.recording.input,
+        max_bytes: config.recording.max_bytes,
+        max_files: config.recording.max_files,
+    };
+    let path = options.path.clone();
+    match terminal.start_recording(options, size) {
+        Ok(()) => log::info!("[{}] Recording to {}", session_id, path.display()),
+        Err(e) => log::error!(
+            "[{}] Failed to start recording to {}: {}",
+            session_id,
+            path.display(),
+            e
+        ),
+    }
 }
 
 async fn terminal_loop(
     mut terminal: EchokitChild<ClaudeCode>,
     mut rx: ws::WsInputRx,
     pty_sub_tx: ws::WsOutputTx,
-    idle_sec: u64,
+    policy: SessionPolicy,
 ) -> anyhow::Result<()> {
     enum TerminalEvent {
         Input(WsInputMessage),
         InputClosed,
 
         ClaudeResult(ClaudeCodeResult),
+        /// Coalesced PTY output is due
+        Flush,
 
         Error,
     }
 
     log::info!("[{}] Start terminal event loop", terminal.session_id());
-    let times = idle_sec / 5;
+    let times = (policy.idle_sec * 1000 / (policy.poll_interval.as_millis() as u64).max(1)).max(1);
+    let mut auto_approved = Vec::new();
     let mut idle_counter = 0;
     let mut input_received = false;
 
     loop {
+        let flush_deadline = pty_sub_tx.flush_deadline();
         let event = tokio::select! {
             result = terminal.read_pty_output_and_history_line() => {
                 match result {
@@ -139,6 +558,8 @@ async fn terminal_loop(
                     None => TerminalEvent::InputClosed,
                 }
             },
+            _ = tokio::time::sleep_until(flush_deadline.unwrap_or_else(tokio::time::Instant::now)),
+                if flush_deadline.is_some() => TerminalEvent::Flush,
         };
 
         if !matches!(
@@ -151,17 +572,21 @@ async fn terminal_loop(
 
         match event {
             TerminalEvent::ClaudeResult(ClaudeCodeResult::PtyOutput(output)) => {
-                if pty_sub_tx
-                    .send(WsOutputMessage::SessionPtyOutput { output })
-                    .is_err()
-                {
+                if terminal.update_from_screen(&output.text) {
+                    send_current_state(&terminal, &pty_sub_tx).await;
+                }
+                if !pty_sub_tx.send(WsOutputMessage::pty_output(output)) {
                     log::warn!("[{}] no active PTY subscribers", terminal.session_id());
                     continue;
                 }
             }
+            TerminalEvent::Flush => pty_sub_tx.flush(),
 
             TerminalEvent::ClaudeResult(ClaudeCodeResult::WaitForUserInput) => {
                 log::info!("[{}] Waiting for user input", terminal.session_id());
+                if terminal.update_on_quiet() {
+                    send_current_state(&terminal, &pty_sub_tx).await;
+                }
 
                 let _ = pty_sub_tx.send(WsOutputMessage::SessionIdle {
                     session_id: terminal.session_id().to_string(),
@@ -178,10 +603,7 @@ async fn terminal_loop(
                         terminal.session_id()
                     );
 
-                    terminal.send_text("/exit").await?;
-                    tokio::time::sleep(std::time::Duration::from_millis(300)).await;
-                    terminal.send_enter().await?;
-
+                    exit_claude(&mut terminal, &policy.pacing, &pty_sub_tx).await?;
                     break;
                 }
             }
@@ -192,21 +614,50 @@ async fn terminal_loop(
                         terminal.session_id(),
                         terminal.state()
                     );
-                    send_current_state(
-                        terminal.session_id().to_string(),
-                        terminal.state(),
-                        &pty_sub_tx,
-                    )
-                    .await;
+                    send_current_state(&terminal, &pty_sub_tx).await;
+                }
+
+                for event in terminal.take_events() {
+                    send_event(terminal.session_id().to_string(), event, &pty_sub_tx);
+                }
+
+                if let Some(ids) = policy.auto_approved(terminal.state())
+                    && ids != auto_approved
+                {
+                    log::info!("[{}] Auto approving {:?}", terminal.session_id(), ids);
+                    terminal.send_enter().await?;
+                    auto_approved = ids;
                 }
             }
 
+            TerminalEvent::Input(WsInputMessage::CloseSession {}) => {
+                log::info!("[{}] Closing session on request", terminal.session_id());
+                exit_claude(&mut terminal, &policy.pacing, &pty_sub_tx).await?;
+                break;
+            }
             TerminalEvent::Input(input) => {
                 log::info!("Sending input to terminal: {:?}", input);
-                if matches!(&input, WsInputMessage::Input { .. }) {
+                if matches!(
+                    &input,
+                    WsInputMessage::Input { .. }
+                        | WsInputMessage::InputMultiline { .. }
+                        | WsInputMessage::FileUploaded { notify: true, .. }
+                ) {
+                    if let Some(max_tokens) = policy.max_tokens
+                        && terminal.usage().billed() >= max_tokens
+                    {
+                        let _ = pty_sub_tx.send(WsOutputMessage::SessionError {
+                            session_id: terminal.session_id().to_string(),
+                            code: ws::WsOutputError::BudgetExceeded {
+                                used_tokens: terminal.usage().billed(),
+                                max_tokens,
+                            },
+                        });
+                        continue;
+                    }
                     input_received = true;
                 }
-                handler_input_message(&mut terminal, input, &mut pty_sub_tx.clone()).await;
+                handler_input_message(&mut terminal, input, &policy.pacing, &pty_sub_tx).await;
             }
             TerminalEvent::InputClosed | TerminalEvent::Error => {
                 log::error!("Input channel closed or error occurred, terminating terminal loop");
@@ -229,76 +680,111 @@ async fn terminal_loop(
     Ok(())
 }
 
+async fn exit_claude(
+    terminal: &mut EchokitChild<ClaudeCode>,
+    pacing: &InputPacing,
+    pty_sub_tx: &ws::WsOutputTx,
+) -> anyhow::Result<()> {
+    terminal.send_text("/exit").await?;
+    let output = terminal
+        .wait_before_key("/exit", pacing, pacing.exit_delay())
+        .await?;
+    forward_output(output, pty_sub_tx);
+    terminal.send_enter().await?;
+    Ok(())
+}
+
+/// Forward PTY output read while waiting on an echo
+fn forward_output(output: OutputChunk, pty_sub_tx: &ws::WsOutputTx) {
+    if !output.is_empty() {
+        let _ = pty_sub_tx.send(WsOutputMessage::pty_output(output));
+    }
+}
+
 async fn handler_input_message(
     terminal: &mut EchokitChild<ClaudeCode>,
     input: WsInputMessage,
+    pacing: &InputPacing,
     pty_sub_tx: &ws::WsOutputTx,
 ) {
     let session_id = terminal.session_id().to_string();
     match input {
-        WsInputMessage::CreateSession {} => {
-            send_current_state(session_id, terminal.state(), pty_sub_tx).await
-        }
-        WsInputMessage::CurrentState {} => {
-            send_current_state(session_id, terminal.state(), pty_sub_tx).await
+        WsInputMessage::CreateSession { .. } => send_current_state(terminal, pty_sub_tx).await,
+        WsInputMessage::CloseSession {} => {}
+        WsInputMessage::CurrentState {} => send_current_state(terminal, pty_sub_tx).await,
+        WsInputMessage::GetChanges { content, reply } => {
+            // diffing reads every touched file, keep it off the session loop
+            let changes = terminal.changes(content);
+            let pty_sub_tx = pty_sub_tx.clone();
+            tokio::task::spawn_blocking(move || {
+                let message = WsOutputMessage::SessionChanges {
+                    session_id,
+                    changes: changes(),
+                };
+                match reply {
+                    Some(reply) => {
+                        let _ = reply.send(message);
+                    }
+                    None => {
+                        let _ = pty_sub_tx.send(message);
+                    }
+                }
+            });
         }
 
         WsInputMessage::Select { index } => {
-            for _ in 0..index {
-                if let Err(e) = terminal.send_down_arrow().await {
-                    let _ = pty_sub_tx.send(WsOutputMessage::SessionError {
-                        session_id: session_id.clone(),
-                        code: ws::WsOutputError::InternalError {
-                            error_message: format!("Failed to send down arrow input: {}", e),
-                        },
-                    });
-                }
-            }
-
-            tokio::time::sleep(std::time::Duration::from_millis(500)).await;
-
-            if let Err(e) = terminal.send_enter().await {
-                let _ = pty_sub_tx.send(WsOutputMessage::SessionError {
-                    session_id: session_id.clone(),
-                    code: ws::WsOutputError::InternalError {
-                        error_message: format!("Failed to send enter input: {}", e),
-                    },
-                });
-            }
+            select_option(terminal, index, pacing, pty_sub_tx).await
+        }
+        WsInputMessage::ApprovePlan { auto_accept } => {
+            approve_plan(terminal, auto_accept, pacing, pty_sub_tx).await
+        }
+        WsInputMessage::RejectPlan { feedback } => {
+            reject_plan(terminal, feedback, pacing, pty_sub_tx).await
         }
 
         WsInputMessage::Input { input } => {
-            let state = terminal.state();
-            if state.input_available() {
-                log::debug!("[{}] Sending user input: {}", session_id, input);
-                if let Err(e) = terminal.send_text(&input).await {
-                    let _ = pty_sub_tx.send(WsOutputMessage::SessionError {
-                        session_id: session_id.clone(),
-                        code: ws::WsOutputError::InternalError {
-                            error_message: format!("Failed to send input: {}", e),
-                        },
-                    });
-                }
-
-                tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+            send_user_input(terminal, input, pacing, pty_sub_tx).await
+        }
+        WsInputMessage::InputMultiline { input } => {
+            let pacing = InputPacing {
+                submit: SubmitMode::Paste,
+                ..*pacing
+            };
+            send_user_input(terminal, input, &pacing, pty_sub_tx).await
+        }
+        WsInputMessage::FileUploaded { files, notify } => {
+            let _ = pty_sub_tx.send(WsOutputMessage::SessionFileUploaded {
+                session_id,
+                files: files.clone(),
+            });
 
-                if let Err(e) = terminal.send_enter().await {
-                    let _ = pty_sub_tx.send(WsOutputMessage::SessionError {
-                        session_id,
-                        code: ws::WsOutputError::InternalError {
-                            error_message: format!("Failed to send enter input: {}", e),
-                        },
-                    });
-                }
-            } else {
-                log::debug!("[{}] Sending user input (invalid): {}", session_id, input);
-                let _ = pty_sub_tx.send(WsOutputMessage::SessionError {
-                    session_id,
-                    code: ws::WsOutputError::InvalidInputForState {
-                        error_state: state.to_string(),
-                        error_input: input.clone(),
-                    },
-                });
+            if notify {
+                let paths = files
+                    .iter()
+                    .map(|file| file.path.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                let input = format!("I've uploaded {}", paths);
+                send_user_input(terminal, input, pacing, pty_sub_tx).await;
+            }
+        }
+        WsInputMessage::SetMode { mode } => set_mode(terminal, mode, pacing, pty_sub_tx).await,
+        WsInputMessage::SlashCommand { name, args } => {
+            slash_command(terminal, &name, &args, pacing, pty_sub_tx).await
+        }
+        WsInputMessage::Key(key) => {
+            log::debug!("[{}] Sending key: {:?}", session_id, key);
+            if let Err(e) = terminal.send_key(&key).await {
+                let code = if e.kind() == std::io::ErrorKind::InvalidInput {
+                    ws::WsOutputError::InvalidInput {
+                        error_message: e.to_string(),
+                    }
+                } else {
+                    ws::WsOutputError::InternalError {
+                        error_message: format!("Failed to send key: {}", e),
+                    }
+                };
+                let _ = pty_sub_tx.send(WsOutputMessage::SessionError { session_id, code });
             }
         }
         WsInputMessage::BytesInput { input } => {
@@ -359,18 +845,477 @@ async fn handler_input_message(
     }
 }
 
-async fn send_current_state(
-    session_id: String,
-    state: &ClaudeCodeState,
+async fn send_user_input(
+    terminal: &mut EchokitChild<ClaudeCode>,
+    input: String,
+    pacing: &InputPacing,
     pty_sub_tx: &ws::WsOutputTx,
 ) {
-    if pty_sub_tx
-        .send(WsOutputMessage::SessionState {
+    let session_id = terminal.session_id().to_string();
+    let state = terminal.state();
+    if state.input_available() {
+        log::debug!("[{}] Sending user input: {}", session_id, input);
+        submit_input(terminal, &input, pacing, pty_sub_tx).await;
+        let command = input.trim();
+        if command == "/clear" {
+            terminal.expect_new_history();
+        } else if (command == "/compact" || command.starts_with("/compact "))
+            && terminal.start_compacting()
+        {
+            send_current_state(terminal, pty_sub_tx).await;
+        }
+    } else {
+        log::debug!("[{}] Sending user input (invalid): {}", session_id, input);
+        let _ = pty_sub_tx.send(WsOutputMessage::SessionError {
+            session_id,
+            code: ws::WsOutputError::InvalidInputForState {
+                error_state: state.to_string(),
+                error_input: input.clone(),
+            },
+        });
+    }
+}
+
+/// Write `input` and press Enter once it shows up, whatever the state
+async fn submit_input(
+    terminal: &mut EchokitChild<ClaudeCode>,
+    input: &str,
+    pacing: &InputPacing,
+    pty_sub_tx: &ws::WsOutputTx,
+) {
+    let session_id = terminal.session_id().to_string();
+    if let Err(e) = terminal.send_input_text(input, pacing).await {
+        let _ = pty_sub_tx.send(WsOutputMessage::SessionError {
             session_id: session_id.clone(),
-            current_state: state.clone(),
-        })
-        .is_err()
+            code: ws::WsOutputError::InternalError {
+                error_message: format!("Failed to send input: {}", e),
+            },
+        });
+    }
+
+    match terminal
+        .wait_before_key(input, pacing, pacing.enter_delay())
+        .await
+    {
+        Ok(output) => forward_output(output, pty_sub_tx),
+        Err(e) => log::warn!("[{}] Failed to read PTY output: {}", session_id, e),
+    }
+
+    if let Err(e) = terminal.send_enter().await {
+        let _ = pty_sub_tx.send(WsOutputMessage::SessionError {
+            session_id,
+            code: ws::WsOutputError::InternalError {
+                error_message: format!("Failed to send enter input: {}", e),
+            },
+        });
+    }
+}
+
+/// Move the selection of the menu on screen down `index` times and press Enter
+async fn select_option(
+    terminal: &mut EchokitChild<ClaudeCode>,
+    index: usize,
+    pacing: &InputPacing,
+    pty_sub_tx: &ws::WsOutputTx,
+) {
+    let session_id = terminal.session_id().to_string();
+    for _ in 0..index {
+        if let Err(e) = terminal.send_down_arrow().await {
+            let _ = pty_sub_tx.send(WsOutputMessage::SessionError {
+                session_id: session_id.clone(),
+                code: ws::WsOutputError::InternalError {
+                    error_message: format!("Failed to send down arrow input: {}", e),
+                },
+            });
+        }
+
+        // with wait_for_echo every arrow waits for the menu to redraw
+        match terminal
+            .wait_before_key("", pacing, pacing.key_delay())
+            .await
+        {
+            Ok(output) => forward_output(output, pty_sub_tx),
+            Err(e) => log::warn!("[{}] Failed to read PTY output: {}", session_id, e),
+        }
+    }
+
+    if !pacing.wait_for_echo {
+        tokio::time::sleep(pacing.select_delay()).await;
+    }
+
+    if let Err(e) = terminal.send_enter().await {
+        let _ = pty_sub_tx.send(WsOutputMessage::SessionError {
+            session_id,
+            code: ws::WsOutputError::InternalError {
+                error_message: format!("Failed to send enter input: {}", e),
+            },
+        });
+    }
+}
+
+/// Answer the plan approval menu, `auto_accept` also switches to accept edits
+async fn approve_plan(
+    terminal: &mut EchokitChild<ClaudeCode>,
+    auto_accept: bool,
+    pacing: &InputPacing,
+    pty_sub_tx: &ws::WsOutputTx,
+) {
+    if !matches!(terminal.state(), ClaudeCodeState::PlanProposed { .. }) {
+        send_plan_state_error(terminal, "ApprovePlan", pty_sub_tx);
+        return;
+    }
+
+    log::info!(
+        "[{}] Approving plan (auto accept: {})",
+        terminal.session_id(),
+        auto_accept
+    );
+    let index = if auto_accept {
+        PLAN_OPTION_AUTO_ACCEPT
+    } else {
+        PLAN_OPTION_APPROVE
+    };
+    select_option(terminal, index, pacing, pty_sub_tx).await;
+}
+
+/// Reject the plan with Esc, then tell Claude what to change
+async fn reject_plan(
+    terminal: &mut EchokitChild<ClaudeCode>,
+    feedback: Option<String>,
+    pacing: &InputPacing,
+    pty_sub_tx: &ws::WsOutputTx,
+) {
+    let session_id = terminal.session_id().to_string();
+    if !matches!(terminal.state(), ClaudeCodeState::PlanProposed { .. }) {
+        send_plan_state_error(terminal, "RejectPlan", pty_sub_tx);
+        return;
+    }
+
+    log::info!("[{}] Rejecting plan", session_id);
+    if let Err(e) = terminal.send_esc().await {
+        let _ = pty_sub_tx.send(WsOutputMessage::SessionError {
+            session_id,
+            code: ws::WsOutputError::InternalError {
+                error_message: format!("Failed to send cancel input: {}", e),
+            },
+        });
+        return;
+    }
+
+    let Some(feedback) = feedback.filter(|feedback| !feedback.trim().is_empty()) else {
+        return;
+    };
+    // the input box returns once the menu is gone
+    match terminal
+        .wait_before_key("", pacing, pacing.select_delay())
+        .await
+    {
+        Ok(output) => forward_output(output, pty_sub_tx),
+        Err(e) => log::warn!("[{}] Failed to read PTY output: {}", session_id, e),
+    }
+    submit_input(terminal, &feedback, pacing, pty_sub_tx).await;
+}
+
+fn send_plan_state_error(
+    terminal: &EchokitChild<ClaudeCode>,
+    input: &str,
+    pty_sub_tx: &ws::WsOutputTx,
+) {
+    let _ = pty_sub_tx.send(WsOutputMessage::SessionError {
+        session_id: terminal.session_id().to_string(),
+        code: ws::WsOutputError::InvalidInputForState {
+            error_state: terminal.state().to_string(),
+            error_input: input.to_string(),
+        },
+    });
+}
+
+/// Longest wait for the output of a slash command
+const COMMAND_OUTPUT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+async fn slash_command(
+    terminal: &mut EchokitChild<ClaudeCode>,
+    name: &str,
+    args: &str,
+    pacing: &InputPacing,
+    pty_sub_tx: &ws::WsOutputTx,
+) {
+    let session_id = terminal.session_id().to_string();
+    let Some(command) = commands::find(name) else {
+        let _ = pty_sub_tx.send(WsOutputMessage::SessionError {
+            session_id,
+            code: invalid_input(format!(
+                "Unknown command /{}, known commands are {}",
+                name.trim_start_matches('/'),
+                commands::SLASH_COMMANDS
+                    .iter()
+                    .map(|command| format!("/{}", command.name))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+        });
+        return;
+    };
+    let input = match command.input(args) {
+        Ok(input) => input,
+        Err(e) => {
+            let _ = pty_sub_tx.send(WsOutputMessage::SessionError {
+                session_id,
+                code: invalid_input(e),
+            });
+            return;
+        }
+    };
+    if !terminal.state().input_available() {
+        let _ = pty_sub_tx.send(WsOutputMessage::SessionError {
+            session_id,
+            code: ws::WsOutputError::InvalidInputForState {
+                error_state: terminal.state().to_string(),
+                error_input: input,
+            },
+        });
+        return;
+    }
+
+    log::info!("[{}] Running {}", session_id, input);
+    submit_input(terminal, &input, pacing, pty_sub_tx).await;
+    if command.name == "clear" {
+        terminal.expect_new_history();
+    } else if command.name == "compact" && terminal.start_compacting() {
+        send_current_state(terminal, pty_sub_tx).await;
+    }
+
+    let output = if command.output == CommandOutput::None {
+        OutputChunk::default()
+    } else {
+        match terminal
+            .read_until_quiet(pacing.echo_timeout(), COMMAND_OUTPUT_TIMEOUT)
+            .await
+        {
+            Ok(output) => output,
+            Err(e) => {
+                log::warn!("[{}] Failed to read PTY output: {}", session_id, e);
+                OutputChunk::default()
+            }
+        }
+    };
+    let result = command.parse(&output.text);
+    forward_output(output, pty_sub_tx);
+
+    if command.dialog
+        && let Err(e) = terminal.send_esc().await
     {
+        log::warn!("[{}] Failed to close /{}: {}", session_id, command.name, e);
+    }
+
+    let _ = pty_sub_tx.send(WsOutputMessage::SessionCommandResult {
+        session_id,
+        command: command.name.to_string(),
+        result,
+    });
+}
+
+async fn set_mode(
+    terminal: &mut EchokitChild<ClaudeCode>,
+    mode: PermissionMode,
+    pacing: &InputPacing,
+    pty_sub_tx: &ws::WsOutputTx,
+) {
+    let session_id = terminal.session_id().to_string();
+    if !terminal.state().mode_switch_available() {
+        let _ = pty_sub_tx.send(WsOutputMessage::SessionError {
+            session_id,
+            code: ws::WsOutputError::InvalidInputForState {
+                error_state: terminal.state().to_string(),
+                error_input: format!("SetMode {}", mode),
+            },
+        });
+        return;
+    }
+
+    // a mode missing from the cycle ends the loop back at the starting mode
+    let start = terminal.permission_mode();
+    for _ in 0..PermissionMode::CYCLE_LEN {
+        if terminal.permission_mode() == mode {
+            break;
+        }
+        match terminal.cycle_permission_mode(pacing.echo_timeout()).await {
+            Ok((current, output)) => {
+                log::info!("[{}] Permission mode is now {}", session_id, current);
+                forward_output(output, pty_sub_tx);
+                if current == start {
+                    break;
+                }
+            }
+            Err(e) => {
+                let _ = pty_sub_tx.send(WsOutputMessage::SessionError {
+                    session_id,
+                    code: ws::WsOutputError::InternalError {
+                        error_message: format!("Failed to send Shift+Tab: {}", e),
+                    },
+                });
+                return;
+            }
+        }
+    }
+
+    if terminal.permission_mode() == mode {
+        send_current_state(terminal, pty_sub_tx).await;
+    } else {
+        let _ = pty_sub_tx.send(WsOutputMessage::SessionError {
+            session_id,
+            code: ws::WsOutputError::InvalidInput {
+                error_message: format!(
+                    "Could not switch to {} mode, the session is in {} mode",
+                    mode,
+                    terminal.permission_mode()
+                ),
+            },
+        });
+    }
+}
+
+async fn send_current_state(terminal: &EchokitChild<ClaudeCode>, pty_sub_tx: &ws::WsOutputTx) {
+    let session_id = terminal.session_id().to_string();
+    if !pty_sub_tx.send(WsOutputMessage::SessionState {
+        session_id: session_id.clone(),
+        current_state: terminal.state().clone(),
+        todos: terminal.todos().clone(),
+        permission_mode: terminal.permission_mode(),
+    }) {
         log::warn!("[{}] no active subscribers for current state", session_id);
     }
 }
+
+fn send_event(session_id: String, event: ClaudeCodeEvent, pty_sub_tx: &ws::WsOutputTx) {
+    if !pty_sub_tx.send(event_message(session_id.clone(), event)) {
+        log::warn!("[{}] no active subscribers for session event", session_id);
+    }
+}
+
+pub fn event_message(session_id: String, event: ClaudeCodeEvent) -> WsOutputMessage {
+    match event {
+        ClaudeCodeEvent::Subagent(subagent) => WsOutputMessage::SessionSubagent {
+            session_id,
+            subagent,
+        },
+        ClaudeCodeEvent::Todos(todos) => WsOutputMessage::SessionTodos { session_id, todos },
+        ClaudeCodeEvent::Compacted(compaction) => WsOutputMessage::SessionCompacted {
+            session_id,
+            compaction,
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn options(args: &[&str], env: &[&str]) -> ws::SessionOptions {
+        ws::SessionOptions {
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            env: env
+                .iter()
+                .map(|key| (key.to_string(), "1".to_string()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_check_allowed() {
+        let config = Config {
+            allowed_args: vec!["--verbose".to_string(), "--add-dir".to_string()],
+            allowed_env: vec!["MAX_THINKING_TOKENS".to_string()],
+            ..Default::default()
+        };
+
+        for args in [
+            &[][..],
+            &["--verbose"],
+            &["--add-dir=/x"],
+            &["--add-dir", "/x"],
+            &["--add-dir", "/x", "--verbose"],
+            &["--verbose", "--add-dir", "/x", "--add-dir", "/y"],
+        ] {
+            assert!(
+                check_allowed(&config, &options(args, &[])).is_ok(),
+                "{:?}",
+                args
+            );
+        }
+        for args in [
+            &["--dangerously-skip-permissions"][..],
+            &["--dangerously-skip-permissions=true"],
+            &["/x"],
+            &["--add-dir", "/x", "/y"],
+            &["--add-dir=/x", "/y"],
+            &["--add-dir", "--dangerously-skip-permissions"],
+        ] {
+            assert!(
+                check_allowed(&config, &options(args, &[])).is_err(),
+                "{:?}",
+                args
+            );
+        }
+
+        assert!(check_allowed(&config, &options(&[], &["MAX_THINKING_TOKENS"])).is_ok());
+        assert!(check_allowed(&config, &options(&[], &["ANTHROPIC_BASE_URL"])).is_err());
+        assert!(check_allowed(&Config::default(), &options(&["--verbose"], &[])).is_err());
+    }
+
+    #[test]
+    fn test_session_cwd() {
+        let dir = std::env::temp_dir().join(format!("echokit_cwd_{}", uuid::Uuid::new_v4()));
+        let root = dir.join("root");
+        std::fs::create_dir_all(root.join("project")).unwrap();
+        std::fs::create_dir_all(dir.join("outside")).unwrap();
+        std::os::unix::fs::symlink(dir.join("outside"), root.join("escape")).unwrap();
+        std::os::unix::fs::symlink("project", root.join("inside")).unwrap();
+
+        let config = Config {
+            working_path: dir.join("sessions"),
+            cwd_root: Some(root.clone()),
+            ..Default::default()
+        };
+        let uuid = uuid::Uuid::new_v4();
+        let cwd = |cwd: PathBuf| ws::SessionOptions {
+            cwd: Some(cwd.display().to_string()),
+            ..Default::default()
+        };
+
+        let project = root.join("project").canonicalize().unwrap();
+        let resolved = session_cwd(&config, &uuid, &cwd(root.join("project")), None);
+        assert_eq!(resolved.ok().unwrap(), project);
+        let resolved = session_cwd(&config, &uuid, &cwd(root.join("inside")), None);
+        assert_eq!(resolved.ok().unwrap(), project);
+
+        for escape in [
+            root.join(".."),
+            root.join("project/../../outside"),
+            root.join("escape"),
+            root.join("missing"),
+            PathBuf::from("/etc"),
+        ] {
+            let resolved = session_cwd(&config, &uuid, &cwd(escape.clone()), None);
+            assert!(resolved.is_err(), "{}", escape.display());
+        }
+
+        let mut repository = cwd(root.join("project"));
+        repository.repository = Some("repo".to_string());
+        assert!(session_cwd(&config, &uuid, &repository, None).is_err());
+        let disabled = Config {
+            cwd_root: None,
+            ..config.clone()
+        };
+        assert!(session_cwd(&disabled, &uuid, &cwd(root.join("project")), None).is_err());
+
+        let resolved = session_cwd(&config, &uuid, &ws::SessionOptions::default(), None);
+        assert_eq!(
+            resolved.ok().unwrap(),
+            dir.join("sessions").join(uuid.to_string())
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
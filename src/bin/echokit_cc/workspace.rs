@@ -0,0 +1,724 @@
+use std::{
+    io::Write,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use axum::{
+    Json,
+    extract::{Multipart, Path as UrlPath, Query, State},
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use echokit_terminal::terminal::recording;
+use tokio::io::AsyncWriteExt;
+
+use crate::ws::{self, WsInputMessage, WsOutputError, WsOutputMessage};
+
+/// Largest file served by the content endpoint
+const MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Largest workspace, before packing, served by the archive endpoint
+const MAX_ARCHIVE_SIZE: u64 = 512 * 1024 * 1024;
+
+/// Largest request body accepted by the upload endpoint
+pub const MAX_UPLOAD_SIZE: usize = 100 * 1024 * 1024;
+
+/// Uploads are refused once the workspace would grow beyond this size
+const WORKSPACE_QUOTA: u64 = 1024 * 1024 * 1024;
+
+pub struct WorkspaceError {
+    status: StatusCode,
+    session_id: String,
+    code: WsOutputError,
+}
+
+impl WorkspaceError {
+    pub fn new(status: StatusCode, session_id: &str, code: WsOutputError) -> Self {
+        Self {
+            status,
+            session_id: session_id.to_string(),
+            code,
+        }
+    }
+
+    pub fn invalid(session_id: &str, error_message: String) -> Self {
+        Self::new(
+            StatusCode::BAD_REQUEST,
+            session_id,
+            WsOutputError::InvalidInput { error_message },
+        )
+    }
+
+    /// The error as reported over the WebSocket
+    pub fn into_code(self) -> WsOutputError {
+        self.code
+    }
+
+    pub fn internal(session_id: &str, error_message: String) -> Self {
+        Self::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            session_id,
+            WsOutputError::InternalError { error_message },
+        )
+    }
+}
+
+impl IntoResponse for WorkspaceError {
+    fn into_response(self) -> Response {
+        let body = WsOutputMessage::SessionError {
+            session_id: self.session_id,
+            code: self.code,
+        };
+        (self.status, Json(body)).into_response()
+    }
+}
+
+/// The working directory of a session, `<working path>/<session id>` as
+/// created by `run_cc.sh`
+pub fn session_dir(working_path: &Path, session_id: &str) -> Result<PathBuf, WorkspaceError> {
+    let uuid = uuid::Uuid::parse_str(session_id)
+        .map_err(|e| WorkspaceError::invalid(session_id, format!("Invalid UUID format: {}", e)))?;
+
+    Ok(working_path.join(uuid.to_string()))
+}
+
+/// Like `session_dir`, but the directory must already exist
+pub fn session_root(working_path: &Path, session_id: &str) -> Result<PathBuf, WorkspaceError> {
+    let root = session_dir(working_path, session_id)?;
+    if !root.is_dir() {
+        return Err(WorkspaceError::new(
+            StatusCode::NOT_FOUND,
+            session_id,
+            WsOutputError::SessionNotFound,
+        ));
+    }
+    Ok(root)
+}
+
+/// Resolve `relative` inside `root`, rejecting absolute paths, `..` and
+/// symlinks that lead outside of the workspace
+pub fn resolve(root: &Path, relative: &str, session_id: &str) -> Result<PathBuf, WorkspaceError> {
+    let mut path = root.to_path_buf();
+    for component in Path::new(relative).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            _ => {
+                return Err(WorkspaceError::invalid(
+                    session_id,
+                    format!("Path must stay inside the workspace: {}", relative),
+                ));
+            }
+        }
+    }
+
+    let not_found = |_| {
+        WorkspaceError::new(
+            StatusCode::NOT_FOUND,
+            session_id,
+            WsOutputError::InvalidInput {
+                error_message: format!("No such file or directory: {}", relative),
+            },
+        )
+    };
+    let canonical = path.canonicalize().map_err(not_found)?;
+    let canonical_root = root
+        .canonicalize()
+        .map_err(|e| WorkspaceError::internal(session_id, e.to_string()))?;
+
+    if !canonical.starts_with(&canonical_root) {
+        return Err(WorkspaceError::invalid(
+            session_id,
+            format!("Path must stay inside the workspace: {}", relative),
+        ));
+    }
+    Ok(canonical)
+}
+
+#[derive(serde::Deserialize)]
+pub struct PathQuery {
+    #[serde(default)]
+    pub path: String,
+    #[serde(default)]
+    pub download: bool,
+}
+
+#[derive(serde::Serialize)]
+pub struct WorkspaceEntry {
+    pub name: String,
+    /// Relative to the session workspace
+    pub path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    /// Seconds since the unix epoch
+    pub modified: Option<u64>,
+}
+
+pub async fn api_list_files(
+    State(global_state): State<Arc<ws::GlobalState>>,
+    UrlPath(id): UrlPath<String>,
+    Query(query): Query<PathQuery>,
+) -> Result<impl IntoResponse, WorkspaceError> {
+    let root = session_root(&global_state.config.working_path, &id)?;
+    let dir = resolve(&root, &query.path, &id)?;
+    let canonical_root = root
+        .canonicalize()
+        .map_err(|e| WorkspaceError::internal(&id, e.to_string()))?;
+
+    let read_dir = std::fs::read_dir(&dir)
+        .map_err(|e| WorkspaceError::invalid(&id, format!("Failed to read directory: {}", e)))?;
+
+    let mut entries = Vec::new();
+    for entry in read_dir.flatten() {
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        let path = entry.path();
+        let relative = path.strip_prefix(&canonical_root).unwrap_or(&path);
+
+        entries.push(WorkspaceEntry {
+            name: entry.file_name().to_string_lossy().to_string(),
+            path: relative.to_string_lossy().to_string(),
+            is_dir: metadata.is_dir(),
+            size: metadata.len(),
+            modified: metadata
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|d| d.as_secs()),
+        });
+    }
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+
+    Ok(Json(serde_json::json!({
+        "session_id": id,
+        "path": query.path,
+        "entries": entries,
+    })))
+}
+
+/// Guess the MIME type from the extension, falling back to sniffing for text
+fn content_type(path: &Path, content: &[u8]) -> String {
+    match mime_guess::from_path(path).first() {
+        Some(mime) if mime.type_() == mime_guess::mime::TEXT => {
+            format!("{}; charset=utf-8", mime.essence_str())
+        }
+        Some(mime) => mime.essence_str().to_string(),
+        None if !content.contains(&0) && std::str::from_utf8(content).is_ok() => {
+            "text/plain; charset=utf-8".to_string()
+        }
+        None => "application/octet-stream".to_string(),
+    }
+}
+
+fn attachment(name: &str) -> String {
+    format!("attachment; filename=\"{}\"", name.replace('"', "_"))
+}
+
+pub async fn api_file_content(
+    State(global_state): State<Arc<ws::GlobalState>>,
+    UrlPath(id): UrlPath<String>,
+    Query(query): Query<PathQuery>,
+) -> Result<Response, WorkspaceError> {
+    let root = session_root(&global_state.config.working_path, &id)?;
+    let path = resolve(&root, &query.path, &id)?;
+
+    let metadata = tokio::fs::metadata(&path)
+        .await
+        .map_err(|e| WorkspaceError::internal(&id, e.to_string()))?;
+    if !metadata.is_file() {
+        return Err(WorkspaceError::invalid(
+            &id,
+            format!("Not a file: {}", query.path),
+        ));
+    }
+    if metadata.len() > MAX_FILE_SIZE {
+        return Err(WorkspaceError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            &id,
+            WsOutputError::InvalidInput {
+                error_message: format!(
+                    "File is {} bytes, the limit is {} bytes",
+                    metadata.len(),
+                    MAX_FILE_SIZE
+                ),
+            },
+        ));
+    }
+
+    let content = tokio::fs::read(&path)
+        .await
+        .map_err(|e| WorkspaceError::internal(&id, e.to_string()))?;
+
+    let mut response = (
+        [(header::CONTENT_TYPE, content_type(&path, &content))],
+        content,
+    )
+        .into_response();
+
+    if query.download {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if let Ok(value) = attachment(&name).parse() {
+            response
+                .headers_mut()
+                .insert(header::CONTENT_DISPOSITION, value);
+        }
+    }
+
+    Ok(response)
+}
+
+#[derive(Debug, Clone, Copy, Default, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchiveFormat {
+    #[default]
+    Tar,
+    Zip,
+}
+
+#[derive(serde::Deserialize)]
+pub struct ArchiveQuery {
+    #[serde(default)]
+    pub format: ArchiveFormat,
+}
+
+/// Every regular file and directory below `root`, symlinks are skipped so the
+/// archive cannot pick up anything outside of the workspace
+fn walk(root: &Path, dir: &Path, entries: &mut Vec<(PathBuf, std::fs::Metadata)>) {
+    let Ok(read_dir) = std::fs::read_dir(dir) else {
+        return;
+    };
+
+    for entry in read_dir.flatten() {
+        let path = entry.path();
+        let Ok(metadata) = std::fs::symlink_metadata(&path) else {
+            continue;
+        };
+        let relative = path.strip_prefix(root).unwrap_or(&path).to_path_buf();
+
+        if metadata.is_dir() {
+            entries.push((relative, metadata));
+            walk(root, &path, entries);
+        } else if metadata.is_file() {
+            entries.push((relative, metadata));
+        }
+    }
+}
+
+/// Bytes written to it become the chunks of a response body
+struct BodyWriter {
+    tx: tokio::sync::mpsc::Sender<std::io::Result<bytes::Bytes>>,
+    buffer: Vec<u8>,
+}
+
+impl BodyWriter {
+    const CHUNK_SIZE: usize = 64 * 1024;
+}
+
+impl Write for BodyWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= Self::CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    /// Blocks while the client is behind, fails once it went away
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::take(&mut self.buffer);
+        self.tx
+            .blocking_send(Ok(chunk.into()))
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))
+    }
+}
+
+fn pack<W: Write>(
+    root: &Path,
+    prefix: &str,
+    entries: &[(PathBuf, std::fs::Metadata)],
+    format: ArchiveFormat,
+    out: W,
+) -> std::io::Result<()> {
+    match format {
+        ArchiveFormat::Tar => {
+            let mut builder = tar::Builder::new(out);
+            builder.follow_symlinks(false);
+            for (relative, metadata) in entries {
+                let name = Path::new(prefix).join(relative);
+                if metadata.is_dir() {
+                    builder.append_dir(&name, root.join(relative))?;
+                } else {
+                    builder.append_path_with_name(root.join(relative), &name)?;
+                }
+            }
+            builder.into_inner()?.flush()
+        }
+        ArchiveFormat::Zip => {
+            let mut writer = zip::ZipWriter::new_stream(out);
+            let options = zip::write::SimpleFileOptions::default()
+                .compression_method(zip::CompressionMethod::Stored)
+                .large_file(true);
+
+            for (relative, metadata) in entries {
+                let name = format!("{}/{}", prefix, relative.to_string_lossy());
+                if metadata.is_dir() {
+                    writer
+                        .add_directory(name, options)
+                        .map_err(std::io::Error::other)?;
+                } else {
+                    writer
+                        .start_file(name, options)
+                        .map_err(std::io::Error::other)?;
+                    std::io::copy(&mut std::fs::File::open(root.join(relative))?, &mut writer)?;
+                }
+            }
+            writer
+                .finish()
+                .map_err(std::io::Error::other)?
+                .into_inner()
+                .flush()
+        }
+    }
+}
+
+pub async fn api_archive(
+    State(global_state): State<Arc<ws::GlobalState>>,
+    UrlPath(id): UrlPath<String>,
+    Query(query): Query<ArchiveQuery>,
+) -> Result<Response, WorkspaceError> {
+    let root = session_root(&global_state.config.working_path, &id)?;
+    let format = query.format;
+
+    let session_id = id.clone();
+    let (root, entries) = tokio::task::spawn_blocking(move || {
+        let mut entries = Vec::new();
+        walk(&root, &root, &mut entries);
+
+        let total: u64 = entries.iter().map(|(_, metadata)| metadata.len()).sum();
+        if total > MAX_ARCHIVE_SIZE {
+            return Err(WorkspaceError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                &session_id,
+                WsOutputError::InvalidInput {
+                    error_message: format!(
+                        "Workspace is {} bytes, the limit is {} bytes",
+                        total, MAX_ARCHIVE_SIZE
+                    ),
+                },
+            ));
+        }
+
+        Ok((root, entries))
+    })
+    .await
+    .map_err(|e| WorkspaceError::internal(&id, e.to_string()))??;
+
+    // packed while it is sent, only a few chunks are held in memory
+    let (tx, rx) = tokio::sync::mpsc::channel(4);
+    let prefix = id.clone();
+    tokio::task::spawn_blocking(move || {
+        let out = BodyWriter {
+            tx: tx.clone(),
+            buffer: Vec::new(),
+        };
+        if let Err(e) = pack(&root, &prefix, &entries, format, out) {
+            log::warn!("[{}] Failed to pack workspace: {}", prefix, e);
+            // ends the body with an error, the client sees a truncated archive
+            let _ = tx.blocking_send(Err(e));
+        }
+    });
+    let chunks = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+
+    let (content_type, extension) = match format {
+        ArchiveFormat::Tar => ("application/x-tar", "tar"),
+        ArchiveFormat::Zip => ("application/zip", "zip"),
+    };
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                attachment(&format!("{}.{}", id, extension)),
+            ),
+        ],
+        axum::body::Body::from_stream(chunks),
+    )
+        .into_response())
+}
+
+#[derive(serde::Deserialize)]
+pub struct RecordingQuery {
+    /// 0 for the current recording, `n` for the `n`th newest rotated one
+    #[serde(default)]
+    pub part: usize,
+}
+
+/// Download the asciicast recording of a session
+pub async fn api_recording(
+    State(global_state): State<Arc<ws::GlobalState>>,
+    UrlPath(id): UrlPath<String>,
+    Query(query): Query<RecordingQuery>,
+) -> Result<Response, WorkspaceError> {
+    let config = &global_state.config;
+    // only a valid UUID may become part of the file name
+    let session_id = session_dir(&config.working_path, &id)?
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let mut path = config.recording.path(&config.working_path, &session_id);
+    if query.part > 0 {
+        path = recording::rotated_path(&path, query.part);
+    }
+
+    let content = match tokio::fs::read(&path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(WorkspaceError::new(
+                StatusCode::NOT_FOUND,
+                &id,
+                WsOutputError::InvalidInput {
+                    error_message: "Session has no such recording".to_string(),
+                },
+            ));
+        }
+        Err(e) => return Err(WorkspaceError::internal(&id, e.to_string())),
+    };
+
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/x-asciicast".to_string()),
+            (header::CONTENT_DISPOSITION, attachment(&name)),
+        ],
+        content,
+    )
+        .into_response())
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct UploadedFile {
+    /// Relative to the session workspace
+    pub path: String,
+    pub size: u64,
+}
+
+#[derive(serde::Deserialize)]
+pub struct UploadQuery {
+    /// Directory inside the workspace to write to, defaults to its root
+    #[serde(default)]
+    pub path: String,
+    /// Tell Claude about the uploaded files with an `input` message
+    #[serde(default)]
+    pub notify: bool,
+}
+
+/// Keep only the final component of an uploaded file name
+fn sanitize_file_name(name: &str) -> Option<String> {
+    let name = name.replace('\\', "/");
+    let name = Path::new(&name).file_name()?.to_string_lossy();
+    let name = name
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect::<String>()
+        .trim()
+        .to_string();
+
+    if name.is_empty() || name.chars().all(|c| c == '.') {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+pub async fn api_upload_files(
+    State(global_state): State<Arc<ws::GlobalState>>,
+    UrlPath(id): UrlPath<String>,
+    Query(query): Query<UploadQuery>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, WorkspaceError> {
+    let root = session_dir(&global_state.config.working_path, &id)?;
+    tokio::fs::create_dir_all(&root)
+        .await
+        .map_err(|e| WorkspaceError::internal(&id, e.to_string()))?;
+    let dir = resolve(&root, &query.path, &id)?;
+    let canonical_root = root
+        .canonicalize()
+        .map_err(|e| WorkspaceError::internal(&id, e.to_string()))?;
+
+    let walk_root = root.clone();
+    let mut usage = tokio::task::spawn_blocking(move || {
+        let mut entries = Vec::new();
+        walk(&walk_root, &walk_root, &mut entries);
+        entries
+            .iter()
+            .map(|(_, metadata)| metadata.len())
+            .sum::<u64>()
+    })
+    .await
+    .map_err(|e| WorkspaceError::internal(&id, e.to_string()))?;
+
+    let mut files = Vec::new();
+    while let Some(mut field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| WorkspaceError::invalid(&id, format!("Invalid multipart body: {}", e)))?
+    {
+        let Some(file_name) = field.file_name() else {
+            continue;
+        };
+        let Some(name) = sanitize_file_name(file_name) else {
+            return Err(WorkspaceError::invalid(
+                &id,
+                format!("Invalid file name: {}", file_name),
+            ));
+        };
+
+        let target = dir.join(&name);
+        if tokio::fs::symlink_metadata(&target)
+            .await
+            .is_ok_and(|metadata| !metadata.is_file())
+        {
+            return Err(WorkspaceError::invalid(
+                &id,
+                format!("Refusing to overwrite {}", name),
+            ));
+        }
+
+        // write next to the target and rename, so a failed upload leaves no partial file
+        let temp = dir.join(format!(".{}.{}.upload", name, uuid::Uuid::new_v4()));
+        let mut file = tokio::fs::File::create(&temp)
+            .await
+            .map_err(|e| WorkspaceError::internal(&id, e.to_string()))?;
+
+        let mut size = 0u64;
+        let written = loop {
+            let chunk = match field.chunk().await {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => break Ok(()),
+                Err(e) => {
+                    break Err(WorkspaceError::invalid(
+                        &id,
+                        format!("Failed to read upload: {}", e),
+                    ));
+                }
+            };
+
+            size += chunk.len() as u64;
+            if usage + size > WORKSPACE_QUOTA {
+                break Err(WorkspaceError::new(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    &id,
+                    WsOutputError::InvalidInput {
+                        error_message: format!(
+                            "Workspace quota of {} bytes exceeded",
+                            WORKSPACE_QUOTA
+                        ),
+                    },
+                ));
+            }
+            if let Err(e) = file.write_all(&chunk).await {
+                break Err(WorkspaceError::internal(&id, e.to_string()));
+            }
+        };
+
+        let written = match written {
+            Ok(()) => file
+                .flush()
+                .await
+                .map_err(|e| WorkspaceError::internal(&id, e.to_string())),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            let _ = tokio::fs::remove_file(&temp).await;
+            return Err(e);
+        }
+
+        tokio::fs::rename(&temp, &target)
+            .await
+            .map_err(|e| WorkspaceError::internal(&id, e.to_string()))?;
+        usage += size;
+
+        let relative = target.strip_prefix(&canonical_root).unwrap_or(&target);
+        log::info!("[{}] Uploaded {} ({} bytes)", id, relative.display(), size);
+        files.push(UploadedFile {
+            path: relative.to_string_lossy().to_string(),
+            size,
+        });
+    }
+
+    if files.is_empty() {
+        return Err(WorkspaceError::invalid(
+            &id,
+            "No files in the upload".to_string(),
+        ));
+    }
+
+    // a session without a process answers the notice with `session_not_found`
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    if global_state.tx.send((id.clone(), tx)).is_ok()
+        && let Ok((_, input_tx, _)) = rx.await
+    {
+        let _ = input_tx.send(WsInputMessage::FileUploaded {
+            files: files.clone(),
+            notify: query.notify,
+        });
+    }
+
+    Ok(Json(serde_json::json!({
+        "session_id": id,
+        "files": files,
+    })))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let dir = std::env::temp_dir().join(format!("echokit_workspace_{}", uuid::Uuid::new_v4()));
+        let root = dir.join("root");
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::write(root.join("src/main.rs"), "").unwrap();
+        std::fs::write(dir.join("secret"), "").unwrap();
+        std::os::unix::fs::symlink(&dir, root.join("escape")).unwrap();
+        std::os::unix::fs::symlink("src", root.join("inside")).unwrap();
+
+        let canonical_root = root.canonicalize().unwrap();
+        let resolved = resolve(&root, "./src/main.rs", "id").ok().unwrap();
+        assert_eq!(resolved, canonical_root.join("src/main.rs"));
+        let resolved = resolve(&root, "inside/main.rs", "id").ok().unwrap();
+        assert_eq!(resolved, canonical_root.join("src/main.rs"));
+        assert!(resolve(&root, "", "id").is_ok());
+
+        for relative in [
+            "../secret",
+            "src/../../secret",
+            "/etc/passwd",
+            "escape/secret",
+            "escape",
+        ] {
+            let error = resolve(&root, relative, "id").err().unwrap();
+            assert_eq!(error.status, StatusCode::BAD_REQUEST, "{}", relative);
+        }
+        let error = resolve(&root, "missing", "id").err().unwrap();
+        assert_eq!(error.status, StatusCode::NOT_FOUND);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,389 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use axum::{
+    Json,
+    extract::{Path as UrlPath, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+
+use crate::workspace::{self, WorkspaceError};
+use crate::ws::{self, WsOutputError};
+
+/// Git config key recording the commit a session branch was created from
+const BASE_CONFIG_KEY: &str = "echokitBase";
+
+/// Branch created for the worktree session in `dir`, named after the session
+/// id as `workspace::session_dir` spells it
+fn branch_name(dir: &Path) -> String {
+    format!(
+        "echokit/{}",
+        dir.file_name().unwrap_or_default().to_string_lossy()
+    )
+}
+
+/// Run git in `dir`, returning stdout or the trimmed stderr on failure
+async fn git(dir: &Path, args: &[&str]) -> Result<String, String> {
+    let output = tokio::process::Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| format!("Failed to run git: {}", e))?;
+
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    } else {
+        Err(format!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+/// Whether `dir` is a linked worktree, whose `.git` is a file pointing at the
+/// main repository
+pub fn is_worktree(dir: &Path) -> bool {
+    dir.join(".git").is_file()
+}
+
+/// Create `<working path>/<session id>` as a worktree of `repository` on a new
+/// `echokit/<session id>` branch starting at `base_ref` (default `HEAD`).
+///
+/// `repository` must live under `repository_root`, repositories are refused
+/// when no root is configured. An existing worktree is reused so that resumed
+/// sessions keep their branch. Returns the worktree and whether it was created
+/// now.
+pub async fn create(
+    repository_root: Option<&Path>,
+    working_path: &Path,
+    session_id: &str,
+    repository: &str,
+    base_ref: Option<&str>,
+) -> Result<(PathBuf, bool), WsOutputError> {
+    let invalid = |error_message: String| WsOutputError::InvalidInput { error_message };
+
+    let dir =
+        workspace::session_dir(working_path, session_id).map_err(WorkspaceError::into_code)?;
+    if is_worktree(&dir) {
+        return Ok((dir, false));
+    }
+    if dir.exists() {
+        return Err(invalid(format!(
+            "Session {} already has a workspace that is not a git worktree",
+            session_id
+        )));
+    }
+
+    let Some(repository_root) = repository_root else {
+        return Err(invalid(
+            "Repository sessions are disabled, start echokit_cc with --repository-root".to_string(),
+        ));
+    };
+    let root = repository_root
+        .canonicalize()
+        .map_err(|e| invalid(format!("Invalid repository root: {}", e)))?;
+    let repository = Path::new(repository)
+        .canonicalize()
+        .map_err(|e| invalid(format!("Invalid repository {}: {}", repository, e)))?;
+    if !repository.starts_with(&root) {
+        return Err(invalid(format!(
+            "Repository must be inside {}",
+            root.display()
+        )));
+    }
+
+    let base_ref = base_ref.unwrap_or("HEAD");
+    let base = git(
+        &repository,
+        &["rev-parse", "--verify", &format!("{}^{{commit}}", base_ref)],
+    )
+    .await
+    .map_err(invalid)?;
+    let base = base.trim();
+
+    std::fs::create_dir_all(working_path).map_err(|e| WsOutputError::InternalError {
+        error_message: format!("Failed to create working path: {}", e),
+    })?;
+
+    let branch = branch_name(&dir);
+    let internal = |error_message: String| WsOutputError::InternalError { error_message };
+    git(
+        &repository,
+        &[
+            "worktree",
+            "add",
+            "-b",
+            &branch,
+            &dir.to_string_lossy(),
+            base,
+        ],
+    )
+    .await
+    .map_err(internal)?;
+    git(
+        &repository,
+        &[
+            "config",
+            &format!("branch.{}.{}", branch, BASE_CONFIG_KEY),
+            base,
+        ],
+    )
+    .await
+    .map_err(internal)?;
+
+    log::info!(
+        "[{}] Created worktree {} on {} from {}",
+        session_id,
+        dir.display(),
+        branch,
+        base
+    );
+    Ok((dir, true))
+}
+
+/// Files of the worktree with uncommitted changes, untracked ones included
+async fn dirty_files(dir: &Path) -> Result<Vec<String>, String> {
+    let status = git(dir, &["status", "--porcelain"]).await?;
+    Ok(status
+        .lines()
+        .filter_map(|line| line.get(3..))
+        .map(str::to_string)
+        .collect())
+}
+
+/// Remove the worktree of a session, and its branch when `delete_branch` is set.
+/// Uncommitted changes are thrown away only with `force`, git refuses to remove
+/// a dirty worktree otherwise. Returns `false` when the session workspace is
+/// not a worktree.
+pub async fn remove(
+    dir: &Path,
+    session_id: &str,
+    delete_branch: bool,
+    force: bool,
+) -> Result<bool, String> {
+    if !is_worktree(dir) {
+        return Ok(false);
+    }
+
+    // `git worktree remove` refuses to run from inside the worktree it removes
+    let common_dir = git(
+        dir,
+        &["rev-parse", "--path-format=absolute", "--git-common-dir"],
+    )
+    .await?;
+    let common_dir = PathBuf::from(common_dir.trim());
+
+    let dir_arg = dir.to_string_lossy();
+    let mut args = vec!["worktree", "remove"];
+    if force {
+        args.push("--force");
+    }
+    args.push(&dir_arg);
+    git(&common_dir, &args).await?;
+
+    if delete_branch {
+        let branch = branch_name(dir);
+        git(&common_dir, &["branch", "-D", &branch]).await?;
+        let _ = git(
+            &common_dir,
+            &[
+                "config",
+                "--unset",
+                &format!("branch.{}.{}", branch, BASE_CONFIG_KEY),
+            ],
+        )
+        .await;
+    }
+
+    log::info!("[{}] Removed worktree {}", session_id, dir.display());
+    Ok(true)
+}
+
+/// The worktree of a session, 404 when the session does not use one
+fn session_worktree(working_path: &Path, session_id: &str) -> Result<PathBuf, WorkspaceError> {
+    let dir = workspace::session_root(working_path, session_id)?;
+    if !is_worktree(&dir) {
+        return Err(WorkspaceError::new(
+            StatusCode::NOT_FOUND,
+            session_id,
+            WsOutputError::InvalidInput {
+                error_message: "Session workspace is not a git worktree".to_string(),
+            },
+        ));
+    }
+    Ok(dir)
+}
+
+#[derive(serde::Serialize)]
+pub struct WorktreeStatus {
+    pub session_id: String,
+    pub branch: String,
+    /// The commit the branch was created from
+    pub base: String,
+    pub head: String,
+    /// `git diff <base>`, committed and uncommitted changes of tracked files
+    pub diff: String,
+    /// New files not yet added to git
+    pub untracked: Vec<String>,
+}
+
+pub async fn api_worktree(
+    State(global_state): State<Arc<ws::GlobalState>>,
+    UrlPath(id): UrlPath<String>,
+) -> Result<impl IntoResponse, WorkspaceError> {
+    let dir = session_worktree(&global_state.config.working_path, &id)?;
+    let internal = |e: String| WorkspaceError::internal(&id, e);
+
+    let branch = git(&dir, &["rev-parse", "--abbrev-ref", "HEAD"])
+        .await
+        .map_err(internal)?
+        .trim()
+        .to_string();
+    let base = git(
+        &dir,
+        &["config", &format!("branch.{}.{}", branch, BASE_CONFIG_KEY)],
+    )
+    .await
+    .map_err(internal)?
+    .trim()
+    .to_string();
+    let head = git(&dir, &["rev-parse", "HEAD"])
+        .await
+        .map_err(internal)?
+        .trim()
+        .to_string();
+    let diff = git(&dir, &["diff", &base]).await.map_err(internal)?;
+    let untracked = git(&dir, &["ls-files", "--others", "--exclude-standard"])
+        .await
+        .map_err(internal)?
+        .lines()
+        .map(str::to_string)
+        .collect();
+
+    Ok(Json(WorktreeStatus {
+        session_id: id,
+        branch,
+        base,
+        head,
+        diff,
+        untracked,
+    }))
+}
+
+#[derive(serde::Deserialize)]
+pub struct CommitRequest {
+    pub message: String,
+}
+
+/// Stage everything in the worktree and commit it on the session branch
+pub async fn api_commit(
+    State(global_state): State<Arc<ws::GlobalState>>,
+    UrlPath(id): UrlPath<String>,
+    Json(body): Json<CommitRequest>,
+) -> Result<impl IntoResponse, WorkspaceError> {
+    let dir = session_worktree(&global_state.config.working_path, &id)?;
+    if body.message.trim().is_empty() {
+        return Err(WorkspaceError::invalid(
+            &id,
+            "Commit message must not be empty".to_string(),
+        ));
+    }
+
+    git(&dir, &["add", "-A"])
+        .await
+        .map_err(|e| WorkspaceError::internal(&id, e))?;
+    if git(&dir, &["diff", "--cached", "--quiet"]).await.is_ok() {
+        return Err(WorkspaceError::invalid(
+            &id,
+            "Nothing to commit".to_string(),
+        ));
+    }
+    git(&dir, &["commit", "-m", &body.message])
+        .await
+        .map_err(|e| WorkspaceError::internal(&id, e))?;
+
+    let commit = git(&dir, &["rev-parse", "HEAD"])
+        .await
+        .map_err(|e| WorkspaceError::internal(&id, e))?;
+
+    Ok(Json(serde_json::json!({
+        "session_id": id,
+        "commit": commit.trim(),
+    })))
+}
+
+#[derive(serde::Deserialize)]
+pub struct DeleteQuery {
+    #[serde(default)]
+    pub delete_branch: bool,
+    /// Remove the worktree even with uncommitted changes
+    #[serde(default)]
+    pub force: bool,
+}
+
+/// Close the session and remove its worktree, the branch is kept unless
+/// `?delete_branch=true`. A worktree with uncommitted changes is kept and the
+/// session left running, 409 lists the changed files, unless `?force=true`.
+pub async fn api_delete_session(
+    State(global_state): State<Arc<ws::GlobalState>>,
+    UrlPath(id): UrlPath<String>,
+    Query(query): Query<DeleteQuery>,
+) -> Result<impl IntoResponse, WorkspaceError> {
+    let dir = workspace::session_dir(&global_state.config.working_path, &id)?;
+
+    if !query.force && is_worktree(&dir) {
+        let dirty = dirty_files(&dir)
+            .await
+            .map_err(|e| WorkspaceError::internal(&id, e))?;
+        if !dirty.is_empty() {
+            return Err(WorkspaceError::new(
+                StatusCode::CONFLICT,
+                &id,
+                WsOutputError::InvalidInput {
+                    error_message: format!(
+                        "Worktree has uncommitted changes, commit them or delete with force=true: {}",
+                        dirty.join(", ")
+                    ),
+                },
+            ));
+        }
+    }
+
+    let closed = tokio::time::timeout(
+        global_state.config.timeouts.close(),
+        crate::request_session(
+            &global_state,
+            id.clone(),
+            ws::WsInputMessage::CloseSession {},
+            |e| {
+                matches!(
+                    e,
+                    ws::WsOutputMessage::SessionEnded { .. }
+                        | ws::WsOutputMessage::SessionError { .. }
+                )
+            },
+        ),
+    )
+    .await;
+    if closed.is_err() {
+        return Err(WorkspaceError::internal(
+            &id,
+            "Timed out waiting for the session to end".to_string(),
+        ));
+    }
+
+    let worktree_removed = remove(&dir, &id, query.delete_branch, query.force)
+        .await
+        .map_err(|e| WorkspaceError::internal(&id, e))?;
+
+    Ok(Json(serde_json::json!({
+        "session_id": id,
+        "worktree_removed": worktree_removed,
+    })))
+}
@@ -1,17 +1,39 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
 use axum::extract::ws::{Message, WebSocket};
-use echokit_terminal::terminal::claude::ClaudeCodeState;
+use echokit_terminal::terminal::{
+    OutputChunk,
+    changes::FileChange,
+    claude::{ClaudeCodeState, Compaction, PermissionMode, Subagent, TodoProgress},
+    commands::CommandResult,
+    keys::KeyInput,
+    output::{OutputBroadcast, OutputMessage},
+};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::config::Config;
+use crate::workspace::UploadedFile;
 
 #[derive(Debug, Clone, serde::Deserialize)]
 #[serde(tag = "type")]
 pub enum WsInputMessage {
     #[serde(alias = "create_session")]
-    CreateSession {},
+    CreateSession {
+        #[serde(flatten)]
+        options: SessionOptions,
+    },
+    #[serde(alias = "close_session")]
+    CloseSession {},
     #[serde(alias = "get_current_state")]
     CurrentState {},
     #[serde(alias = "input")]
     Input { input: String },
+    /// Always submitted as one bracketed paste, for code snippets and logs
+    #[serde(alias = "input_multiline")]
+    InputMultiline { input: String },
+    /// A key press like Shift+Tab or Ctrl+C, encoded for xterm
+    #[serde(alias = "key")]
+    Key(KeyInput),
     #[serde(alias = "bytes_input")]
     BytesInput { input: Vec<u8> },
     #[serde(alias = "cancel")]
@@ -20,6 +42,131 @@ pub enum WsInputMessage {
     Confirm {},
     #[serde(alias = "select")]
     Select { index: usize },
+    /// Approve a proposed plan, with `auto_accept` edits are no longer confirmed
+    #[serde(alias = "approve_plan")]
+    ApprovePlan {
+        #[serde(default)]
+        auto_accept: bool,
+    },
+    /// Reject a proposed plan and send `feedback` as the next prompt
+    #[serde(alias = "reject_plan")]
+    RejectPlan {
+        #[serde(default)]
+        feedback: Option<String>,
+    },
+    /// Run a known slash command, answered with `session_command_result`
+    #[serde(alias = "slash_command")]
+    SlashCommand {
+        name: String,
+        #[serde(default)]
+        args: String,
+    },
+    /// Cycle the permission mode with Shift+Tab until `mode` is active
+    #[serde(alias = "set_mode")]
+    SetMode { mode: PermissionMode },
+    /// Answered with `session_changes` to `reply` only, not to every client
+    #[serde(alias = "get_changes")]
+    GetChanges {
+        #[serde(default)]
+        content: bool,
+        #[serde(skip)]
+        reply: Option<WsReplyTx>,
+    },
+    /// Sent by the upload API once the files are written to the workspace
+    #[serde(skip)]
+    FileUploaded {
+        files: Vec<UploadedFile>,
+        notify: bool,
+    },
+}
+
+/// How a new session is started, every field is optional
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+pub struct SessionOptions {
+    /// Name of a profile from the config file
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// Run the session in a new git worktree of this repository
+    #[serde(default)]
+    pub repository: Option<String>,
+    /// Commit the worktree branch starts from, defaults to `HEAD`
+    #[serde(default)]
+    pub base_ref: Option<String>,
+    /// Working directory, must be inside `--cwd-root`
+    #[serde(default)]
+    pub cwd: Option<String>,
+    #[serde(default)]
+    pub model: Option<String>,
+    /// One of `default`, `acceptEdits`, `plan` or `bypassPermissions`
+    #[serde(default)]
+    pub permission_mode: Option<String>,
+    /// Extra arguments appended to the claude command line, only flags of
+    /// the `allowed_args` config and their values
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Only variables of the `allowed_env` config
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    #[serde(default)]
+    pub rows: Option<u16>,
+    #[serde(default)]
+    pub cols: Option<u16>,
+}
+
+impl SessionOptions {
+    pub fn size(&self) -> (u16, u16) {
+        (self.rows.unwrap_or(24), self.cols.unwrap_or(80))
+    }
+
+    /// Whether claude has to be launched directly instead of through the
+    /// `--claude-command` script
+    pub fn is_custom(&self) -> bool {
+        self.profile.is_some()
+            || self.cwd.is_some()
+            || self.model.is_some()
+            || self.permission_mode.is_some()
+            || !self.args.is_empty()
+            || !self.env.is_empty()
+    }
+}
+
+impl WsOutputMessage {
+    pub fn pty_output(output: OutputChunk) -> Self {
+        WsOutputMessage::SessionPtyOutput {
+            output: output.text,
+            bytes: output.bytes,
+        }
+    }
+}
+
+impl OutputMessage for WsOutputMessage {
+    fn from_output(output: OutputChunk) -> Self {
+        WsOutputMessage::pty_output(output)
+    }
+
+    fn into_output(self) -> Result<OutputChunk, Self> {
+        match self {
+            WsOutputMessage::SessionPtyOutput { output, bytes } => Ok(OutputChunk {
+                bytes,
+                text: output,
+            }),
+            message => Err(message),
+        }
+    }
+}
+
+impl WsInputMessage {
+    /// Queries answered from session state, and upload notices only a running
+    /// session takes. They never start a new session.
+    pub fn is_query(&self) -> bool {
+        matches!(
+            self,
+            WsInputMessage::CurrentState {}
+                | WsInputMessage::CloseSession {}
+                | WsInputMessage::GetChanges { .. }
+                | WsInputMessage::FileUploaded { .. }
+        )
+    }
 }
 
 #[derive(Debug, Clone, serde::Serialize)]
@@ -39,13 +186,24 @@ pub enum WsOutputError {
     InternalError {
         error_message: String,
     },
+    #[serde(rename = "budget_exceeded")]
+    BudgetExceeded {
+        used_tokens: u64,
+        max_tokens: u64,
+    },
 }
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Clone, serde::Serialize)]
 #[serde(tag = "type")]
 pub enum WsOutputMessage {
+    /// `bytes` are the output exactly as read, sent in binary frames
     #[serde(rename = "session_pty_output")]
-    SessionPtyOutput { output: String },
+    SessionPtyOutput {
+        output: String,
+        #[serde(skip)]
+        bytes: Vec<u8>,
+    },
     #[serde(rename = "session_ended")]
     SessionEnded { session_id: String },
     #[serde(rename = "session_idle")]
@@ -54,6 +212,51 @@ pub enum WsOutputMessage {
     SessionState {
         session_id: String,
         current_state: ClaudeCodeState,
+        todos: TodoProgress,
+        permission_mode: PermissionMode,
+    },
+    #[serde(rename = "session_subagent")]
+    SessionSubagent {
+        session_id: String,
+        subagent: Subagent,
+    },
+    #[serde(rename = "session_todos")]
+    SessionTodos {
+        session_id: String,
+        todos: TodoProgress,
+    },
+    #[serde(rename = "session_compacted")]
+    SessionCompacted {
+        session_id: String,
+        #[serde(flatten)]
+        compaction: Compaction,
+    },
+    #[serde(rename = "session_command_result")]
+    SessionCommandResult {
+        session_id: String,
+        command: String,
+        result: CommandResult,
+    },
+    #[serde(rename = "session_changes")]
+    SessionChanges {
+        session_id: String,
+        changes: Vec<FileChange>,
+    },
+    #[serde(rename = "session_file_uploaded")]
+    SessionFileUploaded {
+        session_id: String,
+        files: Vec<UploadedFile>,
+    },
+    /// Where a replay is, sent whenever it starts, stops, seeks or changes
+    /// speed
+    #[serde(rename = "session_replay")]
+    SessionReplay {
+        session_id: String,
+        /// Seconds into the recording
+        time: f64,
+        duration: f64,
+        paused: bool,
+        speed: f64,
     },
     #[serde(rename = "session_error")]
     SessionError {
@@ -64,44 +267,91 @@ pub enum WsOutputMessage {
 }
 
 pub type WsOutputRx = tokio::sync::broadcast::Receiver<WsOutputMessage>;
-pub type WsOutputTx = tokio::sync::broadcast::Sender<WsOutputMessage>;
+pub type WsOutputTx = Arc<OutputBroadcast<WsOutputMessage>>;
 pub type WsInputRx = tokio::sync::mpsc::UnboundedReceiver<WsInputMessage>;
 pub type WsInputTx = tokio::sync::mpsc::UnboundedSender<WsInputMessage>;
+/// Answers to a single client, for requests that are not broadcast
+pub type WsReplyTx = tokio::sync::mpsc::UnboundedSender<WsOutputMessage>;
+pub type WsReplyRx = tokio::sync::mpsc::UnboundedReceiver<WsOutputMessage>;
 
 #[allow(dead_code)]
-pub type RxReceiver = tokio::sync::oneshot::Receiver<(WsOutputRx, WsInputTx)>;
-pub type RxSender = tokio::sync::oneshot::Sender<(WsOutputRx, WsInputTx)>;
+pub type RxReceiver = tokio::sync::oneshot::Receiver<(WsOutputRx, WsInputTx, WsOutputTx)>;
+pub type RxSender = tokio::sync::oneshot::Sender<(WsOutputRx, WsInputTx, WsOutputTx)>;
 
 pub struct GlobalState {
     pub tx: tokio::sync::mpsc::UnboundedSender<(String, RxSender)>,
+    pub config: Arc<Config>,
 }
 
 impl GlobalState {
-    pub fn new(tx: tokio::sync::mpsc::UnboundedSender<(String, RxSender)>) -> Self {
-        Self { tx }
+    pub fn new(
+        tx: tokio::sync::mpsc::UnboundedSender<(String, RxSender)>,
+        config: Arc<Config>,
+    ) -> Self {
+        Self { tx, config }
     }
 }
 
 enum Event {
     WebSocketInput(Result<Message, axum::Error>),
     PtyOutput(WsOutputMessage),
+    /// An answer to a request of this client only
+    Reply(WsOutputMessage),
+    /// The client fell this many messages behind the session
+    Lagged(u64),
 }
 
 async fn select_event(
     socket: &mut WebSocket,
-    rx: &mut tokio::sync::broadcast::Receiver<WsOutputMessage>,
+    rx: &mut WsOutputRx,
+    reply_rx: &mut WsReplyRx,
 ) -> Option<Event> {
     tokio::select! {
-        Ok(msg) = rx.recv() => Some(Event::PtyOutput(msg)),
+        msg = rx.recv() => match msg {
+            Ok(msg) => Some(Event::PtyOutput(msg)),
+            Err(RecvError::Lagged(skipped)) => Some(Event::Lagged(skipped)),
+            Err(RecvError::Closed) => None,
+        },
+        Some(msg) = reply_rx.recv() => Some(Event::Reply(msg)),
         Some(msg) = socket.recv() => Some(Event::WebSocketInput(msg)),
         else => None,
     }
 }
 
+/// A message as sent to a client, PTY output is a binary frame when it asked
+/// for them
+pub fn output_frame(output: WsOutputMessage, binary: bool) -> Message {
+    match output {
+        WsOutputMessage::SessionPtyOutput { bytes, .. } if binary => Message::Binary(bytes.into()),
+        output => Message::Text(serde_json::to_string(&output).unwrap().into()),
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct WebSocketQuery {
+    /// Send `session_pty_output` as binary frames holding only the output
+    #[serde(default)]
+    pub binary: bool,
+    /// Replay the recording of the session instead of attaching to it
+    #[serde(default)]
+    pub replay: bool,
+    /// Playback speed of a replay
+    #[serde(default = "default_speed")]
+    pub speed: f64,
+    /// Replay the `part`th newest rotated recording, 0 for the current one
+    #[serde(default)]
+    pub part: usize,
+}
+
+fn default_speed() -> f64 {
+    1.0
+}
+
 pub async fn websocket(
     session_id: String,
     mut socket: WebSocket,
     global_state: Arc<GlobalState>,
+    query: WebSocketQuery,
 ) -> anyhow::Result<()> {
     let (rx_sender, rx_receiver) = tokio::sync::oneshot::channel();
 
@@ -113,30 +363,50 @@ pub async fn websocket(
             anyhow::anyhow!("Manager Rx is closed")
         })?;
 
-    let (mut rx, tx) = rx_receiver.await.map_err(|_| {
+    let (mut rx, tx, output) = rx_receiver.await.map_err(|_| {
         log::error!("[{session_id}] request failed, receive Rx from sessions manager");
         anyhow::anyhow!("Failed to receive Rx from sessions manager")
     })?;
 
+    let (reply_tx, mut reply_rx) = tokio::sync::mpsc::unbounded_channel();
+
     loop {
-        let event = select_event(&mut socket, &mut rx).await;
+        let event = select_event(&mut socket, &mut rx, &mut reply_rx).await;
 
         match event {
-            Some(Event::PtyOutput(output)) => {
+            Some(Event::PtyOutput(output) | Event::Reply(output)) => {
                 if socket
-                    .send(Message::Text(
-                        serde_json::to_string(&output).unwrap().into(),
-                    ))
+                    .send(output_frame(output, query.binary))
                     .await
                     .is_err()
                 {
                     break;
                 }
             }
+            Some(Event::Lagged(skipped)) => {
+                log::warn!("[{session_id}] client lagged {skipped} messages behind, resyncing");
+                let (screen, resynced) = output.resync(&rx);
+                rx = resynced;
+                let screen = WsOutputMessage::pty_output(screen);
+                if socket
+                    .send(output_frame(screen, query.binary))
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+                // the skipped messages may have changed the state
+                if tx.send(WsInputMessage::CurrentState {}).is_err() {
+                    break;
+                }
+            }
             Some(Event::WebSocketInput(Ok(msg))) => match msg {
                 Message::Text(text) => {
                     let input_message = serde_json::from_str::<WsInputMessage>(&text);
-                    if let Ok(input_message) = input_message {
+                    if let Ok(mut input_message) = input_message {
+                        if let WsInputMessage::GetChanges { reply, .. } = &mut input_message {
+                            *reply = Some(reply_tx.clone());
+                        }
                         if tx.send(input_message).is_err() {
                             log::error!("[{session_id}] request failed, send input message");
                             break;
@@ -162,12 +432,10 @@ pub async fn websocket(
                     }
                 }
                 Message::Binary(bytes) => {
-                    if tx
-                        .send(WsInputMessage::BytesInput {
-                            input: bytes.to_vec(),
-                        })
-                        .is_err()
-                    {
+                    let input = WsInputMessage::BytesInput {
+                        input: bytes.to_vec(),
+                    };
+                    if tx.send(input).is_err() {
                         log::error!("[{session_id}] request failed, send bytes input message");
                         break;
                     }
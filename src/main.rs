@@ -1,26 +1,38 @@
 use axum::{
     Json, Router,
     extract::{
-        State,
+        Path, Query, State,
         ws::{Message, WebSocket, WebSocketUpgrade},
     },
     response::IntoResponse,
-    routing::{get, get_service, post},
+    routing::{delete, get, get_service, post},
 };
 use clap::Parser;
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::{
+    OnceCell,
+    broadcast::{self, error::RecvError},
+    mpsc, oneshot, watch,
+};
 use tower_http::services::ServeDir;
 
-use echokit_terminal::{
-    terminal::{self, InputItem, claude::ClaudeCodeResult},
-    types::claude::ClaudeCodeLog,
+use echokit_terminal::terminal::{
+    self, InputItem, OutputChunk,
+    driver::{self, TerminalDriver, TerminalRequest},
+    output::OutputBroadcast,
+    pacing::{InputPacing, SubmitMode},
+    shell::{Bash, CustomShell, Fish, RcInjection, Sh, ShellStatus, ShellType, Zsh},
 };
 
 #[derive(Parser)]
 #[command(name = "echokit_terminal")]
 #[command(about = "A terminal for some special shells with web interface", long_about = None)]
 struct Args {
-    /// The shell/command to launch for new connections
+    /// The shell/command each session runs, started when the session is
+    /// first requested
     #[arg(short, long, default_value = "claude", env = "TERMINAL_SHELL")]
     shell: String,
 
@@ -31,51 +43,452 @@ struct Args {
     /// Additional arguments to pass to the shell
     #[arg(long)]
     shell_args: Vec<String>,
+
+    /// Press Enter once the terminal echoes the typed text, instead of after
+    /// a fixed delay
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    wait_for_echo: bool,
+
+    /// Longest wait for the echo, in milliseconds
+    #[arg(long, default_value_t = 2000)]
+    echo_timeout_ms: u64,
+
+    /// Fixed delay before Enter when not waiting for the echo, in milliseconds
+    #[arg(long, default_value_t = 500)]
+    enter_delay_ms: u64,
+
+    /// Start the shell of a session again when it exits
+    #[arg(long, value_enum, default_value_t = Respawn::Never)]
+    respawn: Respawn,
+
+    /// Respawns of one session before it ends for good, 0 for no limit
+    #[arg(long, default_value_t = 5)]
+    max_respawns: u32,
+
+    /// Longest `timeout_ms` of `POST /api/{id}/exec`, in milliseconds
+    #[arg(long, default_value_t = 60_000)]
+    max_exec_timeout_ms: u64,
+
+    /// Sessions running at once, requests for another new one are refused.
+    /// 0 for no limit.
+    #[arg(long, default_value_t = 32)]
+    max_sessions: u32,
+
+    /// Delay before a respawn, in milliseconds
+    #[arg(long, default_value_t = 1000)]
+    respawn_delay_ms: u64,
+
+    /// Run a command other than bash, zsh, fish, sh and claude as a custom
+    /// shell started with `-i`, instead of a plain terminal
+    #[arg(long)]
+    interactive: bool,
+
+    /// An rc file for such a command to load as a custom shell, like its own
+    /// shell integration
+    #[arg(long)]
+    rc_file: Option<std::path::PathBuf>,
+
+    /// The argument passing the rc file to the shell, like `--rcfile`.
+    /// Without it the path goes in the variable `--rc-env`.
+    #[arg(long)]
+    rc_flag: Option<String>,
+
+    #[arg(long, default_value = "ENV")]
+    rc_env: String,
+
+    /// PTY output messages kept for each client, one falling further behind
+    /// is resynced with the screen
+    #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u32).range(1..))]
+    output_buffer: u32,
+
+    /// Close a session once it had no client for this many seconds, 0 to
+    /// keep sessions until their shell exits or `DELETE /api/{id}`
+    #[arg(long, default_value_t = 3600)]
+    idle_timeout_secs: u64,
+
+    /// Send PTY output once this many bytes are pending
+    #[arg(long, default_value_t = 16 * 1024)]
+    output_coalesce_bytes: usize,
+
+    /// Send PTY output once the oldest pending read is this old, in
+    /// milliseconds. 0 sends every read on its own.
+    #[arg(long, default_value_t = 10)]
+    output_coalesce_ms: u64,
+}
+
+/// Rows and columns of every terminal
+const TERMINAL_SIZE: (u16, u16) = (24, 80);
+
+/// Longest time between two looks for idle sessions
+const REAP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// When the shell of a session is started again after it exited
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Respawn {
+    Never,
+    /// Only after a non-zero exit status or a failing terminal
+    OnFailure,
+    Always,
+}
+
+/// How the shell of every session is started
+struct ShellOptions {
+    shell: String,
+    shell_args: Vec<String>,
+    pacing: InputPacing,
+    respawn: Respawn,
+    max_respawns: u32,
+    respawn_delay: std::time::Duration,
+    max_sessions: u32,
+    max_exec_timeout_ms: u64,
+    output_buffer: usize,
+    output_coalesce_bytes: usize,
+    output_coalesce_delay: std::time::Duration,
+    /// For a custom shell
+    interactive: bool,
+    rc: Option<(std::path::PathBuf, RcInjection)>,
+}
+
+/// The channels of a running session, shared by all of its clients
+#[derive(Clone)]
+struct Session {
+    tx: mpsc::UnboundedSender<TerminalRequest>,
+    output: Arc<OutputBroadcast>,
+    /// `None` unless the session runs a shell with integration
+    state_tx: watch::Sender<Option<ShellStatus>>,
+    /// When a request or a client last used the session
+    last_used: Arc<Mutex<std::time::Instant>>,
+}
+
+impl Session {
+    fn touch(&self) {
+        *self.last_used.lock().unwrap() = std::time::Instant::now();
+    }
+
+    /// No client is connected, and none used the session for `timeout`
+    fn is_idle(&self, timeout: std::time::Duration) -> bool {
+        self.output.receiver_count() == 0 && self.last_used.lock().unwrap().elapsed() >= timeout
+    }
 }
 
 struct GlobalState {
-    tx: tokio::sync::mpsc::UnboundedSender<Vec<InputItem>>,
-    pty_sub_tx: tokio::sync::broadcast::Sender<String>,
+    options: ShellOptions,
+    /// A cell stays empty while its shell starts, or after it failed to
+    sessions: Mutex<HashMap<String, Arc<OnceCell<Session>>>>,
 }
 
 impl GlobalState {
-    async fn get_receiver(&self) -> tokio::sync::broadcast::Receiver<String> {
-        self.pty_sub_tx.subscribe()
+    /// The session `id`, its shell is started on the first request
+    async fn session(self: &Arc<Self>, id: &str) -> std::io::Result<Session> {
+        if !is_valid_session_id(id) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid session id {:?}", id),
+            ));
+        }
+
+        let cell = {
+            let mut sessions = self.sessions.lock().unwrap();
+            let max = self.options.max_sessions as usize;
+            if max > 0 && sessions.len() >= max && !sessions.contains_key(id) {
+                return Err(std::io::Error::other(format!(
+                    "Too many sessions, at most {} can run",
+                    max
+                )));
+            }
+            sessions.entry(id.to_string()).or_default().clone()
+        };
+        match cell.get_or_try_init(|| start_session(self, id)).await {
+            Ok(session) => {
+                session.touch();
+                Ok(session.clone())
+            }
+            Err(e) => {
+                // a shell that failed to start takes no place from others
+                let mut sessions = self.sessions.lock().unwrap();
+                if sessions
+                    .get(id)
+                    .is_some_and(|current| Arc::ptr_eq(current, &cell) && !cell.initialized())
+                {
+                    sessions.remove(id);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Whether the session `id` is still the one sending to `output`, it is
+    /// not once it was closed
+    fn is_current(&self, id: &str, output: &Arc<OutputBroadcast>) -> bool {
+        let sessions = self.sessions.lock().unwrap();
+        is_session(&sessions, id, output)
+    }
+
+    /// Forget a session whose shell exited for good, the next request starts
+    /// a new one
+    fn remove_session(&self, id: &str, output: &Arc<OutputBroadcast>) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if is_session(&sessions, id, output) {
+            sessions.remove(id);
+        }
+    }
+
+    /// Kill the shell of the session `id` for good. Returns `false` when no
+    /// session runs under that id, a session still starting included.
+    fn close_session(&self, id: &str) -> bool {
+        let session = {
+            let mut sessions = self.sessions.lock().unwrap();
+            let Some(session) = sessions.get(id).and_then(|cell| cell.get()).cloned() else {
+                return false;
+            };
+            sessions.remove(id);
+            session
+        };
+        log::info!("[{}] Closing session", id);
+        let _ = session.tx.send(TerminalRequest::Close);
+        true
+    }
+
+    /// Close the sessions no client used for `timeout`
+    fn close_idle_sessions(&self, timeout: std::time::Duration) {
+        let idle = {
+            let mut sessions = self.sessions.lock().unwrap();
+            let ids: Vec<String> = sessions
+                .iter()
+                .filter(|(_, cell)| cell.get().is_some_and(|session| session.is_idle(timeout)))
+                .map(|(id, _)| id.clone())
+                .collect();
+            ids.into_iter()
+                .filter_map(|id| Some((sessions.remove(&id)?.get()?.clone(), id)))
+                .collect::<Vec<_>>()
+        };
+        for (session, id) in idle {
+            log::info!("[{}] No client for {:?}, closing session", id, timeout);
+            let _ = session.tx.send(TerminalRequest::Close);
+        }
     }
 }
 
-#[derive(Debug)]
-enum TerminalEvent {
-    PtyOutput(String),
-    Input(Vec<InputItem>),
-    PtyEof,
-    InputClosed,
-    Error,
+fn is_session(
+    sessions: &HashMap<String, Arc<OnceCell<Session>>>,
+    id: &str,
+    output: &Arc<OutputBroadcast>,
+) -> bool {
+    sessions
+        .get(id)
+        .and_then(|cell| cell.get())
+        .is_some_and(|session| Arc::ptr_eq(&session.output, output))
 }
 
-async fn wait_terminal_event<T: terminal::TerminalType>(
-    state: &mut terminal::EchokitChild<T>,
-    rx: &mut tokio::sync::mpsc::UnboundedReceiver<Vec<InputItem>>,
-) -> TerminalEvent {
-    tokio::select! {
-        // 从 PTY 读取数据
-        result = state.read_string() => {
-            match result {
-                Ok(output) => if output.is_empty() {
-                    TerminalEvent::PtyEof
-                } else {
-                    TerminalEvent::PtyOutput(output)
+/// Look for idle sessions to close as long as the server runs
+async fn reap_idle_sessions(state: Arc<GlobalState>, timeout: std::time::Duration) {
+    let mut interval = tokio::time::interval(timeout.min(REAP_INTERVAL));
+    loop {
+        interval.tick().await;
+        state.close_idle_sessions(timeout);
+    }
+}
+
+/// Letters, digits, `-` and `_`, like the UUIDs clients usually pick
+fn is_valid_session_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn spawn_error(e: pty_process::Error) -> std::io::Error {
+    match e {
+        pty_process::Error::Io(e) => e,
+        e => std::io::Error::other(e),
+    }
+}
+
+/// Spawn the configured shell. A command other than `bash`, `zsh`, `fish`,
+/// `sh` and `claude` runs as a custom shell with `--interactive` or
+/// `--rc-file`, and as a plain terminal otherwise.
+async fn start_session(state: &Arc<GlobalState>, id: &str) -> std::io::Result<Session> {
+    let options = &state.options;
+    let size = TERMINAL_SIZE;
+    let pacing = options.pacing;
+    log::info!("[{}] Starting {}", id, options.shell);
+
+    match options.shell.as_str() {
+        "bash" => start_shell(state, id, Bash::default),
+        "zsh" => start_shell(state, id, Zsh::default),
+        "fish" => start_shell(state, id, Fish::default),
+        "sh" => start_shell(state, id, Sh::default),
+        "claude" => {
+            let shell = options.shell.clone();
+            // claude starts asynchronously, it is spawned before the loop
+            let mut terminal = terminal::claude::new(&shell, uuid::Uuid::nil(), size)
+                .await
+                .map_err(spawn_error)?;
+            terminal.set_pacing(pacing);
+            let (session, rx) = new_session(options);
+            tokio::spawn(supervise(
+                state.clone(),
+                id.to_string(),
+                rx,
+                session.output.clone(),
+                session.state_tx.clone(),
+                terminal,
+                move || {
+                    let shell = shell.clone();
+                    async move {
+                        let mut terminal = terminal::claude::new(&shell, uuid::Uuid::nil(), size)
+                            .await
+                            .map_err(spawn_error)?;
+                        terminal.set_pacing(pacing);
+                        Ok(terminal)
+                    }
                 },
-                Err(_) => TerminalEvent::Error,
-            }
+            ));
+            Ok(session)
+        }
+        other if options.interactive || options.rc.is_some() => {
+            let program = other.to_string();
+            let interactive = options.interactive;
+            let rc = options.rc.clone();
+            start_shell(state, id, move || {
+                let shell = CustomShell::new(program.clone()).interactive(interactive);
+                match rc.clone() {
+                    Some((path, injection)) => shell.rc(path, injection),
+                    None => shell,
+                }
+            })
+        }
+        other => {
+            let shell = other.to_string();
+            let args = options.shell_args.clone();
+            start_supervised(state, id, move || {
+                let mut terminal = terminal::new(&shell, &args, size).map_err(spawn_error)?;
+                terminal.set_pacing(pacing);
+                Ok(terminal)
+            })
+        }
+    }
+}
+
+/// Start a session running the shell `shell` makes, at each spawn
+fn start_shell<T, F>(state: &Arc<GlobalState>, id: &str, shell: F) -> std::io::Result<Session>
+where
+    T: ShellType + Send + 'static,
+    F: Fn() -> T + Send + Sync + 'static,
+{
+    let args = state.options.shell_args.clone();
+    let pacing = state.options.pacing;
+    start_supervised(state, id, move || {
+        let mut terminal =
+            terminal::shell::new(shell(), &args, TERMINAL_SIZE).map_err(spawn_error)?;
+        terminal.set_pacing(pacing);
+        Ok(terminal)
+    })
+}
+
+/// A session with no client yet, and the receiver of its input
+fn new_session(options: &ShellOptions) -> (Session, mpsc::UnboundedReceiver<TerminalRequest>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let (state_tx, _) = watch::channel(None);
+    let output = OutputBroadcast::new(
+        options.output_buffer,
+        options.output_coalesce_bytes,
+        options.output_coalesce_delay,
+        TERMINAL_SIZE,
+    );
+    (
+        Session {
+            tx,
+            output: Arc::new(output),
+            state_tx,
+            last_used: Arc::new(Mutex::new(std::time::Instant::now())),
         },
-        msg = rx.recv() => {
-            match msg {
-                Some(input) => TerminalEvent::Input(input),
-                None => TerminalEvent::InputClosed,
-            }
+        rx,
+    )
+}
+
+/// Spawn a terminal with `spawn` now, and again whenever it is respawned
+fn start_supervised<D, F>(state: &Arc<GlobalState>, id: &str, spawn: F) -> std::io::Result<Session>
+where
+    D: TerminalDriver + 'static,
+    F: Fn() -> std::io::Result<D> + Send + Sync + 'static,
+{
+    let terminal = spawn()?;
+    let (session, rx) = new_session(&state.options);
+    let spawn = Arc::new(spawn);
+    tokio::spawn(supervise(
+        state.clone(),
+        id.to_string(),
+        rx,
+        session.output.clone(),
+        session.state_tx.clone(),
+        terminal,
+        move || {
+            let spawn = spawn.clone();
+            async move { spawn() }
         },
+    ));
+    Ok(session)
+}
+
+/// Run the terminal loop of a session, respawning the shell when it exits as
+/// the options allow. The session is removed once it is not respawned.
+async fn supervise<D, F, Fut>(
+    state: Arc<GlobalState>,
+    id: String,
+    mut rx: mpsc::UnboundedReceiver<TerminalRequest>,
+    output: Arc<OutputBroadcast>,
+    state_tx: watch::Sender<Option<ShellStatus>>,
+    mut terminal: D,
+    spawn: F,
+) where
+    D: TerminalDriver,
+    F: Fn() -> Fut + Send,
+    Fut: std::future::Future<Output = std::io::Result<D>> + Send,
+{
+    let options = &state.options;
+    let mut respawns = 0;
+    loop {
+        let status = driver::run(terminal, &mut rx, &output, &state_tx).await;
+        if !state.is_current(&id, &output) {
+            log::info!("[{}] Session closed: {:?}", id, status);
+            return;
+        }
+        let failed = !matches!(&status, Ok(status) if status.success());
+        let respawn = match options.respawn {
+            Respawn::Never => false,
+            Respawn::OnFailure => failed,
+            Respawn::Always => true,
+        };
+        if !respawn || (options.max_respawns > 0 && respawns >= options.max_respawns) {
+            log::info!("[{}] Session ended: {:?}", id, status);
+            break;
+        }
+
+        respawns += 1;
+        log::info!(
+            "[{}] Shell exited with {:?}, respawning ({}/{})",
+            id,
+            status,
+            respawns,
+            options.max_respawns
+        );
+        let notice = format!("\r\n[process exited, restarting {}]\r\n", options.shell);
+        output.send(notice.into());
+        output.flush();
+        tokio::time::sleep(options.respawn_delay).await;
+
+        terminal = match spawn().await {
+            Ok(terminal) => terminal,
+            Err(e) => {
+                log::error!("[{}] Failed to respawn {}: {}", id, options.shell, e);
+                break;
+            }
+        };
     }
+
+    state.remove_session(&id, &output);
 }
 
 #[derive(serde::Deserialize)]
@@ -85,9 +498,17 @@ pub struct InputRequest {
 
 async fn api_input(
     State(global_state): State<Arc<GlobalState>>,
+    Path(id): Path<String>,
     Json(body): Json<InputRequest>,
 ) -> impl IntoResponse {
-    if let Err(e) = global_state.tx.send(body.inputs) {
+    let session = match global_state.session(&id).await {
+        Ok(session) => session,
+        Err(e) => {
+            log::error!("[{}] Failed to start session: {}", id, e);
+            return Json(serde_json::json!({"status": "error", "message": e.to_string()}));
+        }
+    };
+    if let Err(e) = session.tx.send(body.inputs.into()) {
         log::error!("Failed to send input: {:?}", e);
         Json(serde_json::json!({"status": "error", "message": "Failed to send input"}))
     } else {
@@ -95,259 +516,72 @@ async fn api_input(
     }
 }
 
-#[derive(Default, Clone, Copy)]
-pub struct TerminalLoopHandle<T: terminal::TerminalType>(std::marker::PhantomData<T>);
-
-impl TerminalLoopHandle<terminal::Normal> {
-    async fn terminal_loop(
-        mut terminal: terminal::EchokitChild<terminal::Normal>,
-        mut rx: tokio::sync::mpsc::UnboundedReceiver<Vec<InputItem>>,
-        pty_sub_tx: tokio::sync::broadcast::Sender<String>,
-    ) {
-        log::info!("Start terminal event loop");
-        loop {
-            let event = wait_terminal_event(&mut terminal, &mut rx).await;
-
-            match event {
-                TerminalEvent::PtyOutput(output) => {
-                    log::info!(
-                        "pty output: {:?}",
-                        strip_ansi_escapes::strip_str(output.as_str())
-                    );
-                    if pty_sub_tx.send(output).is_err() {
-                        log::warn!("no active PTY subscribers");
-                        continue;
-                    }
-                }
-                TerminalEvent::Input(input) => {
-                    log::info!("Sending input to terminal: {:?}", input);
-                    for input_item in input {
-                        match input_item {
-                            InputItem::Text { input } => {
-                                if let Err(e) = terminal.send_text(&input).await {
-                                    log::error!("Failed to send text to terminal: {:?}", e);
-                                }
-                            }
-                            InputItem::KeyboardInterrupt => {
-                                if let Err(e) = terminal.send_keyboard_interrupt().await {
-                                    log::error!(
-                                        "Failed to send keyboard interrupt to terminal: {:?}",
-                                        e
-                                    );
-                                }
-                            }
-                            InputItem::Enter => {
-                                tokio::time::sleep(std::time::Duration::from_millis(500)).await;
-                                if let Err(e) = terminal.send_enter().await {
-                                    log::error!("Failed to send enter to terminal: {:?}", e);
-                                }
-                            }
-                            InputItem::Esc => {
-                                if let Err(e) = terminal.send_esc().await {
-                                    log::error!("Failed to send ESC to terminal: {:?}", e);
-                                }
-                            }
-                            InputItem::Bytes(bytes) => {
-                                if let Err(e) = terminal.write_all(&bytes).await {
-                                    log::error!("Failed to send bytes to terminal: {:?}", e);
-                                }
-                            }
-                        }
-                    }
-                }
-                TerminalEvent::PtyEof => {
-                    log::info!("PTY EOF received");
-                    break;
-                }
-                TerminalEvent::InputClosed | TerminalEvent::Error => {
-                    let r = terminal.wait().await;
-                    log::info!("Terminal process exited with status: {:?}", r);
-                    break;
-                }
-            }
-        }
+/// Kill the shell of a session, its clients are disconnected
+async fn api_close(
+    State(global_state): State<Arc<GlobalState>>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    if global_state.close_session(&id) {
+        Json(serde_json::json!({"status": "success"}))
+    } else {
+        Json(serde_json::json!({"status": "error", "message": "No such session"}))
     }
 }
 
-impl<T: terminal::shell::ShellType> TerminalLoopHandle<T> {
-    async fn terminal_loop_shell(
-        mut terminal: terminal::EchokitChild<T>,
-        mut rx: tokio::sync::mpsc::UnboundedReceiver<Vec<InputItem>>,
-        pty_sub_tx: tokio::sync::broadcast::Sender<String>,
-    ) {
-        log::info!("Start terminal event loop");
-        loop {
-            let event = wait_terminal_event(&mut terminal, &mut rx).await;
-
-            match event {
-                TerminalEvent::PtyOutput(output) => {
-                    log::info!(
-                        "pty output: {:?}",
-                        strip_ansi_escapes::strip_str(output.as_str())
-                    );
-                    if pty_sub_tx.send(output).is_err() {
-                        log::warn!("no active PTY subscribers");
-                        continue;
-                    }
-                }
-                TerminalEvent::Input(input) => {
-                    log::info!("Sending input to terminal: {:?}", input);
-                    for input_item in input {
-                        match input_item {
-                            InputItem::Text { input } => {
-                                if let Err(e) = terminal.send_text(&input).await {
-                                    log::error!("Failed to send text to terminal: {:?}", e);
-                                }
-                            }
-                            InputItem::KeyboardInterrupt => {
-                                if let Err(e) = terminal.send_keyboard_interrupt().await {
-                                    log::error!(
-                                        "Failed to send keyboard interrupt to terminal: {:?}",
-                                        e
-                                    );
-                                }
-                            }
-                            InputItem::Enter => {
-                                tokio::time::sleep(std::time::Duration::from_millis(500)).await;
-                                if let Err(e) = terminal.send_enter().await {
-                                    log::error!("Failed to send enter to terminal: {:?}", e);
-                                }
-                            }
-                            InputItem::Esc => {
-                                if let Err(e) = terminal.send_esc().await {
-                                    log::error!("Failed to send ESC to terminal: {:?}", e);
-                                }
-                            }
-                            InputItem::Bytes(bytes) => {
-                                if let Err(e) = terminal.write_all(&bytes).await {
-                                    log::error!("Failed to send bytes to terminal: {:?}", e);
-                                }
-                            }
-                        }
-                    }
-                }
-                TerminalEvent::PtyEof => {
-                    log::info!("PTY EOF received");
-                    break;
-                }
-                TerminalEvent::InputClosed | TerminalEvent::Error => {
-                    let r = terminal.wait().await;
-                    log::info!("Terminal process exited with status: {:?}", r);
-                    break;
-                }
-            }
-        }
-    }
+/// `/api/input`, for the session of `/ws`
+async fn api_input_default(
+    state: State<Arc<GlobalState>>,
+    body: Json<InputRequest>,
+) -> impl IntoResponse {
+    api_input(state, Path(DEFAULT_SESSION.to_string()), body).await
 }
 
-impl TerminalLoopHandle<terminal::claude::ClaudeCode> {
-    async fn terminal_loop(
-        mut terminal: terminal::EchokitChild<terminal::claude::ClaudeCode>,
-        mut rx: tokio::sync::mpsc::UnboundedReceiver<Vec<InputItem>>,
-        pty_sub_tx: tokio::sync::broadcast::Sender<String>,
-    ) {
-        enum TerminalEvent {
-            PtyOutput(String),
-            HistoryLog(ClaudeCodeLog),
-            Input(Vec<InputItem>),
-            PtyEof,
-            InputClosed,
-            Error,
-        }
+fn default_exec_timeout_ms() -> u64 {
+    30_000
+}
 
-        log::info!("Start terminal event loop");
-        loop {
-            let event = tokio::select! {
-                // 从 PTY 读取数据
-                result = terminal.read_pty_output_and_history_line() => {
-                    match result {
-                        Ok(ClaudeCodeResult::ClaudeLog(line)) => TerminalEvent::HistoryLog(line),
-                        Ok(ClaudeCodeResult::PtyOutput(output)) => if output.is_empty() {
-                            TerminalEvent::PtyEof
-                        } else {
-                            TerminalEvent::PtyOutput(output)
-                        },
-                        Ok(ClaudeCodeResult::Uncaught(s)) => {
-                            log::debug!("ClaudeCode uncaught: {}", s);
-                            continue;
-                        }
-                        Ok(ClaudeCodeResult::WaitForUserInput) => {
-                            log::info!("ClaudeCode is waiting for user input");
-                            continue;
-                        }
-                        Ok(ClaudeCodeResult::WaitForUserInputBeforeTool{..}) => {
-                            log::info!("ClaudeCode is waiting for user input before tool");
-                            continue;
-                        }
-                        Err(_) => TerminalEvent::Error,
-                    }
-                },
-                msg = rx.recv() => {
-                    match msg {
-                        Some(input) => TerminalEvent::Input(input),
-                        None => TerminalEvent::InputClosed,
-                    }
-                },
-            };
+#[derive(serde::Deserialize)]
+pub struct ExecRequest {
+    pub command: String,
+    #[serde(default = "default_exec_timeout_ms")]
+    pub timeout_ms: u64,
+}
 
-            let state = terminal.state();
-            match event {
-                TerminalEvent::PtyOutput(output) => {
-                    if pty_sub_tx.send(output).is_err() {
-                        log::warn!("no active PTY subscribers");
-                        continue;
-                    }
-                }
-                TerminalEvent::HistoryLog(cc_log) => {
-                    log::info!("{state:?} >>: {:?}", cc_log);
-                }
-                TerminalEvent::Input(input) => {
-                    log::info!("Sending input to terminal: {:?}", input);
-                    for input_item in input {
-                        match input_item {
-                            InputItem::Text { input } => {
-                                if let Err(e) = terminal.send_text(&input).await {
-                                    log::error!("Failed to send text to terminal: {:?}", e);
-                                }
-                            }
-                            InputItem::KeyboardInterrupt => {
-                                if let Err(e) = terminal.send_keyboard_interrupt().await {
-                                    log::error!(
-                                        "Failed to send keyboard interrupt to terminal: {:?}",
-                                        e
-                                    );
-                                }
-                            }
-                            InputItem::Enter => {
-                                tokio::time::sleep(std::time::Duration::from_millis(500)).await;
-                                if let Err(e) = terminal.send_enter().await {
-                                    log::error!("Failed to send enter to terminal: {:?}", e);
-                                }
-                            }
-                            InputItem::Esc => {
-                                if let Err(e) = terminal.send_esc().await {
-                                    log::error!("Failed to send ESC to terminal: {:?}", e);
-                                }
-                            }
-                            InputItem::Bytes(bytes) => {
-                                if let Err(e) = terminal.write_all(&bytes).await {
-                                    log::error!("Failed to send bytes to terminal: {:?}", e);
-                                }
-                            }
-                        }
-                    }
-                }
-                TerminalEvent::PtyEof => {
-                    log::info!("PTY EOF received");
-                    break;
-                }
-                TerminalEvent::InputClosed | TerminalEvent::Error => {
-                    let r = terminal.wait().await;
-                    log::info!("Terminal process exited with status: {:?}", r);
-                    break;
-                }
-            }
+/// Run a command in the shell of a session and answer with its output, exit
+/// code and duration. Clients of the session see it run like typed input.
+async fn api_exec(
+    State(global_state): State<Arc<GlobalState>>,
+    Path(id): Path<String>,
+    Json(body): Json<ExecRequest>,
+) -> impl IntoResponse {
+    let session = match global_state.session(&id).await {
+        Ok(session) => session,
+        Err(e) => {
+            log::error!("[{}] Failed to start session: {}", id, e);
+            return Json(serde_json::json!({"status": "error", "message": e.to_string()}));
+        }
+    };
+
+    // the session takes no other input while the command runs
+    let timeout_ms = body
+        .timeout_ms
+        .min(global_state.options.max_exec_timeout_ms);
+    let (reply, result) = oneshot::channel();
+    let request = TerminalRequest::Exec {
+        command: body.command,
+        timeout: std::time::Duration::from_millis(timeout_ms),
+        reply,
+    };
+    if session.tx.send(request).is_err() {
+        return Json(serde_json::json!({"status": "error", "message": "Session has ended"}));
+    }
+    match result.await {
+        Ok(Ok(result)) => Json(serde_json::json!({"status": "success", "result": result})),
+        Ok(Err(e)) => {
+            log::error!("[{}] Failed to run command: {}", id, e);
+            Json(serde_json::json!({"status": "error", "message": e.to_string()}))
         }
+        Err(_) => Json(serde_json::json!({"status": "error", "message": "Session has ended"})),
     }
 }
 
@@ -356,78 +590,61 @@ async fn main() {
     env_logger::init();
     let args = Args::parse();
 
-    let shell_args = args.shell_args;
-
-    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
-    let (ws_tx, _ws_rx) = tokio::sync::broadcast::channel(100);
-    let pty_sub_tx = ws_tx.clone();
-
-    let global_state = Arc::new(GlobalState { tx, pty_sub_tx });
-
-    let app = match args.shell.as_str() {
-        "bash" => {
-            let terminal = terminal::shell::new(terminal::shell::Bash, &shell_args, (24, 80))
-                .expect("Failed to start bash terminal process");
-            tokio::spawn(
-                TerminalLoopHandle::<terminal::shell::Bash>::terminal_loop_shell(
-                    terminal, rx, ws_tx,
-                ),
-            );
-            Router::new()
-                .route("/ws", get(websocket_handler))
-                .route("/api/input", post(api_input))
-                .nest_service("/", get_service(ServeDir::new("static")))
-                .with_state(global_state)
-        }
-        "zsh" => {
-            let terminal = terminal::shell::new(terminal::shell::Zsh, &shell_args, (24, 80))
-                .expect("Failed to start zsh terminal process");
-            tokio::spawn(
-                TerminalLoopHandle::<terminal::shell::Zsh>::terminal_loop_shell(
-                    terminal, rx, ws_tx,
-                ),
-            );
-            Router::new()
-                .route("/ws", get(websocket_handler))
-                .route("/api/input", post(api_input))
-                .nest_service("/", get_service(ServeDir::new("static")))
-                .with_state(global_state)
-        }
-        "claude" => {
-            let terminal = terminal::claude::new(&args.shell, uuid::Uuid::nil(), (24, 80))
-                .await
-                .expect("Failed to start claude terminal process");
-            tokio::spawn(
-                TerminalLoopHandle::<terminal::claude::ClaudeCode>::terminal_loop(
-                    terminal, rx, ws_tx,
-                ),
-            );
-            Router::new()
-                .route("/ws", get(websocket_handler))
-                .route("/api/input", post(api_input))
-                .nest_service("/", get_service(ServeDir::new("static")))
-                .with_state(global_state)
-        }
-        other => {
-            log::warn!("command: {}, defaulting to bash", other);
-            let terminal = terminal::new(other, &shell_args, (24, 80))
-                .expect("Failed to start bash terminal process");
-            tokio::spawn(TerminalLoopHandle::<terminal::Normal>::terminal_loop(
-                terminal, rx, ws_tx,
-            ));
-            Router::new()
-                .route("/ws", get(websocket_handler))
-                .route("/api/input", post(api_input))
-                .nest_service("/", get_service(ServeDir::new("static")))
-                .with_state(global_state)
-        }
+    let pacing = InputPacing {
+        submit: SubmitMode::Type,
+        wait_for_echo: args.wait_for_echo,
+        echo_timeout_ms: args.echo_timeout_ms,
+        enter_delay_ms: args.enter_delay_ms,
+        ..Default::default()
+    };
+    let options = ShellOptions {
+        shell: args.shell,
+        shell_args: args.shell_args,
+        pacing,
+        respawn: args.respawn,
+        max_respawns: args.max_respawns,
+        respawn_delay: std::time::Duration::from_millis(args.respawn_delay_ms),
+        max_sessions: args.max_sessions,
+        max_exec_timeout_ms: args.max_exec_timeout_ms,
+        output_buffer: args.output_buffer as usize,
+        output_coalesce_bytes: args.output_coalesce_bytes,
+        output_coalesce_delay: std::time::Duration::from_millis(args.output_coalesce_ms),
+        interactive: args.interactive,
+        rc: args.rc_file.map(|path| {
+            let injection = match args.rc_flag {
+                Some(flag) => RcInjection::Arg(flag),
+                None => RcInjection::Env(args.rc_env),
+            };
+            (path, injection)
+        }),
     };
+    println!("Shell: {} {}", options.shell, options.shell_args.join(" "));
+
+    let global_state = Arc::new(GlobalState {
+        options,
+        sessions: Mutex::new(HashMap::new()),
+    });
+    if args.idle_timeout_secs > 0 {
+        tokio::spawn(reap_idle_sessions(
+            global_state.clone(),
+            std::time::Duration::from_secs(args.idle_timeout_secs),
+        ));
+    }
+
+    let app = Router::new()
+        .route("/ws", get(websocket_default_handler))
+        .route("/ws/{id}", get(websocket_handler))
+        .route("/api/input", post(api_input_default))
+        .route("/api/{id}", delete(api_close))
+        .route("/api/{id}/input", post(api_input))
+        .route("/api/{id}/exec", post(api_exec))
+        .fallback_service(get_service(ServeDir::new("static")))
+        .with_state(global_state);
 
     let bind_addr = format!("127.0.0.1:{}", args.port);
     let listener = tokio::net::TcpListener::bind(&bind_addr).await.unwrap();
 
     println!("Web terminal server running on http://{}", bind_addr);
-    println!("Shell: claude {}", shell_args.join(" "));
     println!("Press Ctrl+C to stop the server");
 
     // 处理 Ctrl+C 信号
@@ -441,49 +658,183 @@ async fn main() {
     }
 }
 
+#[derive(serde::Deserialize)]
+struct WebSocketQuery {
+    /// Send PTY output as binary frames holding the bytes exactly as read,
+    /// instead of text frames
+    #[serde(default)]
+    binary: bool,
+    /// Also send the shell state as `session_state` JSON text frames, PTY
+    /// output then goes as binary frames
+    #[serde(default)]
+    events: bool,
+}
+
+const DEFAULT_SESSION: &str = "default";
+
 async fn websocket_handler(
     ws: WebSocketUpgrade,
     State(global_state): State<Arc<GlobalState>>,
+    Path(id): Path<String>,
+    Query(query): Query<WebSocketQuery>,
+) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| websocket(socket, global_state, id, query))
+}
+
+/// `/ws`, a session shared by every client not asking for one
+async fn websocket_default_handler(
+    ws: WebSocketUpgrade,
+    state: State<Arc<GlobalState>>,
+    query: Query<WebSocketQuery>,
 ) -> impl IntoResponse {
-    ws.on_upgrade(|socket| websocket(socket, global_state))
+    websocket_handler(ws, state, Path(DEFAULT_SESSION.to_string()), query).await
 }
 
 enum Event {
     WebSocketInput(Result<Message, axum::Error>),
-    PtyOutput(String),
+    PtyOutput(OutputChunk),
+    /// The client fell this many chunks behind the session
+    Lagged(u64),
+    StateChanged,
+}
+
+/// Resolves when the shell state changed, never without a receiver or once
+/// the session is gone
+async fn state_changed(rx: Option<&mut watch::Receiver<Option<ShellStatus>>>) {
+    if let Some(rx) = rx
+        && rx.changed().await.is_ok()
+    {
+        return;
+    }
+    std::future::pending().await
 }
 
 async fn select_event(
     socket: &mut WebSocket,
-    rx: &mut tokio::sync::broadcast::Receiver<String>,
+    rx: &mut broadcast::Receiver<OutputChunk>,
+    state_rx: Option<&mut watch::Receiver<Option<ShellStatus>>>,
 ) -> Option<Event> {
     tokio::select! {
-        Ok(msg) = rx.recv() => Some(Event::PtyOutput(msg)),
-        Some(msg) = socket.recv() => Some(Event::WebSocketInput(msg)),
-        else => None,
+        _ = state_changed(state_rx) => Some(Event::StateChanged),
+        msg = rx.recv() => match msg {
+            Ok(msg) => Some(Event::PtyOutput(msg)),
+            Err(RecvError::Lagged(skipped)) => Some(Event::Lagged(skipped)),
+            // the session ended
+            Err(RecvError::Closed) => None,
+        },
+        msg = socket.recv() => msg.map(Event::WebSocketInput),
+    }
+}
+
+/// PTY output as a frame for a client, `None` for text frames without text
+fn output_message(output: OutputChunk, binary: bool) -> Option<Message> {
+    if binary {
+        Some(Message::Binary(output.bytes.into()))
+    } else if output.text.is_empty() {
+        None
+    } else {
+        Some(Message::Text(output.text.into()))
     }
 }
 
-async fn websocket(mut socket: WebSocket, global_state: Arc<GlobalState>) {
-    let mut receiver = global_state.get_receiver().await;
+/// A `session_state` message, `None` for sessions without a shell state
+fn state_message(id: &str, status: &Option<ShellStatus>) -> Option<Message> {
+    let status = status.as_ref()?;
+    let message = serde_json::json!({
+        "type": "session_state",
+        "session_id": id,
+        "current_state": status.current_state,
+        "last_exit_code": status.last_exit_code,
+        "cwd": status.cwd,
+    });
+    Some(Message::Text(message.to_string().into()))
+}
+
+async fn websocket(
+    mut socket: WebSocket,
+    global_state: Arc<GlobalState>,
+    id: String,
+    query: WebSocketQuery,
+) {
+    let binary = query.binary || query.events;
+    let session = match global_state.session(&id).await {
+        Ok(session) => session,
+        Err(e) => {
+            log::error!("[{}] Failed to start session: {}", id, e);
+            let _ = socket
+                .send(Message::Text(
+                    format!("Failed to start session: {}\r\n", e).into(),
+                ))
+                .await;
+            return;
+        }
+    };
+    // only the receivers are kept, so they close once the session ends
+    let Session {
+        tx,
+        output,
+        state_tx,
+        last_used,
+    } = session;
+    let mut receiver = output.subscribe();
+    let mut state_rx = query.events.then(|| state_tx.subscribe());
+    // lagging clients still need the screen, as long as the session runs
+    let screen = Arc::downgrade(&output);
+    drop((output, state_tx));
+    let message = state_rx
+        .as_mut()
+        .and_then(|rx| state_message(&id, &rx.borrow_and_update()));
+    if let Some(message) = message
+        && socket.send(message).await.is_err()
+    {
+        return;
+    }
 
     loop {
-        let event = select_event(&mut socket, &mut receiver).await;
+        let event = select_event(&mut socket, &mut receiver, state_rx.as_mut()).await;
 
         match event {
             Some(Event::PtyOutput(output)) => {
-                if socket.send(Message::Text(output.into())).await.is_err() {
+                if let Some(message) = output_message(output, binary)
+                    && socket.send(message).await.is_err()
+                {
+                    break;
+                }
+            }
+            Some(Event::Lagged(skipped)) => {
+                let Some(output) = screen.upgrade() else {
+                    break;
+                };
+                log::warn!(
+                    "[{}] client lagged {} chunks behind, resyncing",
+                    id,
+                    skipped
+                );
+                let (snapshot, resynced) = output.resync(&receiver);
+                drop(output);
+                receiver = resynced;
+                if let Some(message) = output_message(snapshot, binary)
+                    && socket.send(message).await.is_err()
+                {
+                    break;
+                }
+            }
+            Some(Event::StateChanged) => {
+                let message = state_rx
+                    .as_mut()
+                    .and_then(|rx| state_message(&id, &rx.borrow_and_update()));
+                if let Some(message) = message
+                    && socket.send(message).await.is_err()
+                {
                     break;
                 }
             }
             Some(Event::WebSocketInput(Ok(msg))) => match msg {
                 Message::Text(text) => {
-                    let _ = global_state
-                        .tx
-                        .send(vec![InputItem::Bytes(text.as_bytes().to_vec())]);
+                    let _ = tx.send(vec![InputItem::Bytes(text.as_bytes().to_vec())].into());
                 }
                 Message::Binary(bytes) => {
-                    let _ = global_state.tx.send(vec![InputItem::Bytes(bytes.to_vec())]);
+                    let _ = tx.send(vec![InputItem::Bytes(bytes.to_vec())].into());
                 }
                 Message::Close(_) => {
                     break;
@@ -495,4 +846,6 @@ async fn websocket(mut socket: WebSocket, global_state: Arc<GlobalState>) {
             }
         }
     }
+    // the idle time of a session starts when its last client leaves
+    *last_used.lock().unwrap() = std::time::Instant::now();
 }
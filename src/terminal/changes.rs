@@ -0,0 +1,386 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use crate::types::claude::{ClaudeCodeLog, TrackedFileBackup};
+
+/// Tools that write files, all of them take the target in `file_path`
+const EDIT_TOOLS: [&str; 3] = ["Edit", "MultiEdit", "Write"];
+
+/// Number of unchanged lines shown around each hunk
+const CONTEXT: usize = 3;
+
+/// Upper bound on the LCS table, larger inputs are diffed as a full replacement
+const MAX_DIFF_CELLS: usize = 4_000_000;
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct FileEdit {
+    pub tool_use_id: String,
+    pub tool: String,
+    /// `None` until the tool result arrives
+    pub applied: Option<bool>,
+    #[serde(skip)]
+    input: serde_json::Value,
+}
+
+fn str_field<'a>(value: &'a serde_json::Value, key: &str) -> &'a str {
+    value.get(key).and_then(|v| v.as_str()).unwrap_or_default()
+}
+
+fn replacement(value: &serde_json::Value) -> (&str, &str) {
+    (
+        str_field(value, "old_string"),
+        str_field(value, "new_string"),
+    )
+}
+
+impl FileEdit {
+    /// The (old, new) text pairs written by this edit
+    fn replacements(&self) -> Vec<(&str, &str)> {
+        match self.tool.as_str() {
+            "Edit" => vec![replacement(&self.input)],
+            "MultiEdit" => self
+                .input
+                .get("edits")
+                .and_then(|edits| edits.as_array())
+                .map(|edits| edits.iter().map(replacement).collect())
+                .unwrap_or_default(),
+            "Write" => vec![("", str_field(&self.input, "content"))],
+            _ => vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileChangeStatus {
+    Created,
+    Modified,
+    Deleted,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct FileChange {
+    pub path: String,
+    pub status: FileChangeStatus,
+    pub edits: Vec<FileEdit>,
+    /// Unified diff against the content before the session first touched the
+    /// file, or of the individual edits when no backup is available
+    pub diff: String,
+    /// Only filled when the full content is requested
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct TrackedFile {
+    /// The earliest backup Claude Code made of this file
+    backup: Option<TrackedFileBackup>,
+    edits: Vec<FileEdit>,
+}
+
+/// Every file a Claude Code session touched, built from `file-history-snapshot`
+/// lines and the Edit/Write/MultiEdit tool calls
+#[derive(Debug, Clone, Default)]
+pub struct ChangeLedger {
+    cwd: Option<PathBuf>,
+    files: BTreeMap<String, TrackedFile>,
+    /// tool_use id -> path of the edits waiting for their result
+    pending: HashMap<String, String>,
+}
+
+impl ChangeLedger {
+    pub fn record(&mut self, log: &ClaudeCodeLog) {
+        if let Some(cwd) = log.cwd() {
+            self.cwd = Some(cwd.into());
+        }
+
+        if let ClaudeCodeLog::Snapshot(snapshot) = log {
+            let backups = snapshot
+                .snapshot
+                .iter()
+                .flat_map(|snapshot| &snapshot.tracked_file_backups);
+
+            for (path, backup) in backups {
+                let file = self.files.entry(path.clone()).or_default();
+                if file
+                    .backup
+                    .as_ref()
+                    .is_none_or(|first| backup.version < first.version)
+                {
+                    file.backup = Some(backup.clone());
+                }
+            }
+            return;
+        }
+
+        if let Some((id, name, input)) = log.is_tool_request() {
+            if !EDIT_TOOLS.contains(&name.as_str()) {
+                return;
+            }
+            let Some(path) = input.get("file_path").and_then(|v| v.as_str()) else {
+                return;
+            };
+
+            self.pending.insert(id.clone(), path.to_string());
+            self.files
+                .entry(path.to_string())
+                .or_default()
+                .edits
+                .push(FileEdit {
+                    tool_use_id: id,
+                    tool: name,
+                    applied: None,
+                    input,
+                });
+            return;
+        }
+
+        let (id, is_error) = log.is_tool_result();
+        if let Some(path) = self.pending.remove(&id)
+            && let Some(edit) = self
+                .files
+                .get_mut(&path)
+                .and_then(|file| file.edits.iter_mut().find(|e| e.tool_use_id == id))
+        {
+            edit.applied = Some(!is_error);
+        }
+    }
+
+    /// List the touched files, reading backups from `backup_dir`
+    /// (`~/.claude/file-history/<session id>`) and the current content from disk
+    pub fn changes(&self, backup_dir: &Path, with_content: bool) -> Vec<FileChange> {
+        self.files
+            .iter()
+            .map(|(path, file)| {
+                let after = std::fs::read_to_string(self.resolve(path)).ok();
+                let before = match &file.backup {
+                    Some(TrackedFileBackup {
+                        backup_file_name: Some(name),
+                        ..
+                    }) => std::fs::read_to_string(backup_dir.join(name)).ok(),
+                    Some(TrackedFileBackup {
+                        backup_file_name: None,
+                        ..
+                    }) => Some(String::new()),
+                    None => None,
+                };
+
+                let created = matches!(
+                    file.backup,
+                    Some(TrackedFileBackup {
+                        backup_file_name: None,
+                        ..
+                    })
+                );
+                let status = if created {
+                    FileChangeStatus::Created
+                } else if after.is_none() {
+                    FileChangeStatus::Deleted
+                } else {
+                    FileChangeStatus::Modified
+                };
+
+                let diff = match &before {
+                    Some(before) => {
+                        unified_diff(path, before, after.as_deref().unwrap_or_default())
+                    }
+                    None => {
+                        let hunks = file
+                            .edits
+                            .iter()
+                            .filter(|edit| edit.applied != Some(false))
+                            .flat_map(|edit| edit.replacements())
+                            .map(|(old, new)| diff_hunks(old, new))
+                            .collect::<String>();
+                        with_header(path, hunks)
+                    }
+                };
+
+                FileChange {
+                    path: path.clone(),
+                    status,
+                    edits: file.edits.clone(),
+                    diff,
+                    before: before.filter(|_| with_content),
+                    after: after.filter(|_| with_content),
+                }
+            })
+            .collect()
+    }
+
+    fn resolve(&self, path: &str) -> PathBuf {
+        match &self.cwd {
+            Some(cwd) => cwd.join(path),
+            None => PathBuf::from(path),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum DiffLine<'a> {
+    Equal(&'a str),
+    Delete(&'a str),
+    Insert(&'a str),
+}
+
+fn diff_lines<'a>(a: &[&'a str], b: &[&'a str]) -> Vec<DiffLine<'a>> {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+
+    let a_mid = &a[prefix..a.len() - suffix];
+    let b_mid = &b[prefix..b.len() - suffix];
+    let (n, m) = (a_mid.len(), b_mid.len());
+
+    let mut lines: Vec<DiffLine> = a[..prefix].iter().map(|l| DiffLine::Equal(l)).collect();
+
+    if n * m > MAX_DIFF_CELLS {
+        lines.extend(a_mid.iter().map(|l| DiffLine::Delete(l)));
+        lines.extend(b_mid.iter().map(|l| DiffLine::Insert(l)));
+    } else {
+        let idx = |i: usize, j: usize| i * (m + 1) + j;
+        let mut lcs = vec![0u32; (n + 1) * (m + 1)];
+        for i in (0..n).rev() {
+            for j in (0..m).rev() {
+                lcs[idx(i, j)] = if a_mid[i] == b_mid[j] {
+                    lcs[idx(i + 1, j + 1)] + 1
+                } else {
+                    lcs[idx(i + 1, j)].max(lcs[idx(i, j + 1)])
+                };
+            }
+        }
+
+        let (mut i, mut j) = (0, 0);
+        while i < n && j < m {
+            if a_mid[i] == b_mid[j] {
+                lines.push(DiffLine::Equal(a_mid[i]));
+                i += 1;
+                j += 1;
+            } else if lcs[idx(i + 1, j)] >= lcs[idx(i, j + 1)] {
+                lines.push(DiffLine::Delete(a_mid[i]));
+                i += 1;
+            } else {
+                lines.push(DiffLine::Insert(b_mid[j]));
+                j += 1;
+            }
+        }
+        lines.extend(a_mid[i..].iter().map(|l| DiffLine::Delete(l)));
+        lines.extend(b_mid[j..].iter().map(|l| DiffLine::Insert(l)));
+    }
+
+    lines.extend(a[a.len() - suffix..].iter().map(|l| DiffLine::Equal(l)));
+    lines
+}
+
+fn hunk_range(start: usize, len: usize) -> String {
+    match len {
+        0 => format!("{},0", start),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{}", start + 1, len),
+    }
+}
+
+/// The `@@` hunks of a unified diff between two texts, without file headers
+fn diff_hunks(before: &str, after: &str) -> String {
+    let a = before.lines().collect::<Vec<_>>();
+    let b = after.lines().collect::<Vec<_>>();
+    let lines = diff_lines(&a, &b);
+
+    let changed = lines
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| !matches!(line, DiffLine::Equal(_)))
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+
+    let mut output = String::new();
+    let mut k = 0;
+    while k < changed.len() {
+        let start = changed[k].saturating_sub(CONTEXT);
+        while k + 1 < changed.len() && changed[k + 1] <= changed[k] + 2 * CONTEXT + 1 {
+            k += 1;
+        }
+        let end = (changed[k] + CONTEXT + 1).min(lines.len());
+        k += 1;
+
+        let a_start = lines[..start]
+            .iter()
+            .filter(|line| !matches!(line, DiffLine::Insert(_)))
+            .count();
+        let b_start = lines[..start]
+            .iter()
+            .filter(|line| !matches!(line, DiffLine::Delete(_)))
+            .count();
+        let hunk = &lines[start..end];
+        let a_len = hunk
+            .iter()
+            .filter(|line| !matches!(line, DiffLine::Insert(_)))
+            .count();
+        let b_len = hunk
+            .iter()
+            .filter(|line| !matches!(line, DiffLine::Delete(_)))
+            .count();
+
+        output.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            hunk_range(a_start, a_len),
+            hunk_range(b_start, b_len)
+        ));
+        for line in hunk {
+            let (sign, text) = match line {
+                DiffLine::Equal(text) => (' ', text),
+                DiffLine::Delete(text) => ('-', text),
+                DiffLine::Insert(text) => ('+', text),
+            };
+            output.push(sign);
+            output.push_str(text);
+            output.push('\n');
+        }
+    }
+
+    output
+}
+
+fn with_header(path: &str, hunks: String) -> String {
+    if hunks.is_empty() {
+        hunks
+    } else {
+        format!("--- {path}\n+++ {path}\n{hunks}")
+    }
+}
+
+/// Unified diff of `before` and `after`, empty when they are identical
+pub fn unified_diff(path: &str, before: &str, after: &str) -> String {
+    with_header(path, diff_hunks(before, after))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_unified_diff() {
+        let before = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n";
+        let after = "a\nB\nc\nd\ne\nf\ng\nh\ni\nj\nk\n";
+
+        assert_eq!(
+            unified_diff("src/lib.rs", before, after),
+            "--- src/lib.rs\n+++ src/lib.rs\n\
+             @@ -1,5 +1,5 @@\n a\n-b\n+B\n c\n d\n e\n\
+             @@ -8,3 +8,4 @@\n h\n i\n j\n+k\n"
+        );
+        assert_eq!(unified_diff("src/lib.rs", before, before), "");
+    }
+
+    #[test]
+    fn test_unified_diff_new_file() {
+        assert_eq!(
+            unified_diff("notes.md", "", "hello\n"),
+            "--- notes.md\n+++ notes.md\n@@ -0,0 +1 @@\n+hello\n"
+        );
+    }
+}
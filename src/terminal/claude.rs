@@ -1,11 +1,17 @@
-use std::collections::LinkedList;
+use std::collections::{HashMap, LinkedList};
 
 use linemux::Line;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-use crate::types::claude::ClaudeCodeLog;
+use crate::types::claude::{ClaudeCodeLog, ClaudeCodeTodo, ClaudeCodeUsage, TodoStatus};
 
-use super::{EchokitChild, PtyCommand, PtySize, TerminalType};
+use super::{
+    EchokitChild, OutputChunk, PtyCommand, PtySize, TerminalType,
+    changes::{ChangeLedger, FileChange},
+    commands,
+    keys::{Key, KeyInput, Modifier},
+    utf8::Utf8Decoder,
+};
 
 #[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
 pub struct UseTool {
@@ -15,11 +21,206 @@ pub struct UseTool {
     pub done: bool,
 }
 
+/// Tool names Claude Code uses to launch a subagent
+const SUBAGENT_TOOLS: [&str; 2] = ["Task", "Agent"];
+
+/// Tool Claude Code calls in plan mode to ask for approval of its plan
+const EXIT_PLAN_MODE_TOOL: &str = "ExitPlanMode";
+
+/// Options of the menu asking to approve a plan: "Yes, and auto-accept
+/// edits", "Yes, and manually approve edits", "No, keep planning"
+pub const PLAN_OPTION_AUTO_ACCEPT: usize = 0;
+pub const PLAN_OPTION_APPROVE: usize = 1;
+
+/// Label prefix of the recording markers telling which history file the
+/// session moved to, followed by its path
+pub const HISTORY_MARKER: &str = "history:";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubagentStatus {
+    Running,
+    Done,
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct Subagent {
+    /// The id of the `Task` tool_use that launched this subagent
+    pub id: String,
+    pub description: String,
+    pub subagent_type: String,
+    pub current_tool: Option<String>,
+    pub tool_uses: usize,
+    pub status: SubagentStatus,
+    #[serde(skip)]
+    prompt: String,
+    #[serde(skip)]
+    agent_id: Option<String>,
+}
+
+impl Subagent {
+    fn from_tool(tool: &UseTool) -> Self {
+        let field = |key: &str| {
+            tool.input
+                .get(key)
+                .and_then(|v| v.as_str())
+                .unwrap_or_default()
+                .to_string()
+        };
+
+        Subagent {
+            id: tool.id.clone(),
+            description: field("description"),
+            subagent_type: field("subagent_type"),
+            current_tool: None,
+            tool_uses: 0,
+            status: SubagentStatus::Running,
+            prompt: field("prompt"),
+            agent_id: None,
+        }
+    }
+}
+
+/// The plan Claude keeps with the `TodoWrite` tool
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+pub struct TodoProgress {
+    pub items: Vec<ClaudeCodeTodo>,
+    pub completed: usize,
+    pub total: usize,
+}
+
+impl TodoProgress {
+    fn new(items: Vec<ClaudeCodeTodo>) -> Self {
+        let completed = items
+            .iter()
+            .filter(|todo| todo.status == TodoStatus::Completed)
+            .count();
+
+        TodoProgress {
+            total: items.len(),
+            completed,
+            items,
+        }
+    }
+}
+
+/// Session events that are not part of the `ClaudeCodeState` transitions,
+/// collected by `update_state` and drained with `take_events`
+#[derive(Debug, Clone)]
+pub enum ClaudeCodeEvent {
+    Subagent(Subagent),
+    Todos(TodoProgress),
+    Compacted(Compaction),
+}
+
+/// The conversation was compacted or cleared
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct Compaction {
+    /// `auto`, `manual` or `clear`
+    pub trigger: String,
+    /// Context size before, from Claude Code or the last API call
+    pub pre_tokens: u64,
+    /// Context size after, estimated from the summary when Claude Code does
+    /// not log it
+    pub post_tokens: Option<u64>,
+}
+
+/// The spinner line shown while Claude Code summarizes the conversation,
+/// `✻ Compacting conversation… (esc to interrupt)`
+const COMPACTING_MARKER: &str = "Compacting conversation";
+
+/// The glyphs the Claude Code spinner cycles through
+const SPINNER_GLYPHS: [char; 7] = ['·', '✢', '✳', '✶', '✻', '✽', '*'];
+
+/// A compaction still shown after this long is taken to have ended unnoticed
+const COMPACTING_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(600);
+
+/// Rough size of a token, for estimating the size of a compaction summary
+const CHARS_PER_TOKEN: usize = 4;
+
+/// The permission mode Claude Code cycles through with Shift+Tab
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PermissionMode {
+    #[default]
+    Default,
+    AcceptEdits,
+    Plan,
+    /// Only part of the cycle when Claude Code was started with it allowed
+    BypassPermissions,
+}
+
+impl PermissionMode {
+    /// Longest Shift+Tab cycle, with bypass permissions enabled
+    pub const CYCLE_LEN: usize = 4;
+
+    /// Parse the `--permission-mode` name used in the history file
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "default" => Some(PermissionMode::Default),
+            "acceptEdits" => Some(PermissionMode::AcceptEdits),
+            "plan" => Some(PermissionMode::Plan),
+            "bypassPermissions" => Some(PermissionMode::BypassPermissions),
+            _ => None,
+        }
+    }
+
+    /// The mode claude starts in with these command line arguments, the last
+    /// `--permission-mode` wins over `--dangerously-skip-permissions`
+    pub fn from_args<S: AsRef<str>>(args: &[S]) -> Self {
+        let mut mode = PermissionMode::Default;
+        let mut args = args.iter().map(|arg| arg.as_ref());
+        while let Some(arg) = args.next() {
+            let name = match arg.split_once('=') {
+                Some(("--permission-mode", name)) => Some(name),
+                _ if arg == "--permission-mode" => args.next(),
+                _ if arg == "--dangerously-skip-permissions" => Some("bypassPermissions"),
+                _ => None,
+            };
+            if let Some(parsed) = name.and_then(PermissionMode::from_name) {
+                mode = parsed;
+            }
+        }
+        mode
+    }
+
+    /// The mode shown in the footer of the input box. Every mode but the
+    /// default one has an indicator, the default footer shows the shortcut hint.
+    pub fn from_screen(output: &str) -> Option<Self> {
+        let screen = strip_ansi_escapes::strip_str(output).to_lowercase();
+        if screen.contains("plan mode on") {
+            Some(PermissionMode::Plan)
+        } else if screen.contains("accept edits on") {
+            Some(PermissionMode::AcceptEdits)
+        } else if screen.contains("bypass permissions on") {
+            Some(PermissionMode::BypassPermissions)
+        } else if screen.contains("? for shortcuts") {
+            Some(PermissionMode::Default)
+        } else {
+            None
+        }
+    }
+}
+
+impl std::fmt::Display for PermissionMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            PermissionMode::Default => "default",
+            PermissionMode::AcceptEdits => "acceptEdits",
+            PermissionMode::Plan => "plan",
+            PermissionMode::BypassPermissions => "bypassPermissions",
+        };
+        f.write_str(s)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
 #[serde(tag = "state")]
 pub enum ClaudeCodeState {
     PreUseTool {
         request: Vec<UseTool>,
+        subagents: Vec<Subagent>,
         is_pending: bool,
         #[serde(skip)]
         start_time: std::time::Instant,
@@ -35,6 +236,13 @@ pub enum ClaudeCodeState {
     Working {
         prompt: String,
     },
+    /// `ExitPlanMode` was called, the TUI asks to approve `plan` (markdown)
+    PlanProposed {
+        tool_id: String,
+        plan: String,
+    },
+    /// The conversation is being summarized, by `/compact` or automatically
+    Compacting,
 }
 
 impl ClaudeCodeState {
@@ -54,6 +262,8 @@ impl ClaudeCodeState {
         matches!(
             self,
             ClaudeCodeState::PreUseTool { .. }
+                | ClaudeCodeState::PlanProposed { .. }
+                | ClaudeCodeState::Compacting
                 | ClaudeCodeState::Output {
                     is_thinking: true,
                     ..
@@ -68,7 +278,7 @@ impl ClaudeCodeState {
                 ClaudeCodeState::PreUseTool {
                     is_pending: true,
                     ..
-                }
+                } | ClaudeCodeState::PlanProposed { .. }
             )
     }
 
@@ -76,47 +286,156 @@ impl ClaudeCodeState {
         matches!(self, ClaudeCodeState::PreUseTool { .. })
     }
 
-    pub fn to_string(&self) -> String {
+    /// Shift+Tab moves the selection of a permission prompt instead of
+    /// switching the mode
+    pub fn mode_switch_available(&self) -> bool {
+        !matches!(
+            self,
+            ClaudeCodeState::PreUseTool {
+                is_pending: true,
+                ..
+            } | ClaudeCodeState::PlanProposed { .. }
+        )
+    }
+
+    /// What the session reports once the PTY stays quiet for the poll
+    /// interval, `None` when it keeps waiting in this state
+    pub fn quiet_result(&self) -> Option<ClaudeCodeResult> {
         match self {
-            ClaudeCodeState::PreUseTool { .. } => "pre_use_tool".to_string(),
+            ClaudeCodeState::PreUseTool { .. } => {
+                Some(ClaudeCodeResult::WaitForUserInputBeforeTool)
+            }
+            ClaudeCodeState::Idle
+            | ClaudeCodeState::Output {
+                is_thinking: false, ..
+            }
+            | ClaudeCodeState::StopUseTool { is_error: true } => {
+                Some(ClaudeCodeResult::WaitForUserInput)
+            }
+            // the spinner keeps redrawing while a compaction runs
+            ClaudeCodeState::Compacting => Some(ClaudeCodeResult::WaitForUserInput),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ClaudeCodeState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            ClaudeCodeState::PreUseTool { .. } => "pre_use_tool",
             ClaudeCodeState::Output { is_thinking, .. } => {
                 if *is_thinking {
-                    "thinking".to_string()
+                    "thinking"
                 } else {
-                    "output".to_string()
+                    "output"
                 }
             }
             ClaudeCodeState::StopUseTool { is_error } => {
                 if *is_error {
-                    "stop_use_tool_error".to_string()
+                    "stop_use_tool_error"
                 } else {
-                    "stop_use_tool".to_string()
+                    "stop_use_tool"
                 }
             }
-            ClaudeCodeState::Idle => "idle".to_string(),
-            ClaudeCodeState::Working { .. } => "working".to_string(),
-        }
+            ClaudeCodeState::Idle => "idle",
+            ClaudeCodeState::Working { .. } => "working",
+            ClaudeCodeState::PlanProposed { .. } => "plan_proposed",
+            ClaudeCodeState::Compacting => "compacting",
+        };
+        f.write_str(s)
     }
 }
 
 pub struct ClaudeCode {
     history_file: linemux::MuxedLines,
     history_file_path: std::path::PathBuf,
-    start_output_buffer: LinkedList<String>,
-    state: ClaudeCodeState,
+    start_output_buffer: LinkedList<OutputChunk>,
+    tracker: ClaudeCodeTracker,
+    poll_interval: std::time::Duration,
+    /// Set by `/clear`, after which Claude Code logs to a new history file
+    clear_requested: Option<std::time::SystemTime>,
+    /// When the project directory was last scanned for that file
+    history_scanned: Option<tokio::time::Instant>,
 }
 
 impl TerminalType for ClaudeCode {
     type Output = ClaudeCodeResult;
 }
 
-/// Create a new ClaudeCode terminal session
+/// The session state recomputed from history lines and screen output alone,
+/// so that recorded sessions can be replayed without a terminal
+pub struct ClaudeCodeTracker {
+    state: ClaudeCodeState,
+    /// Maps sidechain message uuids to the id of the subagent that wrote them
+    sidechain_owner: HashMap<String, String>,
+    todos: TodoProgress,
+    changes: ChangeLedger,
+    /// Token usage per API message id
+    usage: HashMap<String, ClaudeCodeUsage>,
+    permission_mode: PermissionMode,
+    /// Context size of the last API call of the main conversation
+    context_tokens: u64,
+    /// The state a compaction interrupted, restored when it ends
+    before_compacting: Option<ClaudeCodeState>,
+    /// When the running compaction started
+    compacting_since: Option<std::time::Instant>,
+    /// A boundary waiting for its summary
+    pending_compaction: Option<Compaction>,
+    events: Vec<ClaudeCodeEvent>,
+}
+
+impl Default for ClaudeCodeTracker {
+    fn default() -> Self {
+        Self {
+            state: ClaudeCodeState::Idle,
+            sidechain_owner: HashMap::new(),
+            todos: TodoProgress::default(),
+            changes: ChangeLedger::default(),
+            usage: HashMap::new(),
+            permission_mode: PermissionMode::Default,
+            context_tokens: 0,
+            before_compacting: None,
+            compacting_since: None,
+            pending_compaction: None,
+            events: Vec::new(),
+        }
+    }
+}
+
+/// Default interval between idle and permission prompt checks
+pub const DEFAULT_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Create a new ClaudeCode terminal session, waiting `CC_WAIT_TIMEOUT` seconds
+/// (default 20) for the history file
 /// # Arguments
 /// - `claude_start_shell`: The command to run the claude code terminal, e.g. `run_cc.sh`
 pub async fn new(
+    claude_start_shell: &str,
+    uuid: uuid::Uuid,
+    size: (u16, u16),
+) -> pty_process::Result<EchokitChild<ClaudeCode>> {
+    let wait_timeout = std::env::var("CC_WAIT_TIMEOUT")
+        .map(|s| s.parse::<u64>().unwrap_or(20))
+        .unwrap_or(20);
+
+    new_with_timeout(
+        claude_start_shell,
+        uuid,
+        size,
+        std::time::Duration::from_secs(wait_timeout),
+        &[] as &[(&str, &str)],
+    )
+    .await
+}
+
+/// Like [`new`], giving up waiting for the history file after `startup_timeout`
+/// and with `env` set for the command
+pub async fn new_with_timeout<S: AsRef<std::ffi::OsStr>>(
     claude_start_shell: &str,
     mut uuid: uuid::Uuid,
     size: (u16, u16),
+    startup_timeout: std::time::Duration,
+    env: &[(S, S)],
 ) -> pty_process::Result<EchokitChild<ClaudeCode>> {
     let (row, col) = size;
 
@@ -150,6 +469,10 @@ pub async fn new(
         .env("PYTHONUNBUFFERED", "1")
         .env("CLAUDE_SESSION_ID", uuid.to_string());
 
+    for (key, value) in env {
+        cmd = cmd.env(key, value);
+    }
+
     let child = cmd.spawn(pts)?;
 
     log::debug!(
@@ -175,21 +498,23 @@ pub async fn new(
         .to_path_buf();
     std::fs::create_dir_all(&history_file_parent)?;
 
-    let wait_timeout = std::env::var("CC_WAIT_TIMEOUT")
-        .map(|s| s.parse::<u64>().unwrap_or(20))
-        .unwrap_or(20);
+    let wait_timeout = startup_timeout.as_secs().max(1);
 
     let mut ready = false;
 
     let mut start_output_buffer = LinkedList::new();
+    let mut decoder = Utf8Decoder::new();
 
     for i in 0..wait_timeout {
         if !ready {
             let mut buffer = [0u8; 1024];
             let n = pty.read(&mut buffer).await?;
-            let output = str::from_utf8(&buffer[..n]).unwrap_or("");
+            let output = decoder.decode(&buffer[..n]);
             log::trace!("PTY Output during history file check: {}", output);
-            start_output_buffer.push_back(output.to_string());
+            start_output_buffer.push_back(OutputChunk {
+                bytes: buffer[..n].to_vec(),
+                text: output.clone(),
+            });
 
             if output.contains("Claude Code") {
                 log::debug!("Claude Code terminal is ready.");
@@ -197,12 +522,12 @@ pub async fn new(
             }
 
             if output.contains("Enter to confirm · Esc to cancel") {
-                pty.write(b"\r").await?;
+                pty.write_all(b"\r").await?;
             }
         }
 
-        pty.write(&[27, 91, 73]).await?; // ESC [ I
-        // pty.write(b"\r").await?;
+        pty.write_all(&[27, 91, 73]).await?; // ESC [ I
+        // pty.write_all(b"\r").await?;
         log::debug!(
             "Checking for claude code history file existence, attempt {}",
             i + 1
@@ -230,8 +555,15 @@ pub async fn new(
             history_file,
             history_file_path: history_file_path.into(),
             start_output_buffer,
-            state: ClaudeCodeState::Idle,
+            tracker: ClaudeCodeTracker::default(),
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            clear_requested: None,
+            history_scanned: None,
         },
+        decoder,
+        recorder: None,
+        pacing: super::pacing::InputPacing::default(),
+        typed: String::new(),
     })
 }
 
@@ -274,13 +606,17 @@ pub async fn new_with_command<S: AsRef<str>>(
 
     let mut start_output_buffer = LinkedList::new();
     let mut buffer = [0u8; 1024];
+    let mut decoder = Utf8Decoder::new();
 
     loop {
         let n = pty.read(&mut buffer).await?;
-        let output = str::from_utf8(&buffer[..n]).unwrap_or("");
+        let output = decoder.decode(&buffer[..n]);
         log::trace!("PTY Output during history file check: {}", output);
 
-        start_output_buffer.push_back(output.to_string());
+        start_output_buffer.push_back(OutputChunk {
+            bytes: buffer[..n].to_vec(),
+            text: output.clone(),
+        });
 
         if output.contains("Claude Code") {
             log::debug!("Claude Code terminal is ready.");
@@ -310,11 +646,14 @@ pub async fn new_with_command<S: AsRef<str>>(
         let n = n.unwrap()?;
 
         log::debug!("Read {} bytes from PTY for status output", n);
-        let output = str::from_utf8(&buffer[..n]).unwrap_or("");
+        let output = decoder.decode(&buffer[..n]);
         log::trace!("PTY Output during history file check: {}", output);
 
-        status_output.push_str(output);
-        start_output_buffer.push_back(output.to_string());
+        status_output.push_str(&output);
+        start_output_buffer.push_back(OutputChunk {
+            bytes: buffer[..n].to_vec(),
+            text: output.clone(),
+        });
 
         if output.contains("Model:") {
             pty.write_all(b"\x1b").await?;
@@ -322,47 +661,28 @@ pub async fn new_with_command<S: AsRef<str>>(
         }
     }
 
-    let mut uuid = uuid::Uuid::nil();
-    let mut cwd = String::new();
-    let status_output = strip_ansi_escapes::strip_str(&status_output);
-    for line in status_output.lines() {
-        if let Some(session_id) = line.trim().strip_prefix("Session ID:") {
-            let session_id = session_id.trim();
-            log::debug!("Extracted session ID from status output: {}", session_id);
-            if let Ok(uuid_) = uuid::Uuid::parse_str(session_id) {
-                uuid = uuid_;
-                log::debug!("Parsed session ID as UUID: {}", uuid);
-            }
-            continue;
-        }
-
-        if let Some(session_id) = line.trim().strip_prefix("SessionID:") {
-            let session_id = session_id.trim();
-            log::debug!("Extracted session ID from status output: {}", session_id);
-            if let Ok(uuid_) = uuid::Uuid::parse_str(session_id) {
-                uuid = uuid_;
-                log::debug!("Parsed session ID as UUID: {}", uuid);
-            }
-            continue;
-        }
-
-        if let Some(cwd_) = line.trim().strip_prefix("cwd:") {
-            cwd = cwd_.trim().to_string();
-            log::debug!("Extracted current directory from status output: {}", cwd);
-            continue;
-        }
-    }
+    let status = commands::parse_status(&status_output);
+    log::debug!(
+        "Extracted session ID {:?} and current directory {:?} from status output",
+        status.session_id,
+        status.cwd
+    );
+    let uuid = status
+        .session_id
+        .and_then(|session_id| uuid::Uuid::parse_str(&session_id).ok())
+        .unwrap_or_default();
+    let cwd = status.cwd.unwrap_or_default();
 
     if uuid.is_nil() {
-        return Err(pty_process::Error::Io(std::io::Error::other(format!(
-            "Failed to extract session ID from status output"
-        ))));
+        return Err(pty_process::Error::Io(std::io::Error::other(
+            "Failed to extract session ID from status output",
+        )));
     }
 
     if cwd.is_empty() {
-        return Err(pty_process::Error::Io(std::io::Error::other(format!(
-            "Failed to extract current directory from status output"
-        ))));
+        return Err(pty_process::Error::Io(std::io::Error::other(
+            "Failed to extract current directory from status output",
+        )));
     }
 
     log::debug!(
@@ -404,38 +724,472 @@ pub async fn new_with_command<S: AsRef<str>>(
             history_file,
             history_file_path,
             start_output_buffer,
-            state: ClaudeCodeState::Idle,
+            tracker: ClaudeCodeTracker {
+                permission_mode: PermissionMode::from_args(args),
+                ..Default::default()
+            },
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            clear_requested: None,
+            history_scanned: None,
         },
+        decoder,
+        recorder: None,
+        pacing: super::pacing::InputPacing::default(),
+        typed: String::new(),
     })
 }
 
+/// Claude Code only starts the history file of a `/clear` with the next
+/// prompt, one not showing up within this long is not looked for anymore
+const CLEAR_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1800);
+
+/// The history file `/clear` started next to `current`: the first one of the
+/// project directory created since, that begins a new conversation. Files of
+/// other sessions that were only written to meanwhile are never picked, unless
+/// the filesystem keeps no creation time and the modification time stands in.
+fn find_new_history_file(
+    current: &std::path::Path,
+    since: std::time::SystemTime,
+) -> Option<std::path::PathBuf> {
+    std::fs::read_dir(current.parent()?)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            entry.path().extension().is_some_and(|ext| ext == "jsonl") && entry.path() != current
+        })
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            let created = metadata.created().or_else(|_| metadata.modified()).ok()?;
+            (created >= since && starts_conversation(&entry.path()))
+                .then(|| (created, entry.path()))
+        })
+        .min()
+        .map(|(_, path)| path)
+}
+
+/// Whether the first message of a history file is the root of a new
+/// conversation logged under the file's own session id. Snapshot lines before
+/// it carry no session id and are skipped.
+fn starts_conversation(path: &std::path::Path) -> bool {
+    use std::io::BufRead;
+
+    let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) else {
+        return false;
+    };
+    let Ok(file) = std::fs::File::open(path) else {
+        return false;
+    };
+
+    std::io::BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(&line).ok())
+        .find(|line| line.get("sessionId").is_some())
+        .is_some_and(|line| {
+            line["sessionId"] == stem
+                && line.get("parentUuid").is_none_or(|parent| parent.is_null())
+        })
+}
+
+/// The state for a tool request, `ExitPlanMode` proposes a plan instead of
+/// using a tool
+fn tool_request_state(id: String, name: String, input: serde_json::Value) -> ClaudeCodeState {
+    if name == EXIT_PLAN_MODE_TOOL {
+        let plan = input
+            .get("plan")
+            .and_then(|plan| plan.as_str())
+            .unwrap_or_default()
+            .to_string();
+        return ClaudeCodeState::PlanProposed { tool_id: id, plan };
+    }
+
+    ClaudeCodeState::PreUseTool {
+        request: vec![UseTool {
+            id,
+            name,
+            input,
+            done: false,
+        }],
+        subagents: vec![],
+        is_pending: false,
+        start_time: std::time::Instant::now(),
+    }
+}
+
 pub enum ClaudeCodeResult {
-    PtyOutput(String),
-    ClaudeLog(ClaudeCodeLog),
+    /// An empty chunk is EOF
+    PtyOutput(OutputChunk),
+    /// The history is now read from this file, after `/clear`
+    HistoryMoved(std::path::PathBuf),
+    ClaudeLog(Box<ClaudeCodeLog>),
     WaitForUserInputBeforeTool,
     WaitForUserInput,
     Uncaught(String),
 }
 
-impl EchokitChild<ClaudeCode> {
-    pub fn session_id(&self) -> uuid::Uuid {
-        self.uuid
+/// Whether `screen` shows the compaction spinner: a spinner glyph, then
+/// `Compacting conversation…`. Text merely mentioning a compaction, like a
+/// prompt or an answer, does not count.
+fn is_compacting_spinner(screen: &str) -> bool {
+    screen
+        .match_indices(COMPACTING_MARKER)
+        .any(|(start, marker)| {
+            let rest = &screen[start + marker.len()..];
+            screen[..start].trim_end().ends_with(SPINNER_GLYPHS)
+                && (rest.starts_with('…') || rest.starts_with("..."))
+        })
+}
+
+impl ClaudeCodeTracker {
+    pub fn state(&self) -> &ClaudeCodeState {
+        &self.state
     }
 
-    pub fn log_file_path(&self) -> &std::path::PathBuf {
-        &self.terminal_type.history_file_path
+    pub fn todos(&self) -> &TodoProgress {
+        &self.todos
     }
 
-    pub fn state(&self) -> &ClaudeCodeState {
-        &self.terminal_type.state
+    pub fn permission_mode(&self) -> PermissionMode {
+        self.permission_mode
+    }
+
+    /// Track what PTY output shows besides the history file: the permission
+    /// mode and compactions. Returns whether the state changed.
+    pub fn update_from_screen(&mut self, output: &str) -> bool {
+        let compacting = self.update_compacting(output);
+        self.update_permission_mode(output) || compacting
+    }
+
+    fn update_compacting(&mut self, output: &str) -> bool {
+        let screen = strip_ansi_escapes::strip_str(output);
+        if self.state != ClaudeCodeState::Compacting {
+            return is_compacting_spinner(&screen) && self.start_compacting();
+        }
+
+        if self
+            .compacting_since
+            .is_some_and(|since| since.elapsed() > COMPACTING_TIMEOUT)
+        {
+            log::debug!("Compaction timed out");
+            return self.end_compacting();
+        }
+
+        let screen = screen.to_lowercase();
+        let failed = screen.contains("compact")
+            && (screen.contains("error") || screen.contains("cancel"))
+            || screen.contains("interrupted");
+        if failed {
+            log::debug!("Compaction did not finish");
+            return self.end_compacting();
+        }
+        false
+    }
+
+    /// A `/compact` was submitted or its spinner shows up, returns whether
+    /// the state changed
+    pub fn start_compacting(&mut self) -> bool {
+        if self.state == ClaudeCodeState::Compacting {
+            return false;
+        }
+        log::debug!("Compaction started in state {:?}", self.state);
+        let state = std::mem::replace(&mut self.state, ClaudeCodeState::Compacting);
+        self.before_compacting = Some(state);
+        self.compacting_since = Some(std::time::Instant::now());
+        true
+    }
+
+    /// The PTY stayed quiet for the poll interval: a compaction without its
+    /// spinner is over even if no summary was logged. Returns whether the
+    /// state changed.
+    pub fn update_on_quiet(&mut self) -> bool {
+        if self.state == ClaudeCodeState::Compacting {
+            log::debug!("Compaction went quiet without finishing");
+        }
+        self.end_compacting()
+    }
+
+    /// Restore the state a compaction interrupted, returns whether it did
+    fn end_compacting(&mut self) -> bool {
+        if self.state != ClaudeCodeState::Compacting {
+            return false;
+        }
+        self.compacting_since = None;
+        self.state = self
+            .before_compacting
+            .take()
+            .unwrap_or(ClaudeCodeState::Idle);
+        true
+    }
+
+    fn update_permission_mode(&mut self, output: &str) -> bool {
+        match PermissionMode::from_screen(output) {
+            Some(mode) if mode != self.permission_mode => {
+                log::debug!("Permission mode changed to {}", mode);
+                self.permission_mode = mode;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Tokens used so far by the session and its subagents
+    pub fn usage(&self) -> ClaudeCodeUsage {
+        let mut total = ClaudeCodeUsage::default();
+        for usage in self.usage.values() {
+            total += *usage;
+        }
+        total
+    }
+
+    /// Drain the events collected since the last call
+    pub fn take_events(&mut self) -> Vec<ClaudeCodeEvent> {
+        std::mem::take(&mut self.events)
     }
 
     pub fn update_state(&mut self, result: &ClaudeCodeResult) -> bool {
+        if let ClaudeCodeResult::ClaudeLog(log) = result {
+            // subagents edit files too, so the ledger sees sidechain lines as well
+            self.changes.record(log);
+            if let Some((id, usage)) = log.usage() {
+                self.usage.insert(id.to_string(), *usage);
+            }
+
+            if log.is_sidechain() {
+                return self.update_subagent(log);
+            }
+            self.finish_subagent(log);
+            self.update_todos(log);
+        }
+
+        let mode_updated = self.update_mode_from_log(result);
+        if let ClaudeCodeResult::ClaudeLog(log) = result
+            && let Some(state_updated) = self.update_compaction(log)
+        {
+            return state_updated || mode_updated;
+        }
+        let state_updated = self.update_main_state(result);
+        self.start_subagents() || state_updated || mode_updated
+    }
+
+    /// Handle compaction boundaries and summaries, which are not part of the
+    /// conversation. Returns whether the state changed when the log was one of
+    /// them, `None` otherwise.
+    fn update_compaction(&mut self, log: &ClaudeCodeLog) -> Option<bool> {
+        if let Some(usage) = log.usage().map(|(_, usage)| usage) {
+            self.context_tokens = usage.total();
+        }
+
+        if let Some(metadata) = log.compact_boundary() {
+            if let Some(compaction) = self.pending_compaction.take() {
+                self.events.push(ClaudeCodeEvent::Compacted(compaction));
+            }
+            let compaction = Compaction {
+                trigger: metadata.trigger.clone(),
+                pre_tokens: metadata.pre_tokens.unwrap_or(self.context_tokens),
+                post_tokens: metadata.post_tokens,
+            };
+            if compaction.post_tokens.is_some() {
+                self.events.push(ClaudeCodeEvent::Compacted(compaction));
+            } else {
+                self.pending_compaction = Some(compaction);
+            }
+            return Some(self.end_compacting());
+        }
+
+        if let Some(summary) = log.compact_summary() {
+            let post_tokens = (summary.len() / CHARS_PER_TOKEN) as u64;
+            self.context_tokens = post_tokens;
+            if let Some(mut compaction) = self.pending_compaction.take() {
+                compaction.post_tokens = Some(post_tokens);
+                self.events.push(ClaudeCodeEvent::Compacted(compaction));
+            }
+            return Some(false);
+        }
+
+        // older versions write no summary after the boundary
+        if let Some(compaction) = self.pending_compaction.take() {
+            self.events.push(ClaudeCodeEvent::Compacted(compaction));
+        }
+        None
+    }
+
+    fn update_mode_from_log(&mut self, result: &ClaudeCodeResult) -> bool {
+        let ClaudeCodeResult::ClaudeLog(log) = result else {
+            return false;
+        };
+        match log.permission_mode().and_then(PermissionMode::from_name) {
+            Some(mode) if mode != self.permission_mode => {
+                self.permission_mode = mode;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn update_todos(&mut self, log: &ClaudeCodeLog) {
+        let Some(items) = log.todos() else {
+            return;
+        };
+
+        if items != self.todos.items {
+            let todos = TodoProgress::new(items);
+            log::debug!(
+                "Todo list updated: {} of {} done",
+                todos.completed,
+                todos.total
+            );
+            self.events.push(ClaudeCodeEvent::Todos(todos.clone()));
+            self.todos = todos;
+        }
+    }
+
+    /// Register a subagent for every `Task` tool request that does not have one yet
+    fn start_subagents(&mut self) -> bool {
+        let ClaudeCodeState::PreUseTool {
+            request, subagents, ..
+        } = &mut self.state
+        else {
+            return false;
+        };
+
+        let mut updated = false;
+        for tool in request.iter() {
+            if !SUBAGENT_TOOLS.contains(&tool.name.as_str())
+                || subagents.iter().any(|agent| agent.id == tool.id)
+            {
+                continue;
+            }
+
+            let subagent = Subagent::from_tool(tool);
+            log::debug!("Subagent started: {:?}", subagent);
+            self.events
+                .push(ClaudeCodeEvent::Subagent(subagent.clone()));
+            subagents.push(subagent);
+            updated = true;
+        }
+        updated
+    }
+
+    /// Mark the subagent as finished when the result of its `Task` tool arrives
+    fn finish_subagent(&mut self, log: &ClaudeCodeLog) {
+        let (id, is_error) = log.is_tool_result();
+        if id.is_empty() {
+            return;
+        }
+
+        let ClaudeCodeState::PreUseTool { subagents, .. } = &mut self.state else {
+            return;
+        };
+
+        if let Some(subagent) = subagents.iter_mut().find(|agent| agent.id == id) {
+            subagent.status = if is_error {
+                SubagentStatus::Error
+            } else {
+                SubagentStatus::Done
+            };
+            subagent.current_tool = None;
+            log::debug!("Subagent finished: {:?}", subagent);
+            self.events
+                .push(ClaudeCodeEvent::Subagent(subagent.clone()));
+            self.sidechain_owner.retain(|_, owner| *owner != id);
+        }
+    }
+
+    /// Apply a sidechain line to the subagent that produced it, leaving the
+    /// main conversation state untouched
+    fn update_subagent(&mut self, log: &ClaudeCodeLog) -> bool {
+        let ClaudeCodeTracker {
+            state,
+            sidechain_owner,
+            ..
+        } = self;
+
+        let ClaudeCodeState::PreUseTool {
+            subagents,
+            is_pending,
+            start_time,
+            ..
+        } = state
+        else {
+            log::debug!("Sidechain line outside of a tool use: {:?}", log);
+            return false;
+        };
+
+        let owner = log
+            .agent_id()
+            .and_then(|agent_id| {
+                subagents
+                    .iter()
+                    .position(|agent| agent.agent_id.as_deref() == Some(agent_id))
+            })
+            .or_else(|| {
+                let owner_id = sidechain_owner.get(log.parent_uuid()?)?;
+                subagents.iter().position(|agent| agent.id == *owner_id)
+            })
+            .or_else(|| {
+                let prompt = log.is_user_prompt()?;
+                subagents
+                    .iter()
+                    .position(|agent| agent.agent_id.is_none() && agent.prompt == prompt)
+            })
+            .or_else(|| {
+                // a single running subagent owns any line we cannot attribute otherwise
+                let mut running = subagents
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, agent)| agent.status == SubagentStatus::Running);
+                match (running.next(), running.next()) {
+                    (Some((i, _)), None) => Some(i),
+                    _ => None,
+                }
+            });
+
+        let Some(index) = owner else {
+            log::debug!("Sidechain line without a matching subagent: {:?}", log);
+            return false;
+        };
+
+        let subagent = &mut subagents[index];
+        if let Some(uuid) = log.uuid() {
+            sidechain_owner.insert(uuid.to_string(), subagent.id.clone());
+        }
+        if subagent.agent_id.is_none() {
+            subagent.agent_id = log.agent_id().map(str::to_string);
+        }
+
+        // subagent activity means the Task tool is running, not waiting for approval
+        if !*is_pending {
+            *start_time = std::time::Instant::now();
+        }
+
+        if let Some((_, name, _)) = log.is_tool_request() {
+            subagent.current_tool = Some(name);
+            subagent.tool_uses += 1;
+            true
+        } else if !log.is_tool_result().0.is_empty() {
+            subagent.current_tool = None;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn update_main_state(&mut self, result: &ClaudeCodeResult) -> bool {
         let mut state_updated = false;
-        match (result, &mut self.terminal_type.state) {
+        match (result, &mut self.state) {
             (ClaudeCodeResult::PtyOutput(..), _) => {
                 log::debug!("Updating state from Idle to Processing");
             }
+            (ClaudeCodeResult::HistoryMoved(..), _) => {
+                self.events.push(ClaudeCodeEvent::Compacted(Compaction {
+                    trigger: "clear".to_string(),
+                    pre_tokens: self.context_tokens,
+                    post_tokens: Some(0),
+                }));
+                self.context_tokens = 0;
+                self.state = ClaudeCodeState::Idle;
+                state_updated = true;
+            }
             (
                 ClaudeCodeResult::WaitForUserInputBeforeTool,
                 ClaudeCodeState::PreUseTool { is_pending, .. },
@@ -444,7 +1198,7 @@ impl EchokitChild<ClaudeCode> {
                 state_updated = true;
             }
             (ClaudeCodeResult::WaitForUserInput, ClaudeCodeState::Output { .. }) => {
-                self.terminal_type.state = ClaudeCodeState::Idle;
+                self.state = ClaudeCodeState::Idle;
                 state_updated = true;
             }
             (ClaudeCodeResult::ClaudeLog(log), ClaudeCodeState::PreUseTool { request, .. }) => {
@@ -453,15 +1207,14 @@ impl EchokitChild<ClaudeCode> {
 
                 if !id.is_empty() {
                     if is_error {
-                        self.terminal_type.state = ClaudeCodeState::StopUseTool { is_error: true };
+                        self.state = ClaudeCodeState::StopUseTool { is_error: true };
                     } else {
                         let len = request.len();
                         for (i, tool) in request.iter_mut().enumerate() {
                             if tool.id == id {
                                 tool.done = true;
                                 if i == len - 1 {
-                                    self.terminal_type.state =
-                                        ClaudeCodeState::StopUseTool { is_error: false };
+                                    self.state = ClaudeCodeState::StopUseTool { is_error: false };
                                 }
                                 break;
                             }
@@ -472,7 +1225,12 @@ impl EchokitChild<ClaudeCode> {
                 }
 
                 if log.is_stop() {
-                    self.terminal_type.state = ClaudeCodeState::StopUseTool { is_error: false };
+                    self.state = ClaudeCodeState::StopUseTool { is_error: false };
+                    state_updated = true;
+                } else if let Some((id, name, input)) = log.is_tool_request()
+                    && name == EXIT_PLAN_MODE_TOOL
+                {
+                    self.state = tool_request_state(id, name, input);
                     state_updated = true;
                 } else if let Some((id, name, input)) = log.is_tool_request() {
                     request.push(UseTool {
@@ -486,22 +1244,13 @@ impl EchokitChild<ClaudeCode> {
             }
             (ClaudeCodeResult::ClaudeLog(log), ClaudeCodeState::Working { .. }) => {
                 if log.is_stop() {
-                    self.terminal_type.state = ClaudeCodeState::Idle;
+                    self.state = ClaudeCodeState::Idle;
                     state_updated = true;
                 } else if let Some((id, name, input)) = log.is_tool_request() {
-                    self.terminal_type.state = ClaudeCodeState::PreUseTool {
-                        request: vec![UseTool {
-                            id,
-                            name,
-                            input,
-                            done: false,
-                        }],
-                        is_pending: false,
-                        start_time: std::time::Instant::now(),
-                    };
+                    self.state = tool_request_state(id, name, input);
                     state_updated = true;
                 } else if let Some((output, is_thinking)) = log.is_output() {
-                    self.terminal_type.state = ClaudeCodeState::Output {
+                    self.state = ClaudeCodeState::Output {
                         output,
                         is_thinking,
                     };
@@ -516,24 +1265,15 @@ impl EchokitChild<ClaudeCode> {
                 },
             ) => {
                 if let Some(prompt) = log.is_user_prompt() {
-                    self.terminal_type.state = ClaudeCodeState::Working { prompt };
+                    self.state = ClaudeCodeState::Working { prompt };
                     state_updated = true;
                     return state_updated;
                 }
                 if log.is_stop() {
-                    self.terminal_type.state = ClaudeCodeState::Idle;
+                    self.state = ClaudeCodeState::Idle;
                     state_updated = true;
                 } else if let Some((id, name, input)) = log.is_tool_request() {
-                    self.terminal_type.state = ClaudeCodeState::PreUseTool {
-                        request: vec![UseTool {
-                            id,
-                            name,
-                            input,
-                            done: false,
-                        }],
-                        is_pending: false,
-                        start_time: std::time::Instant::now(),
-                    };
+                    self.state = tool_request_state(id, name, input);
                     state_updated = true;
                 } else if let Some((output_, thinking_)) = log.is_output() {
                     *output = output_;
@@ -546,39 +1286,40 @@ impl EchokitChild<ClaudeCode> {
                 ClaudeCodeState::Idle | ClaudeCodeState::StopUseTool { .. },
             ) => {
                 if let Some(prompt) = log.is_user_prompt() {
-                    self.terminal_type.state = ClaudeCodeState::Working { prompt };
+                    self.state = ClaudeCodeState::Working { prompt };
                     state_updated = true;
                     return state_updated;
                 }
 
                 if log.is_stop() {
-                    state_updated = if self.terminal_type.state == ClaudeCodeState::Idle {
-                        false
-                    } else {
-                        true
-                    };
+                    state_updated = self.state != ClaudeCodeState::Idle;
 
-                    self.terminal_type.state = ClaudeCodeState::Idle;
+                    self.state = ClaudeCodeState::Idle;
                 } else if let Some((id, name, input)) = log.is_tool_request() {
-                    self.terminal_type.state = ClaudeCodeState::PreUseTool {
-                        request: vec![UseTool {
-                            id,
-                            name,
-                            input,
-                            done: false,
-                        }],
-                        is_pending: false,
-                        start_time: std::time::Instant::now(),
-                    };
+                    self.state = tool_request_state(id, name, input);
                     state_updated = true;
                 } else if let Some((output, is_thinking)) = log.is_output() {
-                    self.terminal_type.state = ClaudeCodeState::Output {
+                    self.state = ClaudeCodeState::Output {
                         output,
                         is_thinking,
                     };
                     state_updated = true;
                 }
             }
+            (ClaudeCodeResult::ClaudeLog(log), ClaudeCodeState::PlanProposed { tool_id, .. }) => {
+                let (id, is_error) = log.is_tool_result();
+                if !id.is_empty() && id == *tool_id {
+                    // a rejected plan comes back as an error result
+                    self.state = ClaudeCodeState::StopUseTool { is_error };
+                    state_updated = true;
+                } else if let Some(prompt) = log.is_user_prompt() {
+                    self.state = ClaudeCodeState::Working { prompt };
+                    state_updated = true;
+                }
+            }
+            (ClaudeCodeResult::ClaudeLog(log), ClaudeCodeState::Compacting) => {
+                log::debug!("Log while compacting, no state change: {:?}", log);
+            }
             (ClaudeCodeResult::WaitForUserInputBeforeTool, state) => {
                 log::debug!(
                     "Received WaitForUserInputBeforeTool in state {:?}, no state change",
@@ -598,15 +1339,168 @@ impl EchokitChild<ClaudeCode> {
 
         state_updated
     }
+}
+
+impl EchokitChild<ClaudeCode> {
+    pub fn session_id(&self) -> uuid::Uuid {
+        self.uuid
+    }
+
+    pub fn log_file_path(&self) -> &std::path::PathBuf {
+        &self.terminal_type.history_file_path
+    }
+
+    pub fn state(&self) -> &ClaudeCodeState {
+        self.terminal_type.tracker.state()
+    }
+
+    pub fn todos(&self) -> &TodoProgress {
+        self.terminal_type.tracker.todos()
+    }
+
+    /// How long the PTY may stay quiet before Claude Code is considered idle or
+    /// waiting on a permission prompt, [`DEFAULT_POLL_INTERVAL`] by default
+    pub fn set_poll_interval(&mut self, interval: std::time::Duration) {
+        self.terminal_type.poll_interval = interval;
+    }
+
+    pub fn permission_mode(&self) -> PermissionMode {
+        self.terminal_type.tracker.permission_mode()
+    }
+
+    /// Track what PTY output shows besides the history file: the permission
+    /// mode and compactions. Returns whether the state changed.
+    pub fn update_from_screen(&mut self, output: &str) -> bool {
+        self.terminal_type.tracker.update_from_screen(output)
+    }
+
+    /// A `/compact` was submitted, returns whether the state changed
+    pub fn start_compacting(&mut self) -> bool {
+        self.terminal_type.tracker.start_compacting()
+    }
+
+    /// The PTY stayed quiet for the poll interval, returns whether the state
+    /// changed
+    pub fn update_on_quiet(&mut self) -> bool {
+        self.terminal_type.tracker.update_on_quiet()
+    }
+
+    /// Follow the new history file Claude Code starts after `/clear`
+    pub fn expect_new_history(&mut self) {
+        self.terminal_type.clear_requested = Some(std::time::SystemTime::now());
+        self.terminal_type.history_scanned = None;
+    }
+
+    /// The history file `/clear` started, looked for at most once per poll
+    /// interval and off the runtime. Gives up after [`CLEAR_TIMEOUT`].
+    async fn poll_new_history_file(&mut self) -> Option<std::path::PathBuf> {
+        let since = self.terminal_type.clear_requested?;
+        if since.elapsed().unwrap_or_default() > CLEAR_TIMEOUT {
+            log::warn!("No new history file since /clear, keeping the current one");
+            self.terminal_type.clear_requested = None;
+            return None;
+        }
+        if self
+            .terminal_type
+            .history_scanned
+            .is_some_and(|scanned| scanned.elapsed() < self.terminal_type.poll_interval)
+        {
+            return None;
+        }
+        self.terminal_type.history_scanned = Some(tokio::time::Instant::now());
+
+        let current = self.terminal_type.history_file_path.clone();
+        tokio::task::spawn_blocking(move || find_new_history_file(&current, since))
+            .await
+            .ok()
+            .flatten()
+    }
+
+    async fn reattach_history(&mut self, path: std::path::PathBuf) -> std::io::Result<()> {
+        let mut history_file = linemux::MuxedLines::new()?;
+        history_file.add_file(&path).await?;
+        log::info!(
+            "History moved from {} to {}",
+            self.terminal_type.history_file_path.display(),
+            path.display()
+        );
+
+        // a replay follows the recording to the new file
+        self.record_marker(&format!("{}{}", HISTORY_MARKER, path.display()));
+        self.terminal_type.history_file = history_file;
+        self.terminal_type.history_file_path = path;
+        self.terminal_type.clear_requested = None;
+        self.terminal_type.history_scanned = None;
+        Ok(())
+    }
+
+    /// Press Shift+Tab and read PTY output until the footer shows another
+    /// permission mode or `timeout` expires. Returns the mode afterwards and
+    /// the output read meanwhile, which the caller still has to forward.
+    pub async fn cycle_permission_mode(
+        &mut self,
+        timeout: std::time::Duration,
+    ) -> std::io::Result<(PermissionMode, OutputChunk)> {
+        self.send_key(&KeyInput::new(Key::Tab, &[Modifier::Shift]))
+            .await?;
+
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut output = OutputChunk::default();
+
+        while self.read_chunk_before(deadline, &mut output).await? {
+            if self
+                .terminal_type
+                .tracker
+                .update_permission_mode(&output.text)
+            {
+                break;
+            }
+        }
+
+        Ok((self.terminal_type.tracker.permission_mode, output))
+    }
+
+    /// Tokens used so far by the session and its subagents
+    pub fn usage(&self) -> ClaudeCodeUsage {
+        self.terminal_type.tracker.usage()
+    }
+
+    /// Drain the events collected since the last call
+    pub fn take_events(&mut self) -> Vec<ClaudeCodeEvent> {
+        self.terminal_type.tracker.take_events()
+    }
+
+    pub fn update_state(&mut self, result: &ClaudeCodeResult) -> bool {
+        self.terminal_type.tracker.update_state(result)
+    }
+
+    /// Files touched by this session, with diffs against their state before
+    /// the session first edited them. The returned closure reads the files and
+    /// their backups, run it off the async runtime with `spawn_blocking`.
+    pub fn changes(&self, with_content: bool) -> impl FnOnce() -> Vec<FileChange> + Send + 'static {
+        let backup_dir = std::env::home_dir()
+            .unwrap_or_default()
+            .join(".claude")
+            .join("file-history")
+            .join(self.uuid.to_string());
+        let ledger = self.terminal_type.tracker.changes.clone();
+
+        move || ledger.changes(&backup_dir, with_content)
+    }
 
     pub async fn read_pty_output_and_history_line(&mut self) -> std::io::Result<ClaudeCodeResult> {
         if let Some(pty_output) = self.terminal_type.start_output_buffer.pop_front() {
-            log::debug!("Returning buffered PTY output: {}", pty_output);
+            log::debug!("Returning buffered PTY output: {}", pty_output.text);
+            self.record(&pty_output.text, false);
             return Ok(ClaudeCodeResult::PtyOutput(pty_output));
         }
 
-        let mut buffer = [0u8; 1024];
-        let mut string_buffer = Vec::with_capacity(512);
+        if let Some(path) = self.poll_new_history_file().await {
+            self.reattach_history(path.clone()).await?;
+            return Ok(ClaudeCodeResult::HistoryMoved(path));
+        }
+
+        let mut buffer = vec![0u8; super::READ_BUFFER_SIZE];
 
         #[derive(Debug)]
         enum SelectResult {
@@ -614,43 +1508,29 @@ impl EchokitChild<ClaudeCode> {
             Pty(usize),
         }
 
-        let state = &mut self.terminal_type.state;
+        let poll_interval = self.terminal_type.poll_interval;
+        let state = &self.terminal_type.tracker.state;
 
         let read_buff = async {
-            match state {
-                ClaudeCodeState::PreUseTool {
-                    start_time,
-                    is_pending,
-                    ..
-                } => {
-                    if !*is_pending && start_time.elapsed() > std::time::Duration::from_secs(5) {
-                        log::debug!(
-                            "PreUseTool state, waiting for user input before tool, setting read timeout to 5 seconds"
-                        );
-                        return Err(ClaudeCodeResult::WaitForUserInputBeforeTool);
-                    }
+            if let ClaudeCodeState::PreUseTool {
+                start_time,
+                is_pending: false,
+                ..
+            } = state
+                && start_time.elapsed() > poll_interval
+            {
+                log::debug!(
+                    "PreUseTool state, waiting for user input before tool, setting read timeout to {:?}",
+                    poll_interval
+                );
+                return Err(ClaudeCodeResult::WaitForUserInputBeforeTool);
+            }
 
-                    tokio::time::timeout(
-                        std::time::Duration::from_secs(5),
-                        self.pty.read(&mut buffer),
-                    )
-                    .await
-                    .or_else(|_| Err(ClaudeCodeResult::WaitForUserInputBeforeTool))
-                }
-                ClaudeCodeState::Idle
-                | ClaudeCodeState::Output {
-                    is_thinking: false, ..
-                }
-                | ClaudeCodeState::StopUseTool { is_error: true } => {
-                    // log::debug!("Idle state, setting read timeout to 5 seconds");
-                    tokio::time::timeout(
-                        std::time::Duration::from_secs(5),
-                        self.pty.read(&mut buffer),
-                    )
+            match state.quiet_result() {
+                Some(quiet) => tokio::time::timeout(poll_interval, self.pty.read(&mut buffer))
                     .await
-                }
-                .or_else(|_| Err(ClaudeCodeResult::WaitForUserInput)),
-                _ => Ok(self.pty.read(&mut buffer).await),
+                    .map_err(|_| quiet),
+                None => Ok(self.pty.read(&mut buffer).await),
             }
         };
 
@@ -670,46 +1550,216 @@ impl EchokitChild<ClaudeCode> {
 
         match r {
             SelectResult::Line(line_opt) => {
-                return if let Some(line) = line_opt {
+                if let Some(line) = line_opt {
                     let cc_log = serde_json::from_str::<ClaudeCodeLog>(line.line());
 
                     if let Ok(r) = cc_log {
-                        Ok(ClaudeCodeResult::ClaudeLog(r))
+                        Ok(ClaudeCodeResult::ClaudeLog(Box::new(r)))
                     } else {
                         Ok(ClaudeCodeResult::Uncaught(line.line().to_string()))
                     }
                 } else {
                     Ok(ClaudeCodeResult::Uncaught(String::new()))
-                };
+                }
             }
             SelectResult::Pty(n) => {
                 if n == 0 {
-                    return Ok(ClaudeCodeResult::PtyOutput(String::new()));
+                    return Ok(ClaudeCodeResult::PtyOutput(OutputChunk {
+                        bytes: Vec::new(),
+                        text: self.finish_output(),
+                    }));
                 }
 
-                string_buffer.extend_from_slice(&buffer[..n]);
+                let mut output = OutputChunk {
+                    bytes: buffer[..n].to_vec(),
+                    text: self.decode_output(&buffer[..n]),
+                };
+                // the read ended inside the first character, its rest is on the way
+                while output.text.is_empty() {
+                    let n = self.pty.read(&mut buffer).await?;
+                    log::debug!("Read {} bytes from PTY", n);
+                    if n == 0 {
+                        output.text = self.finish_output();
+                        break;
+                    }
+                    output.bytes.extend_from_slice(&buffer[..n]);
+                    output.text = self.decode_output(&buffer[..n]);
+                }
+                Ok(ClaudeCodeResult::PtyOutput(output))
             }
         }
+    }
+}
 
-        loop {
-            let s = str::from_utf8(&string_buffer);
-            if let Ok(s) = s {
-                return Ok(ClaudeCodeResult::PtyOutput(s.to_string()));
-            }
+#[test]
+fn test_permission_mode_from_screen() {
+    let footer = "\x1b[2m  \x1b[38;5;48m⏸ plan mode on\x1b[39m (shift+tab to cycle)\x1b[22m";
+    assert_eq!(
+        PermissionMode::from_screen(footer),
+        Some(PermissionMode::Plan)
+    );
+    assert_eq!(
+        PermissionMode::from_screen("  ⏵⏵ accept edits on (shift+tab to cycle)"),
+        Some(PermissionMode::AcceptEdits)
+    );
+    assert_eq!(
+        PermissionMode::from_screen("\x1b[2m  ? for shortcuts\x1b[22m"),
+        Some(PermissionMode::Default)
+    );
+    assert_eq!(PermissionMode::from_screen("\x1b[1A\x1b[2K"), None);
+}
 
-            let n = self.pty.read(&mut buffer).await?;
-            log::debug!("Read {} bytes from PTY", n);
-            if n == 0 {
-                break;
-            }
+#[test]
+fn test_compacting_spinner() {
+    let mut tracker = ClaudeCodeTracker::default();
+    assert!(!tracker.update_from_screen("> why is it compacting conversation history?"));
+    assert!(!tracker.update_from_screen("Compacting conversation is what /compact does"));
+    assert_eq!(tracker.state, ClaudeCodeState::Idle);
+
+    assert!(tracker.update_from_screen(
+        "\x1b[38;5;174m✻\x1b[39m \x1b[1mCompacting conversation…\x1b[22m (esc to interrupt)"
+    ));
+    assert_eq!(tracker.state, ClaudeCodeState::Compacting);
+    assert!(matches!(
+        tracker.state.quiet_result(),
+        Some(ClaudeCodeResult::WaitForUserInput)
+    ));
+
+    // no summary logged, the quiet screen ends it
+    assert!(tracker.update_on_quiet());
+    assert_eq!(tracker.state, ClaudeCodeState::Idle);
+    assert!(!tracker.update_on_quiet());
+
+    // a submitted /compact
+    tracker.state = ClaudeCodeState::Working {
+        prompt: "fix it".to_string(),
+    };
+    assert!(tracker.start_compacting());
+    assert!(!tracker.start_compacting());
+    assert!(tracker.update_from_screen("Error during compaction: Conversation too short"));
+    assert!(matches!(tracker.state, ClaudeCodeState::Working { .. }));
+}
+
+#[test]
+fn test_find_new_history_file() {
+    let dir = std::env::temp_dir().join(format!("echokit_history_{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let current = dir.join("a9f1c2e4-0000-4000-8000-000000000001.jsonl");
+    std::fs::write(&current, "").unwrap();
+    let since = std::time::SystemTime::now();
+    assert_eq!(find_new_history_file(&current, since), None);
+    // file times come from a coarser clock
+    std::thread::sleep(std::time::Duration::from_millis(50));
+
+    // a resumed conversation of another session continues from a parent
+    let other = dir.join("a9f1c2e4-0000-4000-8000-000000000002.jsonl");
+    std::fs::write(
+        &other,
+        r#"{"sessionId":"a9f1c2e4-0000-4000-8000-000000000002","parentUuid":"x"}"#,
+    )
+    .unwrap();
+    let cleared = dir.join("a9f1c2e4-0000-4000-8000-000000000003.jsonl");
+    std::fs::write(
+        &cleared,
+        concat!(
+            r#"{"type":"file-history-snapshot"}"#,
+            "\n",
+            r#"{"sessionId":"a9f1c2e4-0000-4000-8000-000000000003","parentUuid":null}"#
+        ),
+    )
+    .unwrap();
+    assert_eq!(find_new_history_file(&current, since), Some(cleared));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_permission_mode_from_args() {
+    assert_eq!(
+        PermissionMode::from_args(&["--session-id", "x"]),
+        PermissionMode::Default
+    );
+    assert_eq!(
+        PermissionMode::from_args(&["--permission-mode", "plan", "--model", "sonnet"]),
+        PermissionMode::Plan
+    );
+    assert_eq!(
+        PermissionMode::from_args(&["--permission-mode=acceptEdits"]),
+        PermissionMode::AcceptEdits
+    );
+    assert_eq!(
+        PermissionMode::from_args(&["--dangerously-skip-permissions"]),
+        PermissionMode::BypassPermissions
+    );
+}
 
-            string_buffer.extend_from_slice(&buffer[..n]);
+#[test]
+fn test_plan_proposed_state() {
+    let state = tool_request_state(
+        "call_1".to_string(),
+        "ExitPlanMode".to_string(),
+        serde_json::json!({ "plan": "1. Add a test\n2. Fix the bug" }),
+    );
+    assert_eq!(
+        state,
+        ClaudeCodeState::PlanProposed {
+            tool_id: "call_1".to_string(),
+            plan: "1. Add a test\n2. Fix the bug".to_string(),
         }
+    );
+    assert!(state.confirm_available() && state.cancel_available());
+    assert!(!state.input_available() && !state.mode_switch_available());
 
-        Ok(ClaudeCodeResult::PtyOutput(
-            String::from_utf8_lossy(&string_buffer).to_string(),
-        ))
-    }
+    let state = tool_request_state(
+        "call_2".to_string(),
+        "Bash".to_string(),
+        serde_json::json!({ "command": "ls" }),
+    );
+    assert!(state.is_use_tool());
+}
+
+#[test]
+fn test_plan_proposed_after_tool_use() {
+    let mut tracker = ClaudeCodeTracker {
+        state: tool_request_state(
+            "call_1".to_string(),
+            "Read".to_string(),
+            serde_json::json!({ "file_path": "README.md" }),
+        ),
+        ..Default::default()
+    };
+
+    let log: ClaudeCodeLog = serde_json::from_value(serde_json::json!({
+        "parentUuid": "9f644493-751a-4d23-92ef-b469cad6dec7",
+        "sessionId": "d284f444-9e56-4318-a472-bc18481b7793",
+        "message": {
+            "id": "msg_1",
+            "type": "message",
+            "role": "assistant",
+            "model": "glm-4.7",
+            "content": [{
+                "type": "tool_use",
+                "id": "call_2",
+                "name": "ExitPlanMode",
+                "input": { "plan": "1. Fix the bug" }
+            }],
+            "stop_reason": null,
+            "stop_sequence": null
+        },
+        "type": "assistant",
+        "uuid": "c3d2e1f0-1a2b-4c3d-8e9f-0a1b2c3d4e5f",
+        "timestamp": "2026-02-03T18:11:48.539Z"
+    }))
+    .unwrap();
+
+    assert!(tracker.update_main_state(&ClaudeCodeResult::ClaudeLog(Box::new(log))));
+    assert_eq!(
+        tracker.state,
+        ClaudeCodeState::PlanProposed {
+            tool_id: "call_2".to_string(),
+            plan: "1. Fix the bug".to_string(),
+        }
+    );
 }
 
 #[tokio::test]
@@ -0,0 +1,211 @@
+use std::collections::BTreeMap;
+
+/// Whether a slash command takes arguments
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandArgs {
+    None,
+    Optional,
+    /// Without arguments the command opens an interactive picker
+    Required,
+}
+
+/// What the PTY shows after a slash command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandOutput {
+    /// Nothing worth reading, or the command keeps running (e.g. `/compact`)
+    None,
+    Text,
+    Status,
+    Cost,
+}
+
+/// A Claude Code slash command that can be run through the API
+#[derive(Debug)]
+pub struct SlashCommand {
+    pub name: &'static str,
+    pub args: CommandArgs,
+    pub output: CommandOutput,
+    /// The output is a dialog closed with Esc
+    pub dialog: bool,
+}
+
+const fn command(name: &'static str, args: CommandArgs, output: CommandOutput) -> SlashCommand {
+    SlashCommand {
+        name,
+        args,
+        output,
+        dialog: false,
+    }
+}
+
+pub const SLASH_COMMANDS: &[SlashCommand] = &[
+    command("clear", CommandArgs::None, CommandOutput::None),
+    command("compact", CommandArgs::Optional, CommandOutput::None),
+    command("context", CommandArgs::None, CommandOutput::Text),
+    command("cost", CommandArgs::None, CommandOutput::Cost),
+    command("init", CommandArgs::None, CommandOutput::None),
+    command("model", CommandArgs::Required, CommandOutput::Text),
+    command("review", CommandArgs::Optional, CommandOutput::None),
+    SlashCommand {
+        dialog: true,
+        ..command("status", CommandArgs::None, CommandOutput::Status)
+    },
+];
+
+/// Look up a command by name, with or without the leading `/`
+pub fn find(name: &str) -> Option<&'static SlashCommand> {
+    let name = name.trim().trim_start_matches('/');
+    SLASH_COMMANDS.iter().find(|command| command.name == name)
+}
+
+impl SlashCommand {
+    /// The text typed into the prompt, `args` checked against the command
+    pub fn input(&self, args: &str) -> Result<String, String> {
+        let args = args.trim();
+        match (self.args, args.is_empty()) {
+            (CommandArgs::None, false) => Err(format!("/{} takes no arguments", self.name)),
+            (CommandArgs::Required, true) => Err(format!("/{} needs arguments", self.name)),
+            (_, true) => Ok(format!("/{}", self.name)),
+            (_, false) => Ok(format!("/{} {}", self.name, args)),
+        }
+    }
+
+    pub fn parse(&self, output: &str) -> CommandResult {
+        match self.output {
+            CommandOutput::None => CommandResult::Submitted,
+            CommandOutput::Text => CommandResult::Text {
+                output: strip_ansi_escapes::strip_str(output),
+            },
+            CommandOutput::Status => CommandResult::Status(parse_status(output)),
+            CommandOutput::Cost => CommandResult::Cost(parse_cost(output)),
+        }
+    }
+}
+
+/// Result of a slash command, as far as it could be read from the PTY
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum CommandResult {
+    /// The command was sent, its output is not read
+    Submitted,
+    Text {
+        output: String,
+    },
+    Status(StatusReport),
+    Cost(CostReport),
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct StatusReport {
+    pub session_id: Option<String>,
+    pub cwd: Option<String>,
+    pub model: Option<String>,
+    /// Every `Key: value` line of the dialog
+    pub fields: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct CostReport {
+    pub total_cost_usd: Option<f64>,
+    pub fields: BTreeMap<String, String>,
+}
+
+/// `Key: value` lines of a command output, ignoring ANSI escapes and the
+/// borders and tree markers around them
+pub fn key_values(output: &str) -> BTreeMap<String, String> {
+    let output = strip_ansi_escapes::strip_str(output);
+    let mut fields = BTreeMap::new();
+
+    for line in output.lines() {
+        let line = line.trim_matches(|c: char| c.is_whitespace() || "│⎿•·>❯".contains(c));
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let (key, value) = (key.trim(), value.trim());
+        if key.is_empty() || value.is_empty() || key.len() > 40 || key.contains("  ") {
+            continue;
+        }
+        fields.insert(key.to_string(), value.to_string());
+    }
+
+    fields
+}
+
+/// The `/status` dialog, Claude Code versions differ in the spelling of
+/// `Session ID`
+pub fn parse_status(output: &str) -> StatusReport {
+    let fields = key_values(output);
+    let field = |keys: &[&str]| keys.iter().find_map(|key| fields.get(*key).cloned());
+
+    StatusReport {
+        session_id: field(&["Session ID", "SessionID"]),
+        cwd: field(&["cwd"]),
+        model: field(&["Model"]),
+        fields,
+    }
+}
+
+/// The `/cost` summary, e.g. `Total cost: $0.0123`
+pub fn parse_cost(output: &str) -> CostReport {
+    let fields = key_values(output);
+    let total_cost_usd = fields
+        .get("Total cost")
+        .and_then(|cost| cost.trim_start_matches('$').parse::<f64>().ok());
+
+    CostReport {
+        total_cost_usd,
+        fields,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_command_input() {
+        let cost = find("/cost").unwrap();
+        assert_eq!(cost.input("").unwrap(), "/cost");
+        assert!(cost.input("now").is_err());
+
+        let model = find("model").unwrap();
+        assert!(model.input(" ").is_err());
+        assert_eq!(model.input("sonnet").unwrap(), "/model sonnet");
+
+        assert_eq!(
+            find("compact")
+                .unwrap()
+                .input("keep the todo list")
+                .unwrap(),
+            "/compact keep the todo list"
+        );
+        assert!(find("exit").is_none());
+    }
+
+    #[test]
+    fn test_parse_status() {
+        let output = "\x1b[1m Status \x1b[22m\r\n\r\n │ Version: 2.0.76\r\n │ Session ID: d81a49c3-8461-4fe1-b315-a26aeedb9dd4\r\n │ cwd: /home/echokit/work\r\n\r\n │ Model: Default (claude-sonnet-4-5)\r\n";
+        let status = parse_status(output);
+        assert_eq!(
+            status.session_id.as_deref(),
+            Some("d81a49c3-8461-4fe1-b315-a26aeedb9dd4")
+        );
+        assert_eq!(status.cwd.as_deref(), Some("/home/echokit/work"));
+        assert_eq!(status.model.as_deref(), Some("Default (claude-sonnet-4-5)"));
+        assert_eq!(
+            status.fields.get("Version").map(String::as_str),
+            Some("2.0.76")
+        );
+    }
+
+    #[test]
+    fn test_parse_cost() {
+        let output = "> /cost\r\n  ⎿  Total cost:            $0.0123\r\n     Total duration (API):  1m 2.3s\r\n     Total code changes:    12 lines added, 3 lines removed\r\n";
+        let cost = parse_cost(output);
+        assert_eq!(cost.total_cost_usd, Some(0.0123));
+        assert_eq!(
+            cost.fields.get("Total duration (API)").map(String::as_str),
+            Some("1m 2.3s")
+        );
+    }
+}
@@ -0,0 +1,345 @@
+use std::{future::Future, process::ExitStatus, time::Duration};
+
+use tokio::sync::{mpsc, oneshot, watch};
+
+use super::{
+    EchokitChild, InputItem, Normal, OutputChunk, TerminalType,
+    claude::{ClaudeCode, ClaudeCodeResult},
+    output::OutputBroadcast,
+    shell::{ExecResult, ShellStatus, ShellType},
+};
+
+/// What clients ask of the event loop [`run`]
+#[derive(Debug)]
+pub enum TerminalRequest {
+    Input(Vec<InputItem>),
+    /// Run a command to completion, answered on `reply`
+    Exec {
+        command: String,
+        timeout: Duration,
+        reply: oneshot::Sender<std::io::Result<ExecResult>>,
+    },
+    /// Kill the process, ending the loop
+    Close,
+}
+
+impl From<Vec<InputItem>> for TerminalRequest {
+    fn from(input: Vec<InputItem>) -> Self {
+        TerminalRequest::Input(input)
+    }
+}
+
+/// A terminal the generic event loop [`run`] can drive. Implementing it is all
+/// a new backend needs to accept every [`InputItem`], input and exit go
+/// through the child process unless a backend overrides them.
+pub trait TerminalDriver: Send {
+    type Terminal: TerminalType + Send;
+
+    /// The child process running in the terminal
+    fn child(&mut self) -> &mut EchokitChild<Self::Terminal>;
+
+    /// The next PTY output for the clients, an empty chunk at EOF. Backends
+    /// watching more than the PTY handle the rest here.
+    fn read_output(&mut self) -> impl Future<Output = std::io::Result<OutputChunk>> + Send;
+
+    /// Apply one input item, returning the PTY output read meanwhile
+    fn apply_input(
+        &mut self,
+        item: InputItem,
+    ) -> impl Future<Output = std::io::Result<OutputChunk>> + Send {
+        self.child().apply_input(item)
+    }
+
+    /// Wait for the process to exit
+    fn wait(&mut self) -> impl Future<Output = std::io::Result<ExitStatus>> + Send {
+        self.child().wait()
+    }
+
+    fn kill(&mut self) -> impl Future<Output = std::io::Result<()>> + Send {
+        self.child().kill()
+    }
+
+    /// Run a command to completion, passing the PTY output read meanwhile to
+    /// `on_output`. Only shells support it.
+    fn run_command(
+        &mut self,
+        command: &str,
+        timeout: Duration,
+        on_output: &mut (dyn FnMut(OutputChunk) + Send),
+    ) -> impl Future<Output = std::io::Result<ExecResult>> + Send {
+        let _ = (command, timeout, on_output);
+        std::future::ready(Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "Running commands needs a shell",
+        )))
+    }
+
+    /// The state of the shell, for terminals running one
+    fn shell_status(&self) -> Option<ShellStatus> {
+        None
+    }
+}
+
+impl TerminalDriver for EchokitChild<Normal> {
+    type Terminal = Normal;
+
+    fn child(&mut self) -> &mut EchokitChild<Normal> {
+        self
+    }
+
+    fn read_output(&mut self) -> impl Future<Output = std::io::Result<OutputChunk>> + Send {
+        self.read_chunk()
+    }
+}
+
+impl<T: ShellType + Send> TerminalDriver for EchokitChild<T> {
+    type Terminal = T;
+
+    fn child(&mut self) -> &mut EchokitChild<T> {
+        self
+    }
+
+    async fn read_output(&mut self) -> std::io::Result<OutputChunk> {
+        let output = self.read_chunk().await?;
+        self.update_from_output(&output.text);
+        Ok(output)
+    }
+
+    fn run_command(
+        &mut self,
+        command: &str,
+        timeout: Duration,
+        on_output: &mut (dyn FnMut(OutputChunk) + Send),
+    ) -> impl Future<Output = std::io::Result<ExecResult>> + Send {
+        EchokitChild::run_command(self, command, timeout, on_output)
+    }
+
+    fn shell_status(&self) -> Option<ShellStatus> {
+        Some(EchokitChild::shell_status(self).clone())
+    }
+}
+
+impl TerminalDriver for EchokitChild<ClaudeCode> {
+    type Terminal = ClaudeCode;
+
+    fn child(&mut self) -> &mut EchokitChild<ClaudeCode> {
+        self
+    }
+
+    /// History lines only update the state, they are not sent to clients
+    async fn read_output(&mut self) -> std::io::Result<OutputChunk> {
+        loop {
+            let result = self.read_pty_output_and_history_line().await?;
+            match &result {
+                ClaudeCodeResult::PtyOutput(output) => {
+                    self.update_from_screen(&output.text);
+                    return Ok(output.clone());
+                }
+                ClaudeCodeResult::ClaudeLog(log) => {
+                    log::info!("{:?} >>: {:?}", self.state(), log);
+                }
+                ClaudeCodeResult::Uncaught(s) => log::debug!("ClaudeCode uncaught: {}", s),
+                ClaudeCodeResult::WaitForUserInput => {
+                    log::info!("ClaudeCode is waiting for user input");
+                }
+                ClaudeCodeResult::WaitForUserInputBeforeTool => {
+                    log::info!("ClaudeCode is waiting for user input before tool");
+                }
+                ClaudeCodeResult::HistoryMoved(path) => {
+                    log::info!("ClaudeCode history moved to {}", path.display());
+                }
+            }
+            self.update_state(&result);
+            self.take_events();
+        }
+    }
+}
+
+enum TerminalEvent {
+    PtyOutput(OutputChunk),
+    Request(TerminalRequest),
+    PtyEof,
+    InputClosed,
+    Error(std::io::Error),
+    /// The coalesced PTY output is due
+    Flush,
+}
+
+/// Forward the output of `terminal` to `output_tx` and apply the input
+/// batches and commands from `input_rx` until the terminal or the input
+/// channel closes, publishing changes of the shell state to `state_tx`.
+/// Returns how the process exited with the pending output sent, the channels
+/// can serve a new terminal.
+pub async fn run<D: TerminalDriver>(
+    mut terminal: D,
+    input_rx: &mut mpsc::UnboundedReceiver<TerminalRequest>,
+    output_tx: &OutputBroadcast,
+    state_tx: &watch::Sender<Option<ShellStatus>>,
+) -> std::io::Result<ExitStatus> {
+    log::info!("Start terminal event loop");
+    loop {
+        let status = terminal.shell_status();
+        state_tx.send_if_modified(|current| {
+            if *current == status {
+                return false;
+            }
+            log::info!("Shell state changed: {:?}", status);
+            *current = status;
+            true
+        });
+
+        let flush_deadline = output_tx.flush_deadline();
+        let event = tokio::select! {
+            result = terminal.read_output() => match result {
+                Ok(output) if output.is_eof() => TerminalEvent::PtyEof,
+                Ok(output) => TerminalEvent::PtyOutput(output),
+                Err(e) => TerminalEvent::Error(e),
+            },
+            msg = input_rx.recv() => match msg {
+                Some(request) => TerminalEvent::Request(request),
+                None => TerminalEvent::InputClosed,
+            },
+            _ = tokio::time::sleep_until(flush_deadline.unwrap_or_else(tokio::time::Instant::now)),
+                if flush_deadline.is_some() => TerminalEvent::Flush,
+        };
+
+        match event {
+            TerminalEvent::PtyOutput(output) => {
+                log::info!(
+                    "pty output: {:?}",
+                    strip_ansi_escapes::strip_str(&output.text)
+                );
+                if !output_tx.send(output) {
+                    log::warn!("no active PTY subscribers");
+                }
+            }
+            TerminalEvent::Request(TerminalRequest::Input(input)) => {
+                log::info!("Sending input to terminal: {:?}", input);
+                for item in input {
+                    match terminal.apply_input(item).await {
+                        Ok(output) if !output.is_empty() => {
+                            let _ = output_tx.send(output);
+                        }
+                        Ok(_) => {}
+                        Err(e) => log::error!("Failed to send input to terminal: {:?}", e),
+                    }
+                }
+            }
+            TerminalEvent::Request(TerminalRequest::Exec {
+                command,
+                timeout,
+                reply,
+            }) => {
+                log::info!("Running command in terminal: {:?}", command);
+                let mut forward = |output: OutputChunk| {
+                    let _ = output_tx.send(output);
+                };
+                let result = terminal.run_command(&command, timeout, &mut forward).await;
+                let _ = reply.send(result);
+            }
+            TerminalEvent::Request(TerminalRequest::Close) => {
+                log::info!("Closing terminal");
+                if let Err(e) = terminal.kill().await {
+                    log::error!("Failed to kill terminal process: {:?}", e);
+                }
+                break;
+            }
+            TerminalEvent::Flush => output_tx.flush(),
+            TerminalEvent::PtyEof => {
+                log::info!("PTY EOF received");
+                break;
+            }
+            TerminalEvent::InputClosed => break,
+            TerminalEvent::Error(e) => {
+                log::error!("Failed to read terminal output: {:?}", e);
+                break;
+            }
+        }
+    }
+
+    output_tx.flush();
+    let status = terminal.wait().await;
+    log::info!("Terminal process exited with status: {:?}", status);
+    status
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_run_applies_input() {
+        let terminal = crate::terminal::new("cat", &[] as &[&str], (24, 80)).unwrap();
+        let (input_tx, mut input_rx) = mpsc::unbounded_channel();
+        let output_tx =
+            OutputBroadcast::new(64, 1024, std::time::Duration::from_millis(10), (24, 80));
+        let mut output_rx = output_tx.subscribe();
+        let (state_tx, state_rx) = watch::channel(None);
+        let handle =
+            tokio::spawn(async move { run(terminal, &mut input_rx, &output_tx, &state_tx).await });
+
+        input_tx
+            .send(
+                vec![
+                    InputItem::Text {
+                        input: "hello".to_string(),
+                    },
+                    InputItem::Enter,
+                ]
+                .into(),
+            )
+            .unwrap();
+
+        // the echo of the typed line, then cat writing it back
+        let mut output = String::new();
+        while output.matches("hello").count() < 2 {
+            let chunk = tokio::time::timeout(std::time::Duration::from_secs(5), output_rx.recv())
+                .await
+                .expect("no output from cat")
+                .unwrap();
+            output.push_str(&chunk.text);
+        }
+
+        // cat is no shell to run commands in
+        let (reply, result) = oneshot::channel();
+        input_tx
+            .send(TerminalRequest::Exec {
+                command: "true".to_string(),
+                timeout: Duration::from_secs(1),
+                reply,
+            })
+            .unwrap();
+        let error = result.await.unwrap().unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::Unsupported);
+
+        input_tx
+            .send(vec![InputItem::KeyboardInterrupt].into())
+            .unwrap();
+        let status = tokio::time::timeout(std::time::Duration::from_secs(5), handle)
+            .await
+            .expect("the loop did not end with cat")
+            .unwrap()
+            .unwrap();
+        assert!(!status.success());
+        // cat has no shell state
+        assert_eq!(*state_rx.borrow(), None);
+    }
+
+    #[tokio::test]
+    async fn test_run_closes() {
+        let terminal = crate::terminal::new("cat", &[] as &[&str], (24, 80)).unwrap();
+        let (input_tx, mut input_rx) = mpsc::unbounded_channel();
+        let output_tx = OutputBroadcast::new(64, 1024, Duration::ZERO, (24, 80));
+        let (state_tx, _) = watch::channel(None);
+
+        input_tx.send(TerminalRequest::Close).unwrap();
+        let status = tokio::time::timeout(
+            Duration::from_secs(5),
+            run(terminal, &mut input_rx, &output_tx, &state_tx),
+        )
+        .await
+        .expect("cat was not killed")
+        .unwrap();
+        assert!(!status.success());
+    }
+}
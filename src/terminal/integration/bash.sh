@@ -0,0 +1,22 @@
+# Shell integration for bash, loaded with --rcfile in place of ~/.bashrc.
+# Marks prompts and commands with OSC 133 and reports the cwd with OSC 7.
+
+if [ -f /etc/bash.bashrc ]; then
+    . /etc/bash.bashrc
+fi
+if [ -f ~/.bashrc ]; then
+    . ~/.bashrc
+fi
+
+__echokit_prompt() {
+    local ret=$?
+    printf '\033]133;D;%s\007' "$ret"
+    printf '\033]7;file://%s%s\007' "$HOSTNAME" "$PWD"
+    printf '\033]133;A\007'
+    return $ret
+}
+
+# first, so that it sees the status of the command
+PROMPT_COMMAND="__echokit_prompt${PROMPT_COMMAND:+;$PROMPT_COMMAND}"
+PS1="$PS1\[\033]133;B\007\]"
+PS0='\033]133;C\007'
@@ -0,0 +1,16 @@
+# Shell integration for fish, sourced with --init-command after the user's
+# config. Marks prompts and commands with OSC 133 and reports the cwd with
+# OSC 7.
+
+function __echokit_preexec --on-event fish_preexec
+    printf '\e]133;C\a'
+end
+
+function __echokit_postexec --on-event fish_postexec
+    printf '\e]133;D;%s\a' $status
+end
+
+function __echokit_prompt --on-event fish_prompt
+    printf '\e]7;file://%s%s\a' $hostname $PWD
+    printf '\e]133;A\a'
+end
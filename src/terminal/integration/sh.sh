@@ -0,0 +1,12 @@
+# Shell integration for POSIX sh, loaded through ENV. sh has no hook before
+# a command runs, so only the prompt is marked, with the exit code of the
+# last command and the cwd, which PS1 expands.
+
+if [ -n "$ECHOKIT_USER_ENV" ] && [ -f "$ECHOKIT_USER_ENV" ]; then
+    . "$ECHOKIT_USER_ENV"
+fi
+unset ECHOKIT_USER_ENV
+
+__echokit_esc=$(printf '\033')
+__echokit_bel=$(printf '\007')
+PS1="$__echokit_esc]133;D;\$?$__echokit_bel$__echokit_esc]7;file://$(hostname 2>/dev/null)\${PWD}$__echokit_bel$__echokit_esc]133;A$__echokit_bel${PS1:-\$ }$__echokit_esc]133;B$__echokit_bel"
@@ -0,0 +1,237 @@
+/// A key of a `key` input, written as its name (`tab`, `page_up`, `f5`) or a
+/// single character (`c`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub enum Key {
+    Tab,
+    Enter,
+    Esc,
+    Backspace,
+    Space,
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    Insert,
+    Delete,
+    PageUp,
+    PageDown,
+    /// F1 to F12
+    F(u8),
+    Char(char),
+}
+
+impl TryFrom<String> for Key {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        let mut chars = name.chars();
+        if let (Some(c), None) = (chars.next(), chars.next()) {
+            return Ok(Key::Char(c));
+        }
+
+        let key = match name.to_ascii_lowercase().replace('-', "_").as_str() {
+            "tab" => Key::Tab,
+            "enter" | "return" => Key::Enter,
+            "esc" | "escape" => Key::Esc,
+            "backspace" => Key::Backspace,
+            "space" => Key::Space,
+            "up" => Key::Up,
+            "down" => Key::Down,
+            "left" => Key::Left,
+            "right" => Key::Right,
+            "home" => Key::Home,
+            "end" => Key::End,
+            "insert" => Key::Insert,
+            "delete" => Key::Delete,
+            "page_up" | "pageup" => Key::PageUp,
+            "page_down" | "pagedown" => Key::PageDown,
+            f => match f.strip_prefix('f').and_then(|n| n.parse::<u8>().ok()) {
+                Some(n @ 1..=12) => Key::F(n),
+                _ => return Err(format!("Unknown key {:?}", name)),
+            },
+        };
+        Ok(key)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Modifier {
+    Shift,
+    Alt,
+    #[serde(alias = "control")]
+    Ctrl,
+}
+
+/// A key press with modifiers, e.g. `{"key": "tab", "modifiers": ["shift"]}`
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+pub struct KeyInput {
+    pub key: Key,
+    #[serde(default)]
+    pub modifiers: Vec<Modifier>,
+}
+
+impl std::fmt::Display for KeyInput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for modifier in &self.modifiers {
+            write!(f, "{:?}+", modifier)?;
+        }
+        match self.key {
+            Key::F(n) => write!(f, "F{}", n),
+            Key::Char(c) => write!(f, "{}", c),
+            key => write!(f, "{:?}", key),
+        }
+    }
+}
+
+impl KeyInput {
+    pub fn new(key: Key, modifiers: &[Modifier]) -> Self {
+        Self {
+            key,
+            modifiers: modifiers.to_vec(),
+        }
+    }
+
+    fn has(&self, modifier: Modifier) -> bool {
+        self.modifiers.contains(&modifier)
+    }
+
+    /// The xterm modifier parameter, 1 without modifiers
+    fn modifier_param(&self) -> u8 {
+        1 + self.has(Modifier::Shift) as u8
+            + 2 * self.has(Modifier::Alt) as u8
+            + 4 * self.has(Modifier::Ctrl) as u8
+    }
+
+    /// The bytes xterm sends for this key press, with cursor keys in normal
+    /// mode. Fails for combinations xterm has no encoding for, like Ctrl+Tab.
+    pub fn encode(&self) -> Result<Vec<u8>, String> {
+        let m = self.modifier_param();
+        let shift = self.has(Modifier::Shift);
+        let alt = self.has(Modifier::Alt);
+        let ctrl = self.has(Modifier::Ctrl);
+        let unsupported = || Err(format!("{} has no xterm encoding", self));
+
+        // `CSI <code>` without modifiers, `CSI 1 ; <m> <code>` with them
+        let cursor = |code: char| -> Vec<u8> {
+            if m == 1 {
+                format!("\x1b[{}", code).into_bytes()
+            } else {
+                format!("\x1b[1;{}{}", m, code).into_bytes()
+            }
+        };
+        // `CSI <n> ~` without modifiers, `CSI <n> ; <m> ~` with them
+        let tilde = |n: u8| -> Vec<u8> {
+            if m == 1 {
+                format!("\x1b[{}~", n).into_bytes()
+            } else {
+                format!("\x1b[{};{}~", n, m).into_bytes()
+            }
+        };
+        // Alt prefixes keys without a modifier parameter with ESC
+        let meta = |bytes: &[u8]| -> Vec<u8> {
+            let mut out = if alt { vec![0x1b] } else { vec![] };
+            out.extend_from_slice(bytes);
+            out
+        };
+
+        let bytes = match self.key {
+            Key::Up => cursor('A'),
+            Key::Down => cursor('B'),
+            Key::Right => cursor('C'),
+            Key::Left => cursor('D'),
+            Key::Home => cursor('H'),
+            Key::End => cursor('F'),
+            Key::Insert => tilde(2),
+            Key::Delete => tilde(3),
+            Key::PageUp => tilde(5),
+            Key::PageDown => tilde(6),
+            Key::F(n @ 1..=4) => {
+                let code = (b'P' + n - 1) as char;
+                if m == 1 {
+                    format!("\x1bO{}", code).into_bytes()
+                } else {
+                    cursor(code)
+                }
+            }
+            Key::F(n) => match n {
+                5 => tilde(15),
+                6 => tilde(17),
+                7 => tilde(18),
+                8 => tilde(19),
+                9 => tilde(20),
+                10 => tilde(21),
+                11 => tilde(23),
+                12 => tilde(24),
+                _ => return unsupported(),
+            },
+
+            Key::Tab if ctrl => return unsupported(),
+            Key::Tab if shift => meta(b"\x1b[Z"),
+            Key::Tab => meta(b"\t"),
+            Key::Enter | Key::Esc if shift || ctrl => return unsupported(),
+            Key::Enter => meta(b"\r"),
+            Key::Esc => meta(b"\x1b"),
+            Key::Backspace if shift => return unsupported(),
+            Key::Backspace if ctrl => meta(b"\x08"),
+            Key::Backspace => meta(b"\x7f"),
+            Key::Space if ctrl => meta(b"\x00"),
+            Key::Space => meta(b" "),
+
+            Key::Char(c) if ctrl => match c.to_ascii_lowercase() {
+                c @ ('a'..='z' | '@' | '[' | '\\' | ']' | '^' | '_') => {
+                    meta(&[c.to_ascii_uppercase() as u8 & 0x1f])
+                }
+                '?' => meta(b"\x7f"),
+                _ => return unsupported(),
+            },
+            Key::Char(c) => {
+                let c = if shift { c.to_ascii_uppercase() } else { c };
+                meta(c.to_string().as_bytes())
+            }
+        };
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn encode(key: &str, modifiers: &[Modifier]) -> Result<Vec<u8>, String> {
+        KeyInput::new(Key::try_from(key.to_string())?, modifiers).encode()
+    }
+
+    #[test]
+    fn test_encode_keys() {
+        use Modifier::*;
+
+        assert_eq!(encode("tab", &[]).unwrap(), b"\t");
+        assert_eq!(encode("tab", &[Shift]).unwrap(), b"\x1b[Z");
+        assert!(encode("tab", &[Ctrl]).is_err());
+        assert_eq!(encode("up", &[]).unwrap(), b"\x1b[A");
+        assert_eq!(encode("home", &[Shift]).unwrap(), b"\x1b[1;2H");
+        assert_eq!(encode("End", &[Ctrl, Alt]).unwrap(), b"\x1b[1;7F");
+        assert_eq!(encode("page_up", &[]).unwrap(), b"\x1b[5~");
+        assert_eq!(encode("page-down", &[Ctrl]).unwrap(), b"\x1b[6;5~");
+        assert_eq!(encode("f1", &[]).unwrap(), b"\x1bOP");
+        assert_eq!(encode("F4", &[Shift]).unwrap(), b"\x1b[1;2S");
+        assert_eq!(encode("f5", &[]).unwrap(), b"\x1b[15~");
+        assert_eq!(encode("f12", &[Alt]).unwrap(), b"\x1b[24;3~");
+        assert!(encode("f13", &[]).is_err());
+        assert_eq!(encode("c", &[Ctrl]).unwrap(), b"\x03");
+        assert_eq!(encode("C", &[Ctrl]).unwrap(), b"\x03");
+        assert_eq!(encode("b", &[Alt]).unwrap(), b"\x1bb");
+        assert_eq!(encode("x", &[Ctrl, Alt]).unwrap(), b"\x1b\x18");
+        assert_eq!(encode("a", &[Shift]).unwrap(), b"A");
+        assert!(encode("1", &[Ctrl]).is_err());
+        assert!(encode("hyper", &[]).is_err());
+
+        let input: KeyInput =
+            serde_json::from_str(r#"{"key": "tab", "modifiers": ["shift"]}"#).unwrap();
+        assert_eq!(input, KeyInput::new(Key::Tab, &[Shift]));
+    }
+}
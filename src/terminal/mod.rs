@@ -4,8 +4,23 @@ use tokio::{
     process::Child,
 };
 
+pub mod changes;
 pub mod claude;
+pub mod commands;
+pub mod driver;
+pub mod keys;
+pub mod output;
+pub mod pacing;
+pub mod recording;
 pub mod shell;
+pub mod utf8;
+
+const PASTE_START: &str = "\x1b[200~";
+const PASTE_END: &str = "\x1b[201~";
+const PASTE_CHUNK_SIZE: usize = 1024;
+const PASTE_CHUNK_DELAY: std::time::Duration = std::time::Duration::from_millis(5);
+/// Bytes taken from the PTY per read of the output stream
+pub(crate) const READ_BUFFER_SIZE: usize = 16 * 1024;
 
 #[derive(Debug, Clone, serde::Deserialize)]
 #[serde(tag = "type")]
@@ -16,6 +31,8 @@ pub enum InputItem {
     KeyboardInterrupt,
     Enter,
     Esc,
+    /// `{"type": "Key", "key": "tab", "modifiers": ["shift"]}`
+    Key(keys::KeyInput),
     #[serde(skip)]
     Bytes(Vec<u8>),
 }
@@ -32,13 +49,57 @@ impl TerminalType for Normal {
     type Output = String;
 }
 
+/// One read of PTY output: the bytes exactly as read, and the text decoded so
+/// far. `text` lacks a trailing incomplete UTF-8 sequence, which starts the
+/// text of the next chunk.
+#[derive(Debug, Clone, Default)]
+pub struct OutputChunk {
+    pub bytes: Vec<u8>,
+    pub text: String,
+}
+
+impl OutputChunk {
+    /// An empty chunk marks EOF
+    pub fn is_eof(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Whether there is nothing to forward
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty() && self.text.is_empty()
+    }
+
+    /// Append the chunk read after this one
+    pub fn extend(&mut self, chunk: OutputChunk) {
+        self.bytes.extend(chunk.bytes);
+        self.text.push_str(&chunk.text);
+    }
+}
+
+impl From<String> for OutputChunk {
+    fn from(text: String) -> Self {
+        Self {
+            bytes: text.clone().into_bytes(),
+            text,
+        }
+    }
+}
+
 pub struct EchokitChild<T: TerminalType> {
     uuid: uuid::Uuid,
     pty: Pty,
     child: Child,
     terminal_type: T,
+    decoder: utf8::Utf8Decoder,
+    recorder: Option<recording::Recorder>,
+    /// How `apply_input` types text and presses Enter
+    pacing: pacing::InputPacing,
+    /// Text typed by `apply_input` since the last Enter, awaited as echo
+    typed: String,
 }
 
+/// Run `shell_command` with `shell_args` as they are, shells started with
+/// their integration have their own types in [`shell`]
 pub fn new<S: AsRef<std::ffi::OsStr>>(
     shell_command: &str,
     shell_args: &[S],
@@ -52,18 +113,8 @@ pub fn new<S: AsRef<std::ffi::OsStr>>(
 
     let uuid = uuid::Uuid::new_v4();
     let mut cmd = PtyCommand::new(shell_command);
-    if shell_args.is_empty() {
-        match shell_command {
-            "bash" | "zsh" | "fish" => {
-                cmd = cmd.arg("-i");
-            }
-            _ => {}
-        }
-    } else {
-        let mut iter = shell_args.iter();
-        while let Some(arg) = iter.next() {
-            cmd = cmd.arg(arg);
-        }
+    for arg in shell_args {
+        cmd = cmd.arg(arg);
     }
 
     cmd = cmd
@@ -81,11 +132,72 @@ pub fn new<S: AsRef<std::ffi::OsStr>>(
         pty,
         child,
         terminal_type: Normal,
+        decoder: utf8::Utf8Decoder::new(),
+        recorder: None,
+        pacing: pacing::InputPacing::default(),
+        typed: String::new(),
     })
 }
 
 impl<T: TerminalType> EchokitChild<T> {
+    /// Record the terminal from now on as an asciicast file, replacing a
+    /// recording already running
+    pub fn start_recording(
+        &mut self,
+        options: recording::RecordingOptions,
+        size: (u16, u16),
+    ) -> std::io::Result<()> {
+        self.recorder = Some(recording::Recorder::new(options, size)?);
+        Ok(())
+    }
+
+    pub fn stop_recording(&mut self) {
+        self.recorder = None;
+    }
+
+    /// A failing recording is stopped, the terminal keeps running
+    fn record(&mut self, text: &str, input: bool) {
+        let Some(recorder) = &mut self.recorder else {
+            return;
+        };
+        let result = if input {
+            recorder.input(text)
+        } else {
+            recorder.output(text)
+        };
+        if let Err(e) = result {
+            log::warn!("Stopped recording to {}: {}", recorder.path().display(), e);
+            self.recorder = None;
+        }
+    }
+
+    /// Mark the recording with `label`, if there is one
+    fn record_marker(&mut self, label: &str) {
+        let Some(recorder) = &mut self.recorder else {
+            return;
+        };
+        if let Err(e) = recorder.marker(label) {
+            log::warn!("Stopped recording to {}: {}", recorder.path().display(), e);
+            self.recorder = None;
+        }
+    }
+
+    /// Decode PTY output and record it
+    fn decode_output(&mut self, bytes: &[u8]) -> String {
+        let text = self.decoder.decode(bytes);
+        self.record(&text, false);
+        text
+    }
+
+    /// The rest of the PTY output at EOF
+    fn finish_output(&mut self) -> String {
+        let text = self.decoder.finish();
+        self.record(&text, false);
+        text
+    }
+
     pub async fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.record(&String::from_utf8_lossy(buf), true);
         self.pty.write_all(buf).await?;
         self.pty.flush().await
     }
@@ -98,6 +210,151 @@ impl<T: TerminalType> EchokitChild<T> {
         self.write_all(bytes).await
     }
 
+    /// Write `text` as a single bracketed paste, so newlines do not submit it.
+    /// Large pastes are written in chunks to not flood the TUI input buffer.
+    pub async fn send_paste(&mut self, text: &str) -> std::io::Result<()> {
+        // an embedded end marker would end the paste early and type the rest
+        let text = text.replace("\r\n", "\n").replace(PASTE_END, "");
+
+        self.write_all(PASTE_START.as_bytes()).await?;
+        for chunk in text.as_bytes().chunks(PASTE_CHUNK_SIZE) {
+            self.write_all(chunk).await?;
+            if text.len() > PASTE_CHUNK_SIZE {
+                tokio::time::sleep(PASTE_CHUNK_DELAY).await;
+            }
+        }
+        self.write_all(PASTE_END.as_bytes()).await
+    }
+
+    /// Write `text` the way `pacing` submits input, without pressing Enter
+    pub async fn send_input_text(
+        &mut self,
+        text: &str,
+        pacing: &pacing::InputPacing,
+    ) -> std::io::Result<()> {
+        match pacing.submit {
+            pacing::SubmitMode::Paste => self.send_paste(text).await,
+            // a typed newline would submit the prompt line by line
+            pacing::SubmitMode::Type if text.contains('\n') => self.send_paste(text).await,
+            pacing::SubmitMode::Type => self.send_text(text).await,
+        }
+    }
+
+    /// Read one chunk of PTY output into `output` before `deadline`. Returns
+    /// `false` at EOF or once the deadline passed.
+    async fn read_chunk_before(
+        &mut self,
+        deadline: tokio::time::Instant,
+        output: &mut OutputChunk,
+    ) -> std::io::Result<bool> {
+        let Ok(chunk) = tokio::time::timeout_at(deadline, self.read_chunk()).await else {
+            return Ok(false);
+        };
+        let chunk = chunk?;
+        let eof = chunk.is_eof();
+        output.extend(chunk);
+        Ok(!eof)
+    }
+
+    /// Read PTY output until it echoes `text` (any output when `text` is
+    /// empty) or `timeout` expires. Returns whether the echo was seen and the
+    /// output read meanwhile, which the caller still has to forward.
+    pub async fn wait_for_echo(
+        &mut self,
+        text: &str,
+        timeout: std::time::Duration,
+    ) -> std::io::Result<(bool, OutputChunk)> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut output = OutputChunk::default();
+
+        while self.read_chunk_before(deadline, &mut output).await? {
+            if pacing::is_echoed(&output.text, text) {
+                return Ok((true, output));
+            }
+        }
+
+        Ok((false, output))
+    }
+
+    /// Read PTY output until none arrives for `quiet` or `timeout` expires.
+    /// The caller still has to forward the output.
+    pub async fn read_until_quiet(
+        &mut self,
+        quiet: std::time::Duration,
+        timeout: std::time::Duration,
+    ) -> std::io::Result<OutputChunk> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut output = OutputChunk::default();
+
+        loop {
+            let until = deadline.min(tokio::time::Instant::now() + quiet);
+            if !self.read_chunk_before(until, &mut output).await? {
+                break;
+            }
+        }
+
+        Ok(output)
+    }
+
+    /// Wait until Enter may follow `text`: until it is echoed with
+    /// `wait_for_echo`, for `delay` otherwise. Returns the PTY output read
+    /// meanwhile, which the caller still has to forward.
+    pub async fn wait_before_key(
+        &mut self,
+        text: &str,
+        pacing: &pacing::InputPacing,
+        delay: std::time::Duration,
+    ) -> std::io::Result<OutputChunk> {
+        if !pacing.wait_for_echo {
+            tokio::time::sleep(delay).await;
+            return Ok(OutputChunk::default());
+        }
+
+        let (echoed, output) = self.wait_for_echo(text, pacing.echo_timeout()).await?;
+        if !echoed {
+            log::debug!(
+                "No echo of {:?} within {:?}, continuing anyway",
+                text,
+                pacing.echo_timeout()
+            );
+        }
+        Ok(output)
+    }
+
+    /// How `apply_input` paces text and Enter, the default pacing otherwise
+    pub fn set_pacing(&mut self, pacing: pacing::InputPacing) {
+        self.pacing = pacing;
+    }
+
+    /// Apply one input item: text is typed as is, Enter waits for the text
+    /// typed before it to be echoed. Returns the PTY output read meanwhile,
+    /// which the caller still has to forward.
+    pub async fn apply_input(&mut self, item: InputItem) -> std::io::Result<OutputChunk> {
+        match item {
+            InputItem::Text { input } => {
+                self.send_text(&input).await?;
+                self.typed = input;
+            }
+            InputItem::KeyboardInterrupt => self.send_keyboard_interrupt().await?,
+            InputItem::Enter => {
+                let typed = std::mem::take(&mut self.typed);
+                let output = if typed.is_empty() {
+                    OutputChunk::default()
+                } else {
+                    let pacing = self.pacing;
+                    self.wait_before_key(&typed, &pacing, pacing.enter_delay())
+                        .await?
+                };
+                self.send_enter().await?;
+                return Ok(output);
+            }
+            InputItem::Esc => self.send_esc().await?,
+            InputItem::Key(key) => self.send_key(&key).await?,
+            InputItem::Bytes(bytes) => self.write_all(&bytes).await?,
+        }
+        Ok(OutputChunk::default())
+    }
+
     pub async fn send_esc(&mut self) -> std::io::Result<()> {
         self.write_all(b"\x1b").await
     }
@@ -118,6 +375,14 @@ impl<T: TerminalType> EchokitChild<T> {
         self.write_all(b"\x1b[C").await
     }
 
+    /// Write the xterm encoding of a key press
+    pub async fn send_key(&mut self, key: &keys::KeyInput) -> std::io::Result<()> {
+        let bytes = key
+            .encode()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        self.write_all(&bytes).await
+    }
+
     pub async fn send_keyboard_interrupt(&mut self) -> std::io::Result<()> {
         self.write_all(b"\x03").await
     }
@@ -130,25 +395,33 @@ impl<T: TerminalType> EchokitChild<T> {
         self.pty.read(buffer).await
     }
 
-    pub async fn read_string(&mut self) -> std::io::Result<String> {
-        let mut buffer = [0u8; 1024];
-        let mut string_buffer = Vec::with_capacity(512);
-
-        loop {
-            let n = self.pty.read(&mut buffer).await?;
-            if n == 0 {
-                break;
-            }
+    /// Read one chunk of PTY output, decoding it with the UTF-8 state carried
+    /// over from the previous reads. Returns an empty chunk at EOF.
+    pub async fn read_chunk(&mut self) -> std::io::Result<OutputChunk> {
+        let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+        let n = self.pty.read(&mut buffer).await?;
+        if n == 0 {
+            return Ok(OutputChunk {
+                bytes: Vec::new(),
+                text: self.finish_output(),
+            });
+        }
 
-            string_buffer.extend_from_slice(&buffer[..n]);
+        Ok(OutputChunk {
+            bytes: buffer[..n].to_vec(),
+            text: self.decode_output(&buffer[..n]),
+        })
+    }
 
-            let s = str::from_utf8(&string_buffer);
-            if let Ok(s) = s {
-                return Ok(s.to_string());
+    /// Read PTY output as text, empty at EOF. A read ending inside a UTF-8
+    /// sequence is completed by the next one instead of being replaced.
+    pub async fn read_string(&mut self) -> std::io::Result<String> {
+        loop {
+            let chunk = self.read_chunk().await?;
+            if chunk.is_eof() || !chunk.text.is_empty() {
+                return Ok(chunk.text);
             }
         }
-
-        Ok(String::from_utf8_lossy(&string_buffer).to_string())
     }
 
     pub async fn wait(&mut self) -> std::io::Result<std::process::ExitStatus> {
@@ -0,0 +1,172 @@
+use std::{sync::Mutex, time::Duration};
+
+use tokio::{sync::broadcast, time::Instant};
+
+use super::OutputChunk;
+
+/// A message of an [`OutputBroadcast`], PTY output or anything else its
+/// clients are told
+pub trait OutputMessage: Clone + Send + 'static {
+    fn from_output(output: OutputChunk) -> Self;
+
+    /// The PTY output this message carries, the message itself otherwise
+    fn into_output(self) -> Result<OutputChunk, Self>;
+}
+
+impl OutputMessage for OutputChunk {
+    fn from_output(output: OutputChunk) -> Self {
+        output
+    }
+
+    fn into_output(self) -> Result<OutputChunk, Self> {
+        Ok(self)
+    }
+}
+
+/// The messages of a terminal on their way to its clients.
+///
+/// PTY output is held back until `coalesce_bytes` are pending or the oldest
+/// pending output is `coalesce_delay` old, any other message sends it first.
+/// The owner calls [`OutputBroadcast::flush`] once the
+/// [`OutputBroadcast::flush_deadline`] passed. Sent output is mirrored on a
+/// virtual screen that clients falling behind the broadcast channel are
+/// resynced with.
+pub struct OutputBroadcast<M: OutputMessage = OutputChunk> {
+    tx: broadcast::Sender<M>,
+    coalesce_bytes: usize,
+    coalesce_delay: Duration,
+    inner: Mutex<Pending>,
+}
+
+struct Pending {
+    output: OutputChunk,
+    since: Option<Instant>,
+    screen: vt100::Parser,
+}
+
+impl<M: OutputMessage> OutputBroadcast<M> {
+    /// A broadcast keeping `capacity` messages for slow clients, mirroring a
+    /// screen of `(rows, cols)`. A zero `coalesce_delay` sends output at once.
+    pub fn new(
+        capacity: usize,
+        coalesce_bytes: usize,
+        coalesce_delay: Duration,
+        (rows, cols): (u16, u16),
+    ) -> Self {
+        let (tx, _) = broadcast::channel(capacity);
+        Self {
+            tx,
+            coalesce_bytes,
+            coalesce_delay,
+            inner: Mutex::new(Pending {
+                output: OutputChunk::default(),
+                since: None,
+                screen: vt100::Parser::new(rows, cols, 0),
+            }),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<M> {
+        self.tx.subscribe()
+    }
+
+    /// Clients subscribed right now
+    pub fn receiver_count(&self) -> usize {
+        self.tx.receiver_count()
+    }
+
+    /// Size of the terminal the screen mirrors
+    pub fn set_size(&self, (rows, cols): (u16, u16)) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.screen.screen_mut().set_size(rows, cols);
+    }
+
+    /// Queue PTY output or send any other message. Returns `false` when no
+    /// client is subscribed.
+    pub fn send(&self, message: M) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match message.into_output() {
+            Ok(output) => {
+                if output.is_empty() {
+                    return self.tx.receiver_count() > 0;
+                }
+                inner.since.get_or_insert_with(Instant::now);
+                inner.output.extend(output);
+                if inner.output.bytes.len() >= self.coalesce_bytes || self.coalesce_delay.is_zero()
+                {
+                    self.send_pending(&mut inner);
+                }
+            }
+            Err(message) => {
+                self.send_pending(&mut inner);
+                let _ = self.tx.send(message);
+            }
+        }
+        self.tx.receiver_count() > 0
+    }
+
+    /// Send the pending PTY output now
+    pub fn flush(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        self.send_pending(&mut inner);
+    }
+
+    /// When the pending PTY output is due, `None` when nothing is pending
+    pub fn flush_deadline(&self) -> Option<Instant> {
+        let inner = self.inner.lock().unwrap();
+        inner.since.map(|since| since + self.coalesce_delay)
+    }
+
+    fn send_pending(&self, inner: &mut Pending) {
+        inner.since = None;
+        if inner.output.is_empty() {
+            return;
+        }
+        let output = std::mem::take(&mut inner.output);
+        inner.screen.process(&output.bytes);
+        let _ = self.tx.send(M::from_output(output));
+    }
+
+    /// Replace a receiver that lagged behind. Returns the escape sequences
+    /// redrawing the screen as of now, and a receiver continuing right after
+    /// it.
+    pub fn resync(&self, rx: &broadcast::Receiver<M>) -> (OutputChunk, broadcast::Receiver<M>) {
+        let inner = self.inner.lock().unwrap();
+        let bytes = inner.screen.screen().state_formatted();
+        let text = String::from_utf8_lossy(&bytes).to_string();
+        (OutputChunk { bytes, text }, rx.resubscribe())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_coalesce_and_resync() {
+        let output: OutputBroadcast = OutputBroadcast::new(2, 8, Duration::from_secs(60), (24, 80));
+        let mut rx = output.subscribe();
+
+        assert!(output.send("ab".to_string().into()));
+        assert!(output.send("cd".to_string().into()));
+        assert!(output.flush_deadline().is_some());
+        assert!(rx.try_recv().is_err());
+        output.flush();
+        assert_eq!(rx.try_recv().unwrap().bytes, b"abcd");
+        assert_eq!(output.flush_deadline(), None);
+
+        // enough bytes go out at once
+        output.send("0123456789".to_string().into());
+        assert_eq!(rx.try_recv().unwrap().text, "0123456789");
+
+        for line in ["one\r\n", "two\r\n", "three\r\n"] {
+            output.send(line.repeat(2).into());
+        }
+        assert!(matches!(
+            rx.try_recv(),
+            Err(broadcast::error::TryRecvError::Lagged(_))
+        ));
+        let (screen, _) = output.resync(&rx);
+        assert!(screen.text.contains("three"));
+    }
+}
@@ -0,0 +1,121 @@
+use std::time::Duration;
+
+/// How text reaches the terminal before Enter submits it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubmitMode {
+    /// Wrapped in bracketed-paste sequences, arrives as a single edit
+    #[default]
+    Paste,
+    /// Written as plain keystrokes
+    Type,
+}
+
+/// Pacing of the keystrokes that submit input to a TUI.
+///
+/// With `wait_for_echo` Enter is pressed as soon as the PTY shows the text,
+/// the fixed delays only apply when it is off.
+#[derive(Debug, Clone, Copy, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InputPacing {
+    pub submit: SubmitMode,
+    pub wait_for_echo: bool,
+    /// Longest wait for an echo or a redraw, Enter is pressed anyway afterwards
+    pub echo_timeout_ms: u64,
+    /// Between writing a prompt and pressing Enter
+    pub enter_delay_ms: u64,
+    /// Between two arrow keys of a selection
+    pub key_delay_ms: u64,
+    /// Between the last arrow key of a selection and pressing Enter
+    pub select_delay_ms: u64,
+    /// Between typing `/exit` and pressing Enter
+    pub exit_delay_ms: u64,
+}
+
+impl Default for InputPacing {
+    fn default() -> Self {
+        Self {
+            submit: SubmitMode::Paste,
+            wait_for_echo: true,
+            echo_timeout_ms: 2000,
+            enter_delay_ms: 500,
+            key_delay_ms: 0,
+            select_delay_ms: 500,
+            exit_delay_ms: 300,
+        }
+    }
+}
+
+impl InputPacing {
+    pub fn echo_timeout(&self) -> Duration {
+        Duration::from_millis(self.echo_timeout_ms)
+    }
+
+    pub fn enter_delay(&self) -> Duration {
+        Duration::from_millis(self.enter_delay_ms)
+    }
+
+    pub fn key_delay(&self) -> Duration {
+        Duration::from_millis(self.key_delay_ms)
+    }
+
+    pub fn select_delay(&self) -> Duration {
+        Duration::from_millis(self.select_delay_ms)
+    }
+
+    pub fn exit_delay(&self) -> Duration {
+        Duration::from_millis(self.exit_delay_ms)
+    }
+}
+
+/// Characters ignored when looking for an echo: whitespace from wrapping and
+/// the box drawing of input borders
+fn is_layout(c: char) -> bool {
+    c.is_whitespace() || ('\u{2500}'..='\u{257f}').contains(&c)
+}
+
+/// Claude Code shows long pastes as `[Pasted text #1 +12 lines]`
+const PASTE_PLACEHOLDER: &str = "[Pastedtext";
+
+/// Longest tail of the text looked for in the output
+const ECHO_TAIL: usize = 32;
+
+/// Whether `output` shows `text` as typed into an input line. Only the end of
+/// the text is compared, ignoring ANSI escapes, wrapping and borders. An
+/// empty `text` matches any output.
+pub fn is_echoed(output: &str, text: &str) -> bool {
+    if text.is_empty() {
+        return !output.is_empty();
+    }
+
+    let output = strip_ansi_escapes::strip_str(output)
+        .chars()
+        .filter(|c| !is_layout(*c))
+        .collect::<String>();
+    let text = text.chars().filter(|c| !is_layout(*c)).collect::<Vec<_>>();
+    if text.is_empty() {
+        return true;
+    }
+
+    let tail = text[text.len().saturating_sub(ECHO_TAIL)..]
+        .iter()
+        .collect::<String>();
+    output.contains(&tail) || output.contains(PASTE_PLACEHOLDER)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_echoed() {
+        let output = "\x1b[2K\x1b[1G│ > fix the failing │\r\n│   test in lib.rs  │";
+        assert!(is_echoed(output, "fix the failing test in lib.rs"));
+        assert!(!is_echoed(output, "fix the failing test in main.rs"));
+
+        assert!(is_echoed("\x1b[7m[Pasted text #1 +40 lines]", "a\nb\nc"));
+
+        assert!(is_echoed("\x1b[1A", ""));
+        assert!(!is_echoed("\x1b[1A", "hello"));
+    }
+}
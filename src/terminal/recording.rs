@@ -0,0 +1,300 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Where and how a terminal is recorded
+#[derive(Debug, Clone)]
+pub struct RecordingOptions {
+    /// The current cast file, rotated ones are `<path>.1` (the newest),
+    /// `<path>.2` and so on
+    pub path: PathBuf,
+    /// Also record what is written to the terminal, as `i` events
+    pub input: bool,
+    /// The cast file is rotated once it grows beyond this size, 0 for no limit
+    pub max_bytes: u64,
+    /// Rotated files kept besides the current one
+    pub max_files: usize,
+}
+
+/// `<path>.<n>`, the `n`th newest rotated cast file
+pub fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(format!(".{}", n));
+    PathBuf::from(path)
+}
+
+/// Written events reach the file at the latest once they are this old, or as
+/// long after the last event
+const FLUSH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Writes the output, and optionally the input, of a terminal as an
+/// [asciicast v2](https://docs.asciinema.org/manual/asciicast/v2/) file.
+/// Every rotated file is a complete recording starting at its own header.
+///
+/// The file is written by a thread of its own, recording never blocks the
+/// terminal. The recording ends when the `Recorder` is dropped.
+pub struct Recorder {
+    path: PathBuf,
+    input: bool,
+    tx: mpsc::Sender<Command>,
+}
+
+enum Command {
+    Event(Instant, &'static str, String),
+    Flush(mpsc::Sender<()>),
+}
+
+impl Recorder {
+    /// Start a new cast file, a file left at `options.path` by an earlier
+    /// recording is rotated first
+    pub fn new(options: RecordingOptions, size: (u16, u16)) -> std::io::Result<Self> {
+        if let Some(parent) = options.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        if options.path.exists() {
+            rotate(&options)?;
+        }
+
+        let file = BufWriter::new(File::create(&options.path)?);
+        let path = options.path.clone();
+        let input = options.input;
+        let mut writer = Writer {
+            options,
+            size,
+            file,
+            start: Instant::now(),
+            written: 0,
+        };
+        writer.write_header()?;
+
+        let (tx, rx) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("echokit-recorder".to_string())
+            .spawn(move || writer.run(rx))?;
+        Ok(Self { path, input, tx })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn output(&mut self, text: &str) -> std::io::Result<()> {
+        self.event("o", text)
+    }
+
+    /// Ignored unless the options ask for input
+    pub fn input(&mut self, text: &str) -> std::io::Result<()> {
+        if !self.input {
+            return Ok(());
+        }
+        self.event("i", text)
+    }
+
+    /// Mark the current point of the recording, as an `m` event
+    pub fn marker(&mut self, label: &str) -> std::io::Result<()> {
+        self.event("m", label)
+    }
+
+    /// Wait until every event so far is written to the file
+    pub fn flush(&self) -> std::io::Result<()> {
+        let (done_tx, done_rx) = mpsc::channel();
+        self.tx
+            .send(Command::Flush(done_tx))
+            .map_err(|_| stopped())?;
+        done_rx.recv().map_err(|_| stopped())
+    }
+
+    fn event(&mut self, kind: &'static str, data: &str) -> std::io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.tx
+            .send(Command::Event(Instant::now(), kind, data.to_string()))
+            .map_err(|_| stopped())
+    }
+}
+
+/// The writer thread gave up after an error, which it logged
+fn stopped() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::BrokenPipe, "the recording stopped")
+}
+
+/// The cast file, owned by the thread of a [`Recorder`]
+struct Writer {
+    options: RecordingOptions,
+    size: (u16, u16),
+    file: BufWriter<File>,
+    start: Instant,
+    written: u64,
+}
+
+impl Writer {
+    /// Write the events of `rx` until its `Recorder` is dropped or writing
+    /// fails
+    fn run(mut self, rx: mpsc::Receiver<Command>) {
+        // when the oldest event not flushed yet was written
+        let mut unflushed: Option<Instant> = None;
+        loop {
+            let result = match rx.recv_timeout(FLUSH_INTERVAL) {
+                Ok(Command::Event(time, kind, data)) => {
+                    self.event(time, kind, &data).and_then(|()| {
+                        if unflushed.get_or_insert_with(Instant::now).elapsed() < FLUSH_INTERVAL {
+                            return Ok(());
+                        }
+                        unflushed = None;
+                        self.file.flush()
+                    })
+                }
+                Ok(Command::Flush(done)) => {
+                    unflushed = None;
+                    self.file.flush().map(|()| {
+                        let _ = done.send(());
+                    })
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) if unflushed.is_some() => {
+                    unflushed = None;
+                    self.file.flush()
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => Ok(()),
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            };
+            if let Err(e) = result {
+                log::warn!(
+                    "Stopped recording to {}: {}",
+                    self.options.path.display(),
+                    e
+                );
+                return;
+            }
+        }
+
+        if let Err(e) = self.file.flush() {
+            log::warn!(
+                "Failed to finish recording {}: {}",
+                self.options.path.display(),
+                e
+            );
+        }
+    }
+
+    fn write_header(&mut self) -> std::io::Result<()> {
+        let (rows, cols) = self.size;
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let timestamp = now.as_secs();
+        // the header only holds whole seconds, events are timed from there so
+        // they line up with wall clock times like those of the history
+        let subsec = Duration::from_nanos(now.subsec_nanos() as u64);
+        self.start = Instant::now()
+            .checked_sub(subsec)
+            .unwrap_or_else(Instant::now);
+        let header = serde_json::json!({
+            "version": 2,
+            "width": cols,
+            "height": rows,
+            "timestamp": timestamp,
+            "env": { "TERM": "xterm-256color" },
+        });
+        self.write_line(&header.to_string())
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        self.file.write_all(line.as_bytes())?;
+        self.file.write_all(b"\n")?;
+        self.written += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn event(&mut self, time: Instant, kind: &str, data: &str) -> std::io::Result<()> {
+        let elapsed = time.saturating_duration_since(self.start);
+        let time = (elapsed.as_secs_f64() * 1e6).round() / 1e6;
+        let line = serde_json::to_string(&(time, kind, data))?;
+        self.write_line(&line)?;
+
+        if self.options.max_bytes > 0 && self.written >= self.options.max_bytes {
+            self.file.flush()?;
+            rotate(&self.options)?;
+            self.file = BufWriter::new(File::create(&self.options.path)?);
+            self.written = 0;
+            self.write_header()?;
+        }
+        Ok(())
+    }
+}
+
+/// Move the current cast file to `<path>.1`, dropping the oldest one
+fn rotate(options: &RecordingOptions) -> std::io::Result<()> {
+    if options.max_files == 0 {
+        return std::fs::remove_file(&options.path);
+    }
+
+    for n in (1..options.max_files).rev() {
+        let from = rotated_path(&options.path, n);
+        if from.exists() {
+            std::fs::rename(&from, rotated_path(&options.path, n + 1))?;
+        }
+    }
+    std::fs::rename(&options.path, rotated_path(&options.path, 1))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lines(path: &Path) -> Vec<serde_json::Value> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_record_and_rotate() {
+        let dir = std::env::temp_dir().join(format!("echokit_recording_{}", uuid::Uuid::new_v4()));
+        let options = RecordingOptions {
+            path: dir.join("session.cast"),
+            input: false,
+            max_bytes: 200,
+            max_files: 1,
+        };
+
+        let mut recorder = Recorder::new(options.clone(), (24, 80)).unwrap();
+        recorder.output("\x1b[1mhello\x1b[0m\r\n").unwrap();
+        recorder.input("ignored").unwrap();
+        recorder.flush().unwrap();
+
+        let cast = lines(&options.path);
+        assert_eq!(cast[0]["version"], 2);
+        assert_eq!(cast[0]["width"], 80);
+        assert_eq!(cast[0]["height"], 24);
+        assert_eq!(cast.len(), 2);
+        assert_eq!(cast[1][1], "o");
+        assert_eq!(cast[1][2], "\x1b[1mhello\x1b[0m\r\n");
+
+        // the header and these events pass max_bytes twice
+        for _ in 0..2 {
+            recorder.output(&"x".repeat(150)).unwrap();
+        }
+        recorder.output("last").unwrap();
+        recorder.flush().unwrap();
+
+        let cast = lines(&options.path);
+        assert_eq!(cast.len(), 2);
+        assert_eq!(cast[1][2], "last");
+        assert_eq!(lines(&rotated_path(&options.path, 1)).len(), 2);
+        assert!(!rotated_path(&options.path, 2).exists());
+
+        // a new recording keeps the previous one as the newest rotated file
+        drop(recorder);
+        Recorder::new(options.clone(), (24, 80)).unwrap();
+        assert_eq!(lines(&rotated_path(&options.path, 1))[1][2], "last");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,30 +1,451 @@
-use super::{EchokitChild, PtyCommand, PtySize, TerminalType};
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use super::{EchokitChild, OutputChunk, PtyCommand, PtySize, TerminalType};
 
 pub trait ShellType: TerminalType<Output = String> {
-    fn shell_name() -> &'static str;
+    /// The program to run
+    fn shell_name(&self) -> &str;
+
+    /// Start the shell interactively with the integration script loaded on
+    /// top of the user's rc files
+    fn integrate(&self, cmd: PtyCommand) -> std::io::Result<PtyCommand>;
+
+    /// Whether `integrate` loads a script printing the OSC 133 marks
+    fn integrated(&self) -> bool {
+        true
+    }
+
+    /// The command line `run_command` types for `command`, its output is
+    /// delimited by marks carrying `token`
+    fn exec_line(&self, command: &str, token: &str) -> String {
+        posix_exec_line(command, token)
+    }
+
+    fn tracker(&self) -> &ShellTracker;
+
+    fn tracker_mut(&mut self) -> &mut ShellTracker;
 }
 
-pub struct Bash;
+#[derive(Default)]
+pub struct Bash {
+    tracker: ShellTracker,
+}
 impl TerminalType for Bash {
     type Output = String;
 }
 impl ShellType for Bash {
-    fn shell_name() -> &'static str {
+    fn shell_name(&self) -> &str {
         "bash"
     }
+
+    fn integrate(&self, cmd: PtyCommand) -> std::io::Result<PtyCommand> {
+        let rcfile = integration_file("bashrc", include_str!("integration/bash.sh"))?;
+        Ok(cmd.arg("--rcfile").arg(rcfile).arg("-i"))
+    }
+
+    fn tracker(&self) -> &ShellTracker {
+        &self.tracker
+    }
+
+    fn tracker_mut(&mut self) -> &mut ShellTracker {
+        &mut self.tracker
+    }
+}
+
+#[derive(Default)]
+pub struct Zsh {
+    tracker: ShellTracker,
 }
-pub struct Zsh;
 impl TerminalType for Zsh {
     type Output = String;
 }
 impl ShellType for Zsh {
-    fn shell_name() -> &'static str {
+    fn shell_name(&self) -> &str {
         "zsh"
     }
+
+    /// zsh reads its rc files from `ZDOTDIR`, the integration points it to
+    /// its own and restores the user's one there
+    fn integrate(&self, cmd: PtyCommand) -> std::io::Result<PtyCommand> {
+        let zshenv = integration_file("zsh/.zshenv", include_str!("integration/zshenv.zsh"))?;
+        integration_file("zsh/.zshrc", include_str!("integration/zshrc.zsh"))?;
+        let user_zdotdir = std::env::var_os("ZDOTDIR")
+            .or_else(|| std::env::var_os("HOME"))
+            .unwrap_or_default();
+        Ok(cmd
+            .env("ZDOTDIR", zshenv.parent().unwrap_or(Path::new("/")))
+            .env("ECHOKIT_USER_ZDOTDIR", user_zdotdir)
+            .arg("-i"))
+    }
+
+    fn tracker(&self) -> &ShellTracker {
+        &self.tracker
+    }
+
+    fn tracker_mut(&mut self) -> &mut ShellTracker {
+        &mut self.tracker
+    }
+}
+
+#[derive(Default)]
+pub struct Fish {
+    tracker: ShellTracker,
+}
+impl TerminalType for Fish {
+    type Output = String;
+}
+impl ShellType for Fish {
+    fn shell_name(&self) -> &str {
+        "fish"
+    }
+
+    /// fish keeps reading the user's config, the integration is sourced after it
+    fn integrate(&self, cmd: PtyCommand) -> std::io::Result<PtyCommand> {
+        let script = integration_file("integration.fish", include_str!("integration/fish.fish"))?;
+        let source = format!("source {}", fish_quote(&script.to_string_lossy()));
+        Ok(cmd.arg("--init-command").arg(source).arg("-i"))
+    }
+
+    /// fish has `$status` instead of `$?`, and no way to set it afterwards
+    fn exec_line(&self, command: &str, token: &str) -> String {
+        format!(
+            r#" printf '\e]133;C;echokit=%s\a' {token}; eval {}; printf '\e]133;D;%s;echokit=%s\a' $status {token}"#,
+            fish_quote(command),
+        ) + "\r"
+    }
+
+    fn tracker(&self) -> &ShellTracker {
+        &self.tracker
+    }
+
+    fn tracker_mut(&mut self) -> &mut ShellTracker {
+        &mut self.tracker
+    }
+}
+
+/// POSIX `sh`, such as dash
+#[derive(Default)]
+pub struct Sh {
+    tracker: ShellTracker,
+}
+impl TerminalType for Sh {
+    type Output = String;
+}
+impl ShellType for Sh {
+    fn shell_name(&self) -> &str {
+        "sh"
+    }
+
+    /// An interactive sh reads the file named by `ENV`, the integration reads
+    /// the user's one
+    fn integrate(&self, cmd: PtyCommand) -> std::io::Result<PtyCommand> {
+        let env = integration_file("env.sh", include_str!("integration/sh.sh"))?;
+        let user_env = std::env::var_os("ENV").unwrap_or_default();
+        Ok(cmd
+            .env("ENV", env)
+            .env("ECHOKIT_USER_ENV", user_env)
+            .arg("-i"))
+    }
+
+    fn tracker(&self) -> &ShellTracker {
+        &self.tracker
+    }
+
+    fn tracker_mut(&mut self) -> &mut ShellTracker {
+        &mut self.tracker
+    }
+}
+
+/// How [`CustomShell`] loads its rc file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RcInjection {
+    /// Pass the path after this argument, like `--rcfile` of bash
+    Arg(String),
+    /// Put the path in this environment variable, like `ENV` of sh
+    Env(String),
+}
+
+/// A shell configured at runtime. It runs commands with POSIX syntax, and
+/// reports its state when its rc file prints the OSC 133 and OSC 7 marks.
+pub struct CustomShell {
+    pub program: String,
+    /// Start it with `-i`
+    pub interactive: bool,
+    pub rc: Option<(PathBuf, RcInjection)>,
+    tracker: ShellTracker,
+}
+
+impl CustomShell {
+    pub fn new(program: impl Into<String>) -> Self {
+        Self {
+            program: program.into(),
+            interactive: false,
+            rc: None,
+            tracker: ShellTracker::default(),
+        }
+    }
+
+    pub fn interactive(mut self, interactive: bool) -> Self {
+        self.interactive = interactive;
+        self
+    }
+
+    pub fn rc(mut self, path: impl Into<PathBuf>, injection: RcInjection) -> Self {
+        self.rc = Some((path.into(), injection));
+        self
+    }
+}
+
+impl TerminalType for CustomShell {
+    type Output = String;
+}
+impl ShellType for CustomShell {
+    fn shell_name(&self) -> &str {
+        &self.program
+    }
+
+    /// Only its own rc file can print the marks
+    fn integrated(&self) -> bool {
+        self.rc.is_some()
+    }
+
+    fn integrate(&self, mut cmd: PtyCommand) -> std::io::Result<PtyCommand> {
+        match &self.rc {
+            Some((path, RcInjection::Arg(flag))) => cmd = cmd.arg(flag).arg(path),
+            Some((path, RcInjection::Env(var))) => cmd = cmd.env(var, path),
+            None => {}
+        }
+        if self.interactive {
+            cmd = cmd.arg("-i");
+        }
+        Ok(cmd)
+    }
+
+    fn tracker(&self) -> &ShellTracker {
+        &self.tracker
+    }
+
+    fn tracker_mut(&mut self) -> &mut ShellTracker {
+        &mut self.tracker
+    }
+}
+
+/// `text` in fish single quotes, where only `\` and `'` are escaped
+fn fish_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\\', r"\\").replace('\'', r"\'"))
+}
+
+/// The directory of this process for integration scripts, made on first use
+/// below `$XDG_RUNTIME_DIR` or the temporary directory. The rc files run in
+/// every shell, so it is only accessible to this user.
+fn integration_dir() -> std::io::Result<PathBuf> {
+    use std::os::unix::fs::DirBuilderExt;
+
+    static DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+    let mut dir = DIR.lock().unwrap();
+    if let Some(dir) = &*dir {
+        return Ok(dir.clone());
+    }
+
+    let base = std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .filter(|base| base.is_dir())
+        .unwrap_or_else(std::env::temp_dir);
+    let path = base.join(format!(
+        "echokit_shell_integration-{}-{}",
+        std::process::id(),
+        uuid::Uuid::new_v4().simple()
+    ));
+    // not `create_dir_all`, a directory somebody made beforehand is an error
+    std::fs::DirBuilder::new().mode(0o700).create(&path)?;
+    check_private_dir(&path)?;
+    *dir = Some(path.clone());
+    Ok(path)
+}
+
+/// Refuse a directory other users own or can write to
+fn check_private_dir(dir: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = std::fs::symlink_metadata(dir)?;
+    // SAFETY: geteuid has no preconditions and cannot fail
+    let uid = unsafe { libc::geteuid() };
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("{} is not a private directory of this user", dir.display()),
+        ));
+    }
+    Ok(())
+}
+
+/// Write an integration script to the private directory, returning its path.
+/// Written to a unique name and renamed, so a shell starting meanwhile never
+/// reads half of it.
+fn integration_file(name: &str, script: &str) -> std::io::Result<PathBuf> {
+    use std::os::unix::fs::DirBuilderExt;
+
+    let path = integration_dir()?.join(name);
+    if let Some(dir) = path.parent() {
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)?;
+    }
+    if std::fs::read_to_string(&path).is_ok_and(|current| current == script) {
+        return Ok(path);
+    }
+    let tmp = path.with_extension(uuid::Uuid::new_v4().simple().to_string());
+    std::fs::write(&tmp, script)?;
+    std::fs::rename(&tmp, &path)?;
+    Ok(path)
+}
+
+/// What the shell is doing, from the OSC 133 marks of its integration
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize)]
+#[serde(tag = "state")]
+pub enum ShellState {
+    /// No prompt yet, or the shell runs without integration
+    #[default]
+    Starting,
+    /// At the prompt
+    Idle,
+    /// A command was submitted and has not finished
+    Running,
+}
+
+/// The shell state with what the last prompt reported
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+pub struct ShellStatus {
+    pub current_state: ShellState,
+    /// Exit code of the last command run at the prompt
+    pub last_exit_code: Option<i32>,
+    /// From OSC 7, the shell reports it at each prompt
+    pub cwd: Option<String>,
+}
+
+/// Longest OSC sequence kept while waiting for the rest of it
+const MAX_PENDING_OSC: usize = 4096;
+
+/// Follows the shell state from the OSC 133 and OSC 7 sequences in the PTY
+/// output, sequences split across reads included
+#[derive(Debug, Default)]
+pub struct ShellTracker {
+    status: ShellStatus,
+    /// The shell prints no marks, its state stays `Starting`
+    without_integration: bool,
+    /// An OSC sequence the last output ended in
+    pending: String,
+}
+
+impl ShellTracker {
+    pub fn status(&self) -> &ShellStatus {
+        &self.status
+    }
+
+    /// Whether the shell prints the marks of an integration
+    pub fn is_integrated(&self) -> bool {
+        !self.without_integration
+    }
+
+    /// Returns whether the status changed
+    pub fn update_from_output(&mut self, output: &str) -> bool {
+        let text = std::mem::take(&mut self.pending) + output;
+        let before = self.status.clone();
+
+        let mut rest = text.as_str();
+        while let Some(start) = rest.find("\x1b]") {
+            let body = &rest[start + 2..];
+            let Some(end) = body.find(['\x07', '\x1b']) else {
+                if body.len() < MAX_PENDING_OSC {
+                    self.pending = rest[start..].to_string();
+                }
+                break;
+            };
+            let terminator = &body[end..];
+            if terminator.starts_with('\x07') {
+                self.apply_osc(&body[..end]);
+                rest = &body[end + 1..];
+            } else if terminator.starts_with("\x1b\\") {
+                self.apply_osc(&body[..end]);
+                rest = &body[end + 2..];
+            } else if terminator.len() == 1 {
+                // the output ended between ESC and `\`
+                self.pending = rest[start..].to_string();
+                break;
+            } else {
+                // a sequence cut short by another one
+                rest = terminator;
+            }
+        }
+
+        self.status != before
+    }
+
+    fn apply_osc(&mut self, osc: &str) {
+        let mut params = osc.split(';');
+        match params.next() {
+            Some("133") => {}
+            Some("7") => {
+                if let Some(cwd) = osc.strip_prefix("7;").and_then(cwd_from_url) {
+                    self.status.cwd = Some(cwd);
+                }
+                return;
+            }
+            _ => return,
+        }
+        let mark = params.next().unwrap_or_default();
+        let params: Vec<&str> = params.collect();
+        // the marks `run_command` prints around its command
+        if params.iter().any(|p| p.starts_with("echokit=")) {
+            return;
+        }
+
+        match mark {
+            "A" | "B" => self.status.current_state = ShellState::Idle,
+            "C" => self.status.current_state = ShellState::Running,
+            // the integrations mark the end of a command at every prompt, the
+            // first one ends none. After an empty line the code is unchanged.
+            "D" if self.status.current_state != ShellState::Starting => {
+                self.status.last_exit_code = params.first().and_then(|code| code.parse().ok());
+                self.status.current_state = ShellState::Idle;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The path of an OSC 7 `file://host/path` URL, percent-decoded
+fn cwd_from_url(url: &str) -> Option<String> {
+    let rest = url.strip_prefix("file://")?;
+    let path = &rest[rest.find('/')?..];
+
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = path
+            .get(i + 1..i + 3)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    Some(String::from_utf8_lossy(&decoded).into_owned())
 }
 
 pub fn new<T: ShellType, S: AsRef<std::ffi::OsStr>>(
-    shell: T,
+    mut shell: T,
     shell_args: &[S],
     size: (u16, u16),
 ) -> pty_process::Result<EchokitChild<T>> {
@@ -35,9 +456,11 @@ pub fn new<T: ShellType, S: AsRef<std::ffi::OsStr>>(
     pty.resize(PtySize::new(row, col))?;
 
     let uuid = uuid::Uuid::new_v4();
-    let mut cmd = PtyCommand::new(T::shell_name());
+    let mut cmd = PtyCommand::new(shell.shell_name());
+    // arguments of the user replace the integration
+    shell.tracker_mut().without_integration = !shell_args.is_empty() || !shell.integrated();
     if shell_args.is_empty() {
-        cmd = cmd.arg("-i");
+        cmd = shell.integrate(cmd)?;
     } else {
         for arg in shell_args {
             cmd = cmd.arg(arg);
@@ -59,5 +482,355 @@ pub fn new<T: ShellType, S: AsRef<std::ffi::OsStr>>(
         pty,
         child,
         terminal_type: shell,
+        decoder: super::utf8::Utf8Decoder::new(),
+        recorder: None,
+        pacing: super::pacing::InputPacing::default(),
+        typed: String::new(),
     })
 }
+
+/// Output and exit status of a command run with `run_command`
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct ExecResult {
+    /// What the command wrote to the terminal, stdout and stderr alike,
+    /// without escape sequences
+    pub output: String,
+    /// `None` when the command did not finish in time
+    pub exit_code: Option<i32>,
+    pub duration_ms: u64,
+    pub timed_out: bool,
+}
+
+/// The OSC 133 command start mark printed by `run_command`, `token` tells it
+/// apart from marks the shell prints itself
+fn start_mark(token: &str) -> String {
+    format!("\x1b]133;C;echokit={}\x07", token)
+}
+
+/// The command line typed by `run_command` in POSIX shells: `command`
+/// between an OSC 133 command start mark and an end mark carrying `$?`,
+/// which is kept for the prompt. Quoted for `eval`, a multi-line command
+/// continues at the secondary prompt. The leading space keeps it out of the
+/// history where `HISTCONTROL=ignorespace`.
+pub fn posix_exec_line(command: &str, token: &str) -> String {
+    format!(
+        r#" printf '\033]133;C;echokit=%s\007' {token}; eval '{}'; __echokit_status=$?; printf '\033]133;D;%s;echokit=%s\007' "$__echokit_status" {token}; (exit "$__echokit_status")"#,
+        command.replace('\'', r"'\''"),
+    ) + "\r"
+}
+
+/// Text between two marks, without escape sequences and with `\n` endings
+fn exec_output(raw: &str) -> String {
+    strip_ansi_escapes::strip_str(raw).replace("\r\n", "\n")
+}
+
+/// The output after the start mark of `token` and the exit code, once the
+/// end mark arrived. The echo of the typed line holds no ESC, so only the
+/// printed marks match.
+fn parse_exec_output(raw: &str, token: &str) -> Option<(String, i32)> {
+    let start_mark = start_mark(token);
+    let rest = &raw[raw.find(&start_mark)? + start_mark.len()..];
+    let suffix = format!(";echokit={}\x07", token);
+
+    let mut search = 0;
+    while let Some(at) = rest[search..].find("\x1b]133;D;") {
+        let mark = search + at;
+        let code_start = mark + "\x1b]133;D;".len();
+        let code = rest[code_start..]
+            .find(&suffix)
+            .and_then(|len| rest[code_start..code_start + len].parse().ok());
+        if let Some(code) = code {
+            return Some((exec_output(&rest[..mark]), code));
+        }
+        search = code_start;
+    }
+    None
+}
+
+impl<T: ShellType> EchokitChild<T> {
+    pub fn shell_status(&self) -> &ShellStatus {
+        self.terminal_type.tracker().status()
+    }
+
+    /// Follow the shell state in PTY output, returns whether it changed
+    pub fn update_from_output(&mut self, output: &str) -> bool {
+        self.terminal_type.tracker_mut().update_from_output(output)
+    }
+
+    /// Read one chunk of output before `deadline`, tracking the state and
+    /// passing it to `on_output`. Returns its text, `None` at EOF or once the
+    /// deadline passed.
+    async fn read_tracked(
+        &mut self,
+        deadline: tokio::time::Instant,
+        on_output: &mut (dyn FnMut(OutputChunk) + Send),
+    ) -> std::io::Result<Option<String>> {
+        let Ok(chunk) = tokio::time::timeout_at(deadline, self.read_chunk()).await else {
+            return Ok(None);
+        };
+        let chunk = chunk?;
+        self.update_from_output(&chunk.text);
+        // the event loop reads the EOF again
+        if chunk.is_eof() {
+            return Ok(None);
+        }
+        let text = chunk.text.clone();
+        on_output(chunk);
+        Ok(Some(text))
+    }
+
+    /// Type `command` at the prompt and wait up to `timeout` for it to
+    /// finish, a command still running then is interrupted with Ctrl+C. The
+    /// PTY output read meanwhile goes to `on_output` as it arrives.
+    ///
+    /// Only a shell with integration sitting at its prompt takes commands,
+    /// others would read the command line as input of whatever they run.
+    pub async fn run_command(
+        &mut self,
+        command: &str,
+        timeout: std::time::Duration,
+        on_output: &mut (dyn FnMut(OutputChunk) + Send),
+    ) -> std::io::Result<ExecResult> {
+        let unsupported = |message: String| {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                message,
+            ))
+        };
+        if !self.terminal_type.tracker().is_integrated() {
+            return unsupported(format!(
+                "{} runs without shell integration",
+                self.terminal_type.shell_name()
+            ));
+        }
+
+        let start = std::time::Instant::now();
+        let deadline = tokio::time::Instant::now() + timeout;
+        // a new shell is still starting
+        while self.shell_status().current_state == ShellState::Starting
+            && self.read_tracked(deadline, on_output).await?.is_some()
+        {}
+        let state = self.shell_status().current_state;
+        if state != ShellState::Idle {
+            return unsupported(format!("The shell is not at its prompt: {:?}", state));
+        }
+
+        let token = uuid::Uuid::new_v4().simple().to_string();
+        let line = self.terminal_type.exec_line(command, &token);
+        self.send_text(&line).await?;
+
+        let mut raw = String::new();
+        let mut finished = None;
+        while let Some(text) = self.read_tracked(deadline, on_output).await? {
+            raw.push_str(&text);
+            finished = parse_exec_output(&raw, &token);
+            if finished.is_some() {
+                break;
+            }
+        }
+        // the prompt follows the end mark, the next command finds it idle
+        while finished.is_some()
+            && self.shell_status().current_state == ShellState::Running
+            && self.read_tracked(deadline, on_output).await?.is_some()
+        {}
+
+        let duration_ms = start.elapsed().as_millis() as u64;
+        let result = match finished {
+            Some((output, exit_code)) => ExecResult {
+                output,
+                exit_code: Some(exit_code),
+                duration_ms,
+                timed_out: false,
+            },
+            None => {
+                log::warn!("Command {:?} did not finish within {:?}", command, timeout);
+                self.send_keyboard_interrupt().await?;
+                let start_mark = start_mark(&token);
+                let output = raw
+                    .find(&start_mark)
+                    .map(|at| exec_output(&raw[at + start_mark.len()..]))
+                    .unwrap_or_default();
+                ExecResult {
+                    output,
+                    exit_code: None,
+                    duration_ms,
+                    timed_out: true,
+                }
+            }
+        };
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_exec_output() {
+        let line = posix_exec_line(r#"echo 'it' "$HOME""#, "abc");
+        assert!(line.contains(r#"eval 'echo '\''it'\'' "$HOME"'"#));
+
+        let raw = format!(
+            "{}\n\x1b]133;C;echokit=abc\x07\x1b[1mhi\x1b[0m\r\nthere\r\n\x1b]133;D;2;echokit=abc\x07$ ",
+            line.trim_end()
+        );
+        assert_eq!(
+            parse_exec_output(&raw, "abc"),
+            Some(("hi\nthere\n".to_string(), 2))
+        );
+        assert_eq!(parse_exec_output(&raw[..raw.len() - 4], "abc"), None);
+        assert_eq!(parse_exec_output(&raw, "other"), None);
+    }
+
+    #[test]
+    fn test_shell_tracker() {
+        let mut tracker = ShellTracker::default();
+        assert_eq!(tracker.status().current_state, ShellState::Starting);
+
+        // the first prompt ends no command
+        assert!(tracker.update_from_output(
+            "\x1b]133;D;0\x07\x1b]7;file://host/home/my%20dir\x07\x1b]133;A\x07$ "
+        ));
+        assert_eq!(
+            tracker.status(),
+            &ShellStatus {
+                current_state: ShellState::Idle,
+                last_exit_code: None,
+                cwd: Some("/home/my dir".to_string()),
+            }
+        );
+
+        // a command, with the end mark split across reads
+        assert!(tracker.update_from_output("false\r\n\x1b]133;C\x07"));
+        assert_eq!(tracker.status().current_state, ShellState::Running);
+        assert!(!tracker.update_from_output("\x1b]133;D;"));
+        assert!(!tracker.update_from_output("1\x1b"));
+        assert!(tracker.update_from_output("\\\x1b]133;A\x1b\\$ "));
+        assert_eq!(tracker.status().current_state, ShellState::Idle);
+        assert_eq!(tracker.status().last_exit_code, Some(1));
+
+        // the marks of `run_command` leave the state to the shell's own
+        tracker.update_from_output("\x1b]133;C\x07\x1b]133;C;echokit=abc\x07");
+        tracker.update_from_output("\x1b]133;D;3;echokit=abc\x07");
+        assert_eq!(tracker.status().current_state, ShellState::Running);
+        tracker.update_from_output("\x1b]133;D;3\x07\x1b]7;file:///tmp\x07");
+        assert_eq!(tracker.status().last_exit_code, Some(3));
+        assert_eq!(tracker.status().cwd.as_deref(), Some("/tmp"));
+    }
+
+    #[test]
+    fn test_integration_dir_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = integration_file("test/rc", "true\n").unwrap();
+        let dir = integration_dir().unwrap();
+        assert!(path.starts_with(&dir));
+        check_private_dir(&dir).unwrap();
+        check_private_dir(path.parent().unwrap()).unwrap();
+
+        let shared = dir.join("shared");
+        std::fs::create_dir(&shared).unwrap();
+        std::fs::set_permissions(&shared, std::fs::Permissions::from_mode(0o777)).unwrap();
+        assert_eq!(
+            check_private_dir(&shared).unwrap_err().kind(),
+            std::io::ErrorKind::PermissionDenied
+        );
+    }
+
+    #[test]
+    fn test_fish_exec_line() {
+        assert_eq!(fish_quote(r"it's a \"), r"'it\'s a \\'");
+        let line = Fish::default().exec_line("echo 'a'", "abc");
+        assert!(line.contains(r"; eval 'echo \'a\''; "));
+        assert!(line.ends_with("$status abc\r"));
+    }
+
+    /// Run `cd /tmp; false` and wait for the prompt reporting it
+    async fn assert_integration<T: ShellType>(mut terminal: EchokitChild<T>) {
+        let timeout = std::time::Duration::from_secs(5);
+
+        let result = terminal
+            .run_command("cd /tmp; false", timeout, &mut |_| {})
+            .await
+            .unwrap();
+        assert_eq!(result.exit_code, Some(1));
+
+        // the prompt after the command reports its status and the new cwd
+        let deadline = tokio::time::Instant::now() + timeout;
+        while terminal.shell_status().cwd.as_deref() != Some("/tmp") {
+            let output = tokio::time::timeout_at(deadline, terminal.read_string())
+                .await
+                .expect("no prompt after the command")
+                .unwrap();
+            terminal.update_from_output(&output);
+        }
+        assert_eq!(terminal.shell_status().current_state, ShellState::Idle);
+        assert_eq!(terminal.shell_status().last_exit_code, Some(1));
+
+        terminal.kill().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bash_integration() {
+        assert_integration(new(Bash::default(), &[] as &[&str], (24, 80)).unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn test_sh_integration() {
+        assert_integration(new(Sh::default(), &[] as &[&str], (24, 80)).unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn test_custom_shell_rc() {
+        let rcfile = integration_file("bashrc", include_str!("integration/bash.sh")).unwrap();
+        let shell = CustomShell::new("bash")
+            .interactive(true)
+            .rc(rcfile, RcInjection::Arg("--rcfile".to_string()));
+        assert_integration(new(shell, &[] as &[&str], (24, 80)).unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn test_run_command() {
+        let timeout = std::time::Duration::from_secs(5);
+
+        // without the integration the output cannot be delimited
+        let mut terminal =
+            new(Bash::default(), &["--norc", "--noprofile", "-i"], (24, 80)).unwrap();
+        let error = terminal
+            .run_command("true", timeout, &mut |_| {})
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::Unsupported);
+        terminal.kill().await.unwrap();
+
+        let mut terminal = new(Bash::default(), &[] as &[&str], (24, 80)).unwrap();
+
+        let result = terminal
+            .run_command("echo one; echo two >&2; (exit 3)", timeout, &mut |_| {})
+            .await
+            .unwrap();
+        assert_eq!(result.output, "one\ntwo\n");
+        assert_eq!(result.exit_code, Some(3));
+
+        let result = terminal
+            .run_command("printf 'a\\nb'\necho \"it's\"", timeout, &mut |_| {})
+            .await
+            .unwrap();
+        assert_eq!(result.output, "a\nbit's\n");
+        assert_eq!(result.exit_code, Some(0));
+
+        let result = terminal
+            .run_command(
+                "sleep 10",
+                std::time::Duration::from_millis(300),
+                &mut |_| {},
+            )
+            .await
+            .unwrap();
+        assert!(result.timed_out);
+        assert_eq!(result.exit_code, None);
+
+        terminal.kill().await.unwrap();
+    }
+}
@@ -0,0 +1,104 @@
+/// Longest UTF-8 sequence, a chunk can end in at most 3 bytes of one
+const MAX_SEQUENCE_LEN: usize = 4;
+
+/// Decodes PTY output read in chunks. A sequence split across two reads is
+/// kept until the rest arrives, invalid bytes become U+FFFD.
+#[derive(Debug, Default)]
+pub struct Utf8Decoder {
+    pending: Vec<u8>,
+}
+
+impl Utf8Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bytes of an incomplete sequence waiting for the next chunk
+    pub fn pending(&self) -> &[u8] {
+        &self.pending
+    }
+
+    /// Decode the next chunk. The result is empty when the chunk only holds
+    /// the start of a sequence.
+    pub fn decode(&mut self, chunk: &[u8]) -> String {
+        let mut bytes = std::mem::take(&mut self.pending);
+        bytes.extend_from_slice(chunk);
+
+        let mut output = String::with_capacity(bytes.len());
+        let mut rest = bytes.as_slice();
+        loop {
+            match str::from_utf8(rest) {
+                Ok(s) => {
+                    output.push_str(s);
+                    break;
+                }
+                Err(e) => {
+                    let (valid, invalid) = rest.split_at(e.valid_up_to());
+                    output.push_str(str::from_utf8(valid).unwrap());
+                    match e.error_len() {
+                        Some(len) => {
+                            output.push(char::REPLACEMENT_CHARACTER);
+                            rest = &invalid[len..];
+                        }
+                        // the chunk ends inside a sequence
+                        None => {
+                            debug_assert!(invalid.len() < MAX_SEQUENCE_LEN);
+                            self.pending = invalid.to_vec();
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        output
+    }
+
+    /// Flush at EOF, an incomplete sequence becomes U+FFFD
+    pub fn finish(&mut self) -> String {
+        if self.pending.is_empty() {
+            return String::new();
+        }
+        self.pending.clear();
+        char::REPLACEMENT_CHARACTER.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode_split_sequences() {
+        let text = "│ 你好 ✓ 🦀";
+        let bytes = text.as_bytes();
+
+        // every split point, including inside each multi-byte character
+        for at in 0..=bytes.len() {
+            let mut decoder = Utf8Decoder::new();
+            let mut output = decoder.decode(&bytes[..at]);
+            output.push_str(&decoder.decode(&bytes[at..]));
+            assert_eq!(output, text);
+            assert!(decoder.pending().is_empty());
+        }
+
+        // one byte per read
+        let mut decoder = Utf8Decoder::new();
+        let output = bytes
+            .iter()
+            .map(|b| decoder.decode(&[*b]))
+            .collect::<String>();
+        assert_eq!(output, text);
+    }
+
+    #[test]
+    fn test_decode_invalid_bytes() {
+        let mut decoder = Utf8Decoder::new();
+        assert_eq!(decoder.decode(b"a\xffb\xc3(c"), "a\u{fffd}b\u{fffd}(c");
+
+        assert_eq!(decoder.decode(b"\x00\x1b[0m\xe2\x9c"), "\x00\x1b[0m");
+        assert_eq!(decoder.pending(), b"\xe2\x9c");
+        assert_eq!(decoder.finish(), "\u{fffd}");
+        assert_eq!(decoder.finish(), "");
+    }
+}